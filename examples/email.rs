@@ -10,7 +10,7 @@ async fn main() {
         "test@malted.dev",
         "malted",
         5,
-        &vec![String::from("foo")],
+        &[String::from("foo")],
         "https://google.com",
     )
     .await
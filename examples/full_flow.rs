@@ -4,12 +4,14 @@ use chrono::Utc;
 use dotenv::var;
 use replit_takeout::{
     airtable::{self, AirtableSyncedUser, ProcessState},
+    cancel::CancelWatch,
+    config,
     replit_graphql::ProfileRepls,
 };
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    replit_takeout::telemetry::init();
     dotenv::dotenv().ok();
 
     let token = var("REPLIT_TEST_TOKEN")?;
@@ -26,7 +28,20 @@ async fn main() -> Result<()> {
         finished_at: None,
         repl_count: 0,
         file_count: 0,
-        statistics: vec!["recpWEjc0zLoKEtZP".into()],
+        statistics: vec![config::get().airtable.statistics_record.clone()],
+        git_remote: None,
+        git_remote_username: None,
+        git_remote_password: None,
+        archive_format: None,
+        incremental: false,
+        cancel_requested: false,
+        retry_repl_ids: None,
+        follow_up_attempt: 0,
+        not_before: None,
+        worker_id: None,
+        lease_expires_at: None,
+        heartbeat_at: None,
+        attempts: 0,
     };
 
     let mut user = Record {
@@ -36,7 +51,8 @@ async fn main() -> Result<()> {
     };
 
     log::info!("Starting...");
-    if let Err(err) = ProfileRepls::download(&user.fields.token, user.clone()).await {
+    let cancel = CancelWatch::never();
+    if let Err(err) = ProfileRepls::download(&user.fields.token, user.clone(), cancel).await {
         log::error!("Error with `{}`'s download: {err:#?}", user.fields.username);
 
         user.fields.status = ProcessState::ErroredMain;
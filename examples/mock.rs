@@ -0,0 +1,146 @@
+use std::sync::Arc;
+
+use anyhow::{format_err, Result};
+use replit_takeout::{
+    cancel::CancelWatch,
+    crosisdownload::{download, DownloadLocations, DownloadStatus, ReplInfo},
+    mock::{FixtureRepl, MockReplit, MockUser},
+    replit::{create_client, repls::Repl},
+    replit_graphql::QuickUser,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::fs;
+
+const TOKEN: &str = "mock-token";
+
+/// Download every repl from the mock, or with `serve`, leave it running for something else.
+#[tokio::main]
+async fn main() -> Result<()> {
+    replit_takeout::telemetry::init();
+
+    let fixtures = fixtures();
+    // Small enough that fetching history takes more than one window
+    std::env::set_var("OT_FETCH_WINDOW", "2");
+
+    let mock = MockReplit::start(MockUser::new(1, "mocker", TOKEN), fixtures.clone()).await?;
+    mock.install()?;
+
+    if std::env::args().nth(1).as_deref() == Some("serve") {
+        println!("REPLIT_URL={} with token {TOKEN}", mock.url());
+        std::future::pending::<()>().await;
+    }
+
+    let token = TOKEN.to_string();
+    let user = QuickUser::fetch(&token, None).await?;
+    let repls = Repl::fetch(&token, None).await?;
+    if repls.len() != fixtures.len() {
+        return Err(format_err!(
+            "Expected {} repls, got {}",
+            fixtures.len(),
+            repls.len()
+        ));
+    }
+
+    let dir = std::env::temp_dir().join("replit-takeout-mock");
+    let dir = dir.to_string_lossy();
+    let _ = fs::remove_dir_all(&*dir).await;
+
+    let client = create_client(&token, None)?;
+    let cancel = CancelWatch::never();
+
+    for repl in &repls {
+        let locations = DownloadLocations {
+            main: format!("{dir}/{}/", repl.slug),
+            git: format!("{dir}/{}.git/", repl.slug),
+            staging_git: format!("{dir}/{}.gitstaging/", repl.slug),
+            ot: format!("{dir}/{}.otbackup/", repl.slug),
+        };
+        for location in [
+            &locations.main,
+            &locations.git,
+            &locations.staging_git,
+            &locations.ot,
+        ] {
+            fs::create_dir_all(location).await?;
+        }
+
+        let (status, file_count) = download(
+            client.clone(),
+            ReplInfo {
+                id: &repl.id,
+                username: &user.username,
+                slug: &repl.slug,
+            },
+            &format!("{dir}/{}.zip", repl.slug),
+            locations,
+            OffsetDateTime::parse(&repl.time_created, &Rfc3339)?.unix_timestamp(),
+            "mocker@example.com",
+            Arc::default(),
+            &cancel,
+        )
+        .await?;
+
+        let fixture = fixtures
+            .iter()
+            .find(|fixture| fixture.id == repl.id)
+            .expect("the mock only serves fixtures");
+        let expected = if fixture.connectable {
+            DownloadStatus::Full
+        } else {
+            DownloadStatus::NoHistory
+        };
+        if status != expected {
+            return Err(format_err!("{} downloaded as {status:?}", repl.slug));
+        }
+
+        println!("{}: {status:?} with {file_count} files", repl.slug);
+    }
+
+    println!("Downloaded {} repls into {dir}", repls.len());
+
+    Ok(())
+}
+
+fn fixtures() -> Vec<FixtureRepl> {
+    let mut repls = vec![
+        FixtureRepl::new("0b1d7c2a-0001", "hello")
+            .file_with_history(
+                "main.py",
+                &[
+                    (1_672_531_200, "print('hi')\n"),
+                    // Non-ASCII, since OT counts chars rather than bytes
+                    (1_672_534_800, "print('héllo 👋')\n"),
+                    (
+                        1_672_538_400,
+                        "def main():\n    print('héllo 👋')\n\nmain()\n",
+                    ),
+                ],
+            )
+            .file("README.md", "# Hello\n")
+            .file("src/util.py", "def add(a, b):\n    return a + b\n")
+            .secrets("API_KEY=not-a-real-key\n"),
+        FixtureRepl::new("0b1d7c2a-0002", "homework")
+            .in_folder("school/cs")
+            .private()
+            .file_with_history(
+                "index.js",
+                &[
+                    (1_672_531_200, "console.log(1)\n"),
+                    (1_672_617_600, "console.log(2)\n"),
+                ],
+            ),
+        FixtureRepl::new("0b1d7c2a-0003", "zip-only")
+            .unconnectable()
+            .file("main.rs", "fn main() {}\n"),
+    ];
+
+    // Enough to need more than one page
+    for n in 0..6 {
+        repls.push(
+            FixtureRepl::new(&format!("0b1d7c2a-1{n:03}"), &format!("scratch-{n}"))
+                .file("main.py", format!("print({n})\n")),
+        );
+    }
+
+    repls
+}
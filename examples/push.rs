@@ -0,0 +1,58 @@
+use anyhow::Result;
+use git2::{Repository, Signature};
+use replit_takeout::git_remote::GitRemote;
+
+/// Pushes a throwaway repo to a local bare repository, the same way exports are pushed.
+#[tokio::main]
+async fn main() -> Result<()> {
+    replit_takeout::telemetry::init();
+
+    let root = std::env::temp_dir().join("replit-takeout-push-example");
+    if root.exists() {
+        std::fs::remove_dir_all(&root)?;
+    }
+
+    let work_path = root.join("work");
+    let work = Repository::init(&work_path)?;
+    {
+        let author = Signature::now("Replit Takeout", "test@malted.dev")?;
+        std::fs::write(work_path.join("main.py"), "print('hi')\n")?;
+
+        let mut index = work.index()?;
+        index.add_path("main.py".as_ref())?;
+        index.write()?;
+        let tree = work.find_tree(index.write_tree()?)?;
+        let commit = work.commit(Some("HEAD"), &author, &author, "Initial Commit", &tree, &[])?;
+
+        work.branch("replit-takeout-history", &work.find_commit(commit)?, false)?;
+    }
+
+    let bare_path = root.join("remotes").join("malted").join("example.git");
+    Repository::init_bare(&bare_path)?;
+
+    let remote = GitRemote {
+        url_template: format!(
+            "{}/{{username}}/{{slug}}.git",
+            root.join("remotes").display()
+        ),
+        username: None,
+        password: None,
+    };
+
+    let report = remote
+        .push(
+            work_path.display().to_string(),
+            "malted",
+            "example",
+            "00000000-0000-0000-0000-000000000000",
+        )
+        .await;
+    println!("{report:#?}");
+
+    let bare = Repository::open_bare(&bare_path)?;
+    for reference in bare.references()? {
+        println!("{}", reference?.name().unwrap_or("?"));
+    }
+
+    Ok(())
+}
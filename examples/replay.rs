@@ -0,0 +1,72 @@
+use std::sync::Arc;
+
+use anyhow::{format_err, Result};
+use replit_takeout::{
+    cancel::CancelWatch,
+    crosisdownload::{download_crosis, trace::Trace, DownloadLocations, ReplInfo},
+    mock::MockReplit,
+    replit::create_client,
+};
+use tokio::fs;
+
+/// Run a recorded crosis trace back through the download, offline.
+/// Usage: `replay <trace> [out dir]`
+#[tokio::main]
+async fn main() -> Result<()> {
+    replit_takeout::telemetry::init();
+
+    let mut args = std::env::args().skip(1);
+    let path = args
+        .next()
+        .ok_or_else(|| format_err!("Usage: replay <trace> [out dir]"))?;
+    let dir = args.next().unwrap_or_else(|| {
+        std::env::temp_dir()
+            .join("replit-takeout-replay")
+            .to_string_lossy()
+            .to_string()
+    });
+
+    let trace = Trace::load(&path).await?;
+    let header = trace.header.clone();
+
+    let mock = MockReplit::replay(trace).await?;
+    mock.install()?;
+
+    let _ = fs::remove_dir_all(&dir).await;
+    let locations = DownloadLocations {
+        main: format!("{dir}/{}/", header.slug),
+        git: format!("{dir}/{}.git/", header.slug),
+        staging_git: format!("{dir}/{}.gitstaging/", header.slug),
+        ot: format!("{dir}/{}.otbackup/", header.slug),
+    };
+    for location in [
+        &locations.main,
+        &locations.git,
+        &locations.staging_git,
+        &locations.ot,
+    ] {
+        fs::create_dir_all(location).await?;
+    }
+
+    let result = download_crosis(
+        create_client(&"replay".to_string(), None)?,
+        ReplInfo {
+            id: &header.repl_id,
+            username: &header.username,
+            slug: &header.slug,
+        },
+        locations,
+        header.ts_offset,
+        "replay@example.com",
+        Arc::default(),
+        &CancelWatch::never(),
+    )
+    .await;
+
+    match result {
+        Ok(()) => println!("Replayed {} into {dir}", header.repl_id),
+        Err(err) => println!("Replaying {} failed: {err:#}", header.repl_id),
+    }
+
+    Ok(())
+}
@@ -1,13 +1,10 @@
-use std::collections::HashMap;
-
 use anyhow::Result;
 use dotenv::var;
-use log::error;
-use replit_takeout::{replit::repls::Repl, replit_graphql::ProfileRepls};
+use replit_takeout::replit_graphql::ProfileRepls;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    replit_takeout::telemetry::init();
     dotenv::dotenv().ok();
 
     let token = var("REPLIT_TEST_TOKEN")?;
@@ -31,6 +28,7 @@ async fn main() -> Result<()> {
 
     //#region Old, fixed method
     {
+        #[allow(deprecated)]
         let repls = ProfileRepls::fetch(&token, 222834, None).await?;
         println!("{:#?}", repls.len());
     }
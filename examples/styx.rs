@@ -1,9 +1,17 @@
-use std::time::Duration;
+use std::sync::Arc;
 
 use anyhow::Result;
 use graphql_client::{GraphQLQuery, Response};
 use log::*;
-use replit_takeout::crosisdownload::{download, make_zip, DownloadLocations, ReplInfo};
+use replit_takeout::{
+    archive::{make_archive, ArchiveFormat},
+    cancel::CancelWatch,
+    crosisdownload::{
+        download,
+        progress::{DownloadProgress, TimeoutBudget},
+        DownloadLocations, ReplInfo,
+    },
+};
 use reqwest::{cookie::Jar, header, Client, Url};
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use tokio::fs;
@@ -40,7 +48,7 @@ static REPLIT_GQL_URL: &str = "https://replit.com/graphql";
 async fn main() -> Result<()> {
     // console_subscriber::init();
 
-    env_logger::init();
+    replit_takeout::telemetry::init();
 
     let connect_sid = std::env::args().nth(1).expect("a token");
 
@@ -99,6 +107,7 @@ async fn main() -> Result<()> {
     fs::create_dir(format!("repls/{}", current_user.username)).await?;
 
     let email = String::from("testing.export@codemonkey51.dev");
+    let cancel = CancelWatch::never();
 
     let mut after = None;
     let mut i = 0;
@@ -108,7 +117,7 @@ async fn main() -> Result<()> {
             .post(REPLIT_GQL_URL)
             .json(&ProfileRepls::build_query(profile_repls::Variables {
                 after,
-                id: current_user.id,
+                user_id: current_user.id,
             }))
             .send()
             .await?
@@ -158,6 +167,9 @@ async fn main() -> Result<()> {
 
                 dbg!(ts);
 
+                let progress = Arc::new(DownloadProgress::default());
+                let budget = TimeoutBudget::new(None);
+
                 let download_zip = format!("repls/{}/{}.zip", current_user.username, repl.slug);
                 let download_job = download(
                     reqwest::Client::builder()
@@ -179,15 +191,19 @@ async fn main() -> Result<()> {
                     },
                     ts.unix_timestamp(),
                     &email,
+                    progress.clone(),
+                    &cancel,
                 );
 
-                // At 30 minutes abandon the repl download
-                match tokio::time::timeout(Duration::from_secs(60 * 30), download_job).await {
-                    Err(_) => {
-                        error!(
-                            "Downloading {}::{} timed out after 30 minutes",
-                            repl.id, repl.slug
-                        )
+                // Give up the same way the worker does, once the repl's budget is used up
+                let result = tokio::select! {
+                    res = download_job => Ok(res),
+                    expiry = budget.watch(&progress) => Err(expiry),
+                };
+
+                match result {
+                    Err(expiry) => {
+                        error!("Downloading {}::{} timed out: {expiry}", repl.id, repl.slug)
                     }
                     Ok(Err(err)) => {
                         error!(
@@ -230,7 +246,12 @@ async fn main() -> Result<()> {
 
     // println!("")
     let path = format!("repls/{}", current_user.username);
-    make_zip(path.clone(), format!("repls/{}.zip", current_user.username)).await?;
+    make_archive(
+        path.clone(),
+        format!("repls/{}.zip", current_user.username),
+        ArchiveFormat::Zip,
+    )
+    .await?;
     fs::remove_dir_all(&path).await?;
 
     //#endregion
@@ -1,12 +1,14 @@
 use tokio::fs;
 
 use anyhow::Result;
-use replit_takeout::crosisdownload::{util::download_repl_zip, ReplInfo};
+use replit_takeout::crosisdownload::{
+    progress::DownloadProgress, util::download_repl_zip, ReplInfo,
+};
 use reqwest::{cookie::Jar, header, Client, Url};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    replit_takeout::telemetry::init();
 
     let connect_sid = std::env::args().nth(1).expect("a token");
     let username = std::env::args().nth(2).expect("a username");
@@ -44,6 +46,7 @@ async fn main() -> Result<()> {
             slug: &replslug,
         },
         &format!("repls/{}/{}.zip", username, replslug),
+        &DownloadProgress::default(),
     )
     .await?;
 
@@ -0,0 +1,383 @@
+//! Queue management for operators, so stuck jobs don't need hand-editing in Airtable.
+//! Every route needs `Authorization: Bearer <admin token>`, with the token from the config.
+
+use airtable_api::Record;
+use chrono::{DateTime, Utc};
+use replit_takeout::{
+    airtable::{self, AirtableSyncedUser, ProcessState},
+    config,
+    email::emails::{send_partial_success_email, send_success_email},
+    r2,
+    replit_graphql::ExportProgress,
+};
+use rocket::{
+    http::Status,
+    request::{self, FromRequest, Request},
+    serde::json::Json,
+    Route,
+};
+use serde::Serialize;
+
+/// Airtable only takes this many records per update.
+const UPDATE_BATCH_SIZE: usize = 10;
+
+/// The states a job can be requeued from.
+const REQUEUEABLE: [ProcessState; 3] = [
+    ProcessState::ErroredMain,
+    ProcessState::ErroredR2,
+    ProcessState::Errored,
+];
+
+type AdminResult<T> = Result<Json<T>, (Status, String)>;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        list_jobs,
+        get_job,
+        requeue_job,
+        bulk_requeue,
+        reemail_job,
+        relink_job
+    ]
+}
+
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let Some(admin_token) = &config::get().secrets.admin_token else {
+            warn!("Refusing admin request, there's no admin token configured");
+            return request::Outcome::Error((Status::Unauthorized, ()));
+        };
+
+        let supplied = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .unwrap_or_default();
+
+        if !admin_token.is_empty()
+            && supplied.len() == admin_token.len()
+            && openssl::memcmp::eq(supplied.as_bytes(), admin_token.as_bytes())
+        {
+            request::Outcome::Success(Admin)
+        } else {
+            request::Outcome::Error((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// A job without its secrets.
+#[derive(Serialize)]
+struct JobSummary {
+    record_id: String,
+    user_id: i64,
+    username: String,
+    email: String,
+    status: ProcessState,
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
+    repl_count: usize,
+    file_count: usize,
+    failed_ids: Vec<String>,
+    r2_link: String,
+}
+
+impl From<&Record<AirtableSyncedUser>> for JobSummary {
+    fn from(record: &Record<AirtableSyncedUser>) -> Self {
+        Self {
+            record_id: record.id.clone(),
+            user_id: record.fields.id,
+            username: record.fields.username.clone(),
+            email: record.fields.email.clone(),
+            status: record.fields.status,
+            started_at: record.fields.started_at,
+            finished_at: record.fields.finished_at,
+            repl_count: record.fields.repl_count,
+            file_count: record.fields.file_count,
+            failed_ids: failed_ids(&record.fields),
+            r2_link: record.fields.r2_link.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JobDetail {
+    #[serde(flatten)]
+    job: JobSummary,
+    progress: Option<ExportProgress>,
+}
+
+#[derive(Serialize)]
+struct RequeueResponse {
+    requeued: Vec<String>,
+}
+
+#[get("/jobs?<state>")]
+async fn list_jobs(_admin: Admin, state: Option<String>) -> AdminResult<Vec<JobSummary>> {
+    let state = state.as_deref().map(parse_state).transpose()?;
+
+    let jobs = all_records()
+        .await?
+        .iter()
+        .filter(|record| state.is_none_or(|state| record.fields.status == state))
+        .map(JobSummary::from)
+        .collect();
+
+    Ok(Json(jobs))
+}
+
+#[get("/jobs/<record_id>")]
+async fn get_job(_admin: Admin, record_id: &str) -> AdminResult<JobDetail> {
+    let record = find_record(record_id).await?;
+
+    Ok(Json(JobDetail {
+        job: JobSummary::from(&record),
+        progress: ExportProgress::fetch(record.fields.id).await,
+    }))
+}
+
+#[post("/jobs/<record_id>/requeue")]
+async fn requeue_job(_admin: Admin, record_id: &str) -> AdminResult<RequeueResponse> {
+    let mut record = find_record(record_id).await?;
+
+    if !REQUEUEABLE.contains(&record.fields.status) {
+        return Err((
+            Status::Conflict,
+            format!("Can't requeue a job that's {}", record.fields.status),
+        ));
+    }
+
+    reset_for_requeue(&mut record.fields);
+    airtable::update_records(vec![record])
+        .await
+        .map_err(internal)?;
+
+    info!("Requeued {record_id}");
+
+    Ok(Json(RequeueResponse {
+        requeued: vec![record_id.to_string()],
+    }))
+}
+
+/// Requeue every job in an errored `state`, optionally only those that finished before a time.
+#[post("/jobs/requeue?<state>&<finished_before>")]
+async fn bulk_requeue(
+    _admin: Admin,
+    state: &str,
+    finished_before: Option<&str>,
+) -> AdminResult<RequeueResponse> {
+    let state = parse_state(state)?;
+    if !REQUEUEABLE.contains(&state) {
+        return Err((
+            Status::BadRequest,
+            format!("Can't requeue jobs that are {state}"),
+        ));
+    }
+
+    let finished_before = finished_before
+        .map(|time| time.parse::<DateTime<Utc>>())
+        .transpose()
+        .map_err(|err| {
+            (
+                Status::BadRequest,
+                format!("Invalid finished_before: {err}"),
+            )
+        })?;
+
+    let mut records: Vec<_> = all_records()
+        .await?
+        .into_iter()
+        .filter(|record| record.fields.status == state)
+        .filter(
+            |record| match (finished_before, record.fields.finished_at) {
+                (None, _) => true,
+                (Some(before), Some(finished_at)) => finished_at < before,
+                (Some(_), None) => false,
+            },
+        )
+        .collect();
+
+    for record in &mut records {
+        reset_for_requeue(&mut record.fields);
+    }
+
+    let requeued = records.iter().map(|record| record.id.clone()).collect();
+
+    for batch in records.chunks(UPDATE_BATCH_SIZE) {
+        airtable::update_records(batch.to_vec())
+            .await
+            .map_err(internal)?;
+    }
+
+    info!("Bulk requeued {requeued:?}");
+
+    Ok(Json(RequeueResponse { requeued }))
+}
+
+/// Send the user their download link again.
+#[post("/jobs/<record_id>/reemail")]
+async fn reemail_job(_admin: Admin, record_id: &str) -> AdminResult<JobSummary> {
+    let mut record = find_record(record_id).await?;
+    check_exported(&record.fields)?;
+
+    if record.fields.r2_link.is_empty() || record.fields.r2_link == airtable::NO_R2_LINK {
+        return Err((
+            Status::Conflict,
+            "The job has no link to send, relink it first".to_string(),
+        ));
+    }
+
+    send_link_email(&record.fields).await?;
+
+    record.fields.status = ProcessState::R2LinkEmailSent;
+    airtable::update_records(vec![record.clone()])
+        .await
+        .map_err(internal)?;
+
+    Ok(Json(JobSummary::from(&record)))
+}
+
+/// Presign a fresh link to the user's export, for when the old one has expired.
+#[post("/jobs/<record_id>/relink")]
+async fn relink_job(_admin: Admin, record_id: &str) -> AdminResult<JobSummary> {
+    let mut record = find_record(record_id).await?;
+    check_exported(&record.fields)?;
+
+    // Where this job uploaded to, in the format it asked for
+    let archive_format = record.fields.archive_format.unwrap_or_default();
+    let archive_name = format!("{}.{}", record.fields.username, archive_format.extension());
+    let upload_path = format!("export/{archive_name}");
+
+    record.fields.r2_link = r2::get(upload_path, archive_name).await.map_err(internal)?;
+    airtable::update_records(vec![record.clone()])
+        .await
+        .map_err(internal)?;
+
+    info!("Regenerated the export link for {record_id}");
+
+    Ok(Json(JobSummary::from(&record)))
+}
+
+/// Only jobs that uploaded an archive have anything to link to.
+fn check_exported(user: &AirtableSyncedUser) -> Result<(), (Status, String)> {
+    if user.status.exported() {
+        Ok(())
+    } else {
+        Err((
+            Status::Conflict,
+            format!("The job is {}, it never uploaded an archive", user.status),
+        ))
+    }
+}
+
+async fn all_records() -> Result<Vec<Record<AirtableSyncedUser>>, (Status, String)> {
+    airtable::get_records().await.map_err(internal)
+}
+
+async fn find_record(record_id: &str) -> Result<Record<AirtableSyncedUser>, (Status, String)> {
+    all_records()
+        .await?
+        .into_iter()
+        .find(|record| record.id == record_id)
+        .ok_or_else(|| (Status::NotFound, format!("No job {record_id}")))
+}
+
+fn parse_state(state: &str) -> Result<ProcessState, (Status, String)> {
+    serde_json::from_value(serde_json::Value::String(state.to_string()))
+        .map_err(|_| (Status::BadRequest, format!("Unknown state `{state}`")))
+}
+
+fn failed_ids(user: &AirtableSyncedUser) -> Vec<String> {
+    if user.failed_ids == "none" {
+        return vec![];
+    }
+
+    user.failed_ids
+        .split(',')
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn reset_for_requeue(user: &mut AirtableSyncedUser) {
+    user.status = ProcessState::Registered;
+    user.started_at = None;
+    user.finished_at = None;
+    user.failed_ids = String::from("none");
+    user.attempts = 0;
+
+    // They get counted again when the job reruns
+    user.repl_count = 0;
+    user.file_count = 0;
+}
+
+async fn send_link_email(user: &AirtableSyncedUser) -> Result<(), (Status, String)> {
+    let failed = failed_ids(user);
+
+    let result = if failed.is_empty() {
+        send_success_email(&user.email, &user.username, user.repl_count, &user.r2_link).await
+    } else {
+        send_partial_success_email(
+            &user.email,
+            &user.username,
+            user.repl_count,
+            &failed,
+            &user.r2_link,
+        )
+        .await
+    };
+
+    result.map_err(internal)
+}
+
+fn internal(err: impl std::fmt::Display) -> (Status, String) {
+    error!("Admin request failed: {err:#}");
+    (Status::InternalServerError, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errored_jobs_keep_their_credentials_for_a_requeue() {
+        for state in REQUEUEABLE {
+            let mut user = AirtableSyncedUser {
+                token: "v1:token".into(),
+                git_remote_password: Some("v1:password".into()),
+                status: state,
+                attempts: 3,
+                ..Default::default()
+            };
+
+            // What the worker writes back when the job errors
+            user.wipe_credentials_if_done();
+            assert_eq!(user.token, "v1:token");
+
+            reset_for_requeue(&mut user);
+            user.wipe_credentials_if_done();
+            assert_eq!(user.status, ProcessState::Registered);
+            assert_eq!(user.attempts, 0);
+            assert_eq!(user.token, "v1:token");
+            assert_eq!(user.git_remote_password.as_deref(), Some("v1:password"));
+        }
+    }
+
+    #[test]
+    fn finished_jobs_lose_their_credentials() {
+        let mut user = AirtableSyncedUser {
+            token: "v1:token".into(),
+            git_remote_password: Some("v1:password".into()),
+            status: ProcessState::R2LinkEmailSent,
+            ..Default::default()
+        };
+
+        user.wipe_credentials_if_done();
+        assert!(user.token.is_empty());
+        assert_eq!(user.git_remote_password, None);
+    }
+}
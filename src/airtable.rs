@@ -1,16 +1,23 @@
+use std::collections::HashSet;
+
 use airtable_api::{Airtable, Record};
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, format_err, Result};
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use dotenv::var;
-use once_cell::sync::Lazy;
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use once_cell::sync::{Lazy, OnceCell};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
+use crate::{archive::ArchiveFormat, config, crypto, git_remote::GitRemote};
+
 static AIRTABLE: Lazy<Airtable> = Lazy::new(Airtable::new_from_env);
-static TABLE: &str = "tblZABr7qbdjjZo1G";
-static STATISTICS_TABLE: &str = "tbl2RjULxUSRXJZ39";
-static STATISTICS_RECORD: &str = "recpWEjc0zLoKEtZP";
+
+static STORE: OnceCell<&'static dyn JobStore> = OnceCell::new();
+
+/// What the R2 link is until the job has uploaded the user's archive.
+pub const NO_R2_LINK: &str = "https://example.com";
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct AirtableSyncedUser {
@@ -20,7 +27,9 @@ pub struct AirtableSyncedUser {
     #[serde(rename = "Username")]
     pub username: String,
 
-    #[serde(rename = "Connection Token")]
+    /// Encrypted with [`crate::crypto::encrypt_secret`], and wiped once the job is over.
+    /// Airtable leaves out empty fields, so wiped tokens come back missing.
+    #[serde(rename = "Connection Token", default)]
     pub token: String,
 
     #[serde(rename = "Email")]
@@ -49,79 +58,441 @@ pub struct AirtableSyncedUser {
 
     #[serde(rename = "Statistics")]
     pub statistics: Vec<String>,
+
+    /// Repository url template to push each reconstructed repl to, if the user asked for it.
+    #[serde(rename = "Git Remote")]
+    pub git_remote: Option<String>,
+
+    #[serde(rename = "Git Remote Username")]
+    pub git_remote_username: Option<String>,
+
+    /// Encrypted and wiped just like the token.
+    #[serde(rename = "Git Remote Password")]
+    pub git_remote_password: Option<String>,
+
+    /// Defaults to zip when unset.
+    #[serde(rename = "Archive Format")]
+    pub archive_format: Option<ArchiveFormat>,
+
+    /// Only re-download repls that changed since the user's last export.
+    #[serde(rename = "Incremental", default)]
+    pub incremental: bool,
+
+    /// Set by the user to stop their export. Never written back by the worker, so
+    /// updating a record mid-export can't undo a cancellation.
+    #[serde(rename = "Cancel Requested", default, skip_serializing)]
+    pub cancel_requested: bool,
+
+    /// Set on follow-up jobs: the comma-separated repl IDs to try again and patch into
+    /// the user's existing archive.
+    #[serde(rename = "Retry Repl IDs")]
+    pub retry_repl_ids: Option<String>,
+
+    /// How many follow-up jobs came before this one.
+    #[serde(rename = "Follow Up Attempt", default)]
+    pub follow_up_attempt: usize,
+
+    /// The job won't be picked up before this.
+    #[serde(rename = "Not Before")]
+    pub not_before: Option<DateTime<Utc>>,
+
+    /// The worker holding the job's lease. Like the lease expiry, only ever written by
+    /// [`claim`], [`renew_lease`] and [`release_lease`], so a stale copy of the record can't
+    /// overwrite them.
+    #[serde(rename = "Worker ID", default, skip_serializing)]
+    pub worker_id: Option<String>,
+
+    /// Once this passes without the lease being renewed, another worker can take the job.
+    #[serde(rename = "Lease Expires At", default, skip_serializing)]
+    pub lease_expires_at: Option<DateTime<Utc>>,
+
+    /// The last time the worker running this job said it was still going.
+    #[serde(rename = "Heartbeat At", default, skip_serializing)]
+    pub heartbeat_at: Option<DateTime<Utc>>,
+
+    /// How many times the job has been picked up again after its worker disappeared.
+    #[serde(rename = "Attempts", default)]
+    pub attempts: usize,
 }
 
-pub async fn add_user(user: AirtableSyncedUser) -> bool {
-    let record: Record<AirtableSyncedUser> = Record {
-        id: "".into(),
-        fields: user,
-        created_time: None,
-    };
+impl AirtableSyncedUser {
+    /// Whether this job is waiting to be picked up by a worker.
+    pub fn is_ready(&self) -> bool {
+        self.status == ProcessState::Registered
+            && !self.cancel_requested
+            && self
+                .not_before
+                .is_none_or(|not_before| not_before <= Utc::now())
+    }
 
-    // get_records().await;
+    /// Whether nobody holds this job's lease.
+    pub fn lease_expired(&self) -> bool {
+        self.lease_expires_at
+            .is_none_or(|expires_at| expires_at <= Utc::now())
+    }
 
-    AIRTABLE.create_records(TABLE, vec![record]).await.is_ok()
+    /// Whether a worker can claim this job to run it.
+    pub fn is_claimable(&self) -> bool {
+        self.is_ready() && self.lease_expired()
+    }
+
+    /// Whether this job looks like it's running, but nothing has heard from its worker for
+    /// `stale_after`.
+    pub fn is_stale(&self, stale_after: chrono::Duration) -> bool {
+        let running = matches!(
+            self.status,
+            ProcessState::CollectingRepls | ProcessState::Collected | ProcessState::WaitingInR2
+        );
+
+        // Jobs from before heartbeats only have when they started to go on
+        let last_seen = self.heartbeat_at.or(self.started_at);
+
+        running
+            && self.lease_expired()
+            && last_seen.is_none_or(|last_seen| Utc::now() - last_seen > stale_after)
+    }
+
+    /// The repls a follow-up job should try again, if this is one.
+    pub fn retry_repl_ids(&self) -> Option<HashSet<String>> {
+        let ids = self
+            .retry_repl_ids
+            .as_deref()
+            .filter(|ids| !ids.is_empty())?;
+
+        Some(ids.split(',').map(String::from).collect())
+    }
+
+    /// A finished job has no more use for the user's credentials. Errored jobs keep them so they
+    /// can be requeued.
+    pub fn wipe_credentials_if_done(&mut self) {
+        if self.status.is_terminal() {
+            self.token.clear();
+            self.git_remote_password = None;
+        }
+    }
+
+    pub fn git_remote(&self) -> Result<Option<GitRemote>> {
+        let Some(url_template) = self.git_remote.clone().filter(|url| !url.is_empty()) else {
+            return Ok(None);
+        };
+
+        let password = self
+            .git_remote_password
+            .as_deref()
+            .filter(|password| !password.is_empty())
+            .map(crypto::decrypt_secret)
+            .transpose()?;
+
+        Ok(Some(GitRemote {
+            url_template,
+            username: self.git_remote_username.clone(),
+            password,
+        }))
+    }
+}
+
+/// Where jobs are kept. Airtable, unless something else is put in with [`use_store`].
+#[async_trait]
+pub trait JobStore: Send + Sync {
+    async fn create(&self, fields: AirtableSyncedUser) -> Result<()>;
+
+    async fn list(&self) -> Result<Vec<Record<AirtableSyncedUser>>>;
+
+    /// A single job, read straight from the store.
+    async fn fetch(&self, record_id: &str) -> Result<Record<AirtableSyncedUser>>;
+
+    /// Write the records over the stored ones with the same ids. Fields that aren't serialized,
+    /// like the lease, are left as they are.
+    async fn update(&self, records: Vec<Record<AirtableSyncedUser>>) -> Result<()>;
+
+    /// Set only `fields`, by their Airtable names, leaving the rest of the job alone.
+    async fn patch(&self, record_id: &str, fields: Value) -> Result<()>;
+}
+
+/// Keep jobs in `store` instead of Airtable for the rest of the process. Only works before
+/// anything has touched a job.
+pub fn use_store(store: &'static dyn JobStore) -> Result<()> {
+    STORE
+        .set(store)
+        .map_err(|_| format_err!("The job store is already in use"))
+}
+
+fn store() -> &'static dyn JobStore {
+    *STORE.get_or_init(|| &AirtableJobs)
+}
+
+struct AirtableJobs;
+
+#[async_trait]
+impl JobStore for AirtableJobs {
+    async fn create(&self, fields: AirtableSyncedUser) -> Result<()> {
+        let record = Record {
+            id: "".into(),
+            fields,
+            created_time: None,
+        };
+
+        AIRTABLE.create_records(table(), vec![record]).await?;
+
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<Record<AirtableSyncedUser>>> {
+        Ok(AIRTABLE
+            .list_records(
+                table(),
+                "Grid view",
+                vec![
+                    "ID",
+                    "Connection Token",
+                    "Username",
+                    "Email",
+                    "Status",
+                    "R2 Link",
+                    "Failed Repl IDs",
+                    "Started At",
+                    "Finished At",
+                    "Repl Count",
+                    "File Count",
+                    "Statistics",
+                    "Git Remote",
+                    "Git Remote Username",
+                    "Git Remote Password",
+                    "Archive Format",
+                    "Incremental",
+                    "Cancel Requested",
+                    "Retry Repl IDs",
+                    "Follow Up Attempt",
+                    "Not Before",
+                    "Worker ID",
+                    "Lease Expires At",
+                    "Heartbeat At",
+                    "Attempts",
+                ],
+            )
+            .await?)
+    }
+
+    async fn fetch(&self, record_id: &str) -> Result<Record<AirtableSyncedUser>> {
+        Ok(reqwest::Client::new()
+            .get(record_url(table(), record_id)?)
+            .headers(api_headers()?)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn update(&self, records: Vec<Record<AirtableSyncedUser>>) -> Result<()> {
+        AIRTABLE.update_records(table(), records).await?;
+
+        Ok(())
+    }
+
+    async fn patch(&self, record_id: &str, fields: Value) -> Result<()> {
+        reqwest::Client::new()
+            .patch(record_url(table(), record_id)?)
+            .headers(api_headers()?)
+            .json(&json!({ "fields": fields }))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+pub async fn add_user(user: AirtableSyncedUser) -> bool {
+    store().create(user).await.is_ok()
 }
 
 pub async fn get_records() -> Result<Vec<Record<AirtableSyncedUser>>> {
-    // Get the current records from a table.
-    let records: Vec<Record<AirtableSyncedUser>> = AIRTABLE
-        .list_records(
-            TABLE,
-            "Grid view",
-            vec![
-                "ID",
-                "Connection Token",
-                "Username",
-                "Email",
-                "Status",
-                "R2 Link",
-                "Failed Repl IDs",
-                "Started At",
-                "Finished At",
-                "Repl Count",
-                "File Count",
-                "Statistics",
-            ],
-        )
-        .await?;
+    store().list().await
+}
 
-    // Iterate over the records.
-    // for (i, record) in records.clone().iter().enumerate() {
-    //     println!("{} - {:#?}", i, record);
-    // }
-    Ok(records)
+/// Every job for a Replit user, oldest first.
+pub async fn jobs_for_user(user_id: i64) -> Result<Vec<Record<AirtableSyncedUser>>> {
+    let mut jobs: Vec<_> = get_records()
+        .await?
+        .into_iter()
+        .filter(|record| record.fields.id == user_id)
+        .collect();
+    jobs.sort_by_key(|record| record.created_time);
+
+    Ok(jobs)
 }
 
-pub async fn update_records(records: Vec<Record<AirtableSyncedUser>>) -> Result<()> {
-    AIRTABLE.update_records(TABLE, records).await?;
+/// The job `record` duplicates, if any: another of the same user's jobs that's already running,
+/// or that was queued before it.
+pub fn duplicate_of<'a>(
+    records: &'a [Record<AirtableSyncedUser>],
+    record: &Record<AirtableSyncedUser>,
+) -> Option<&'a Record<AirtableSyncedUser>> {
+    records.iter().find(|other| {
+        other.id != record.id
+            && other.fields.id == record.fields.id
+            && other.fields.status.is_active()
+            && (other.fields.status != ProcessState::Registered
+                || other.created_time < record.created_time)
+    })
+}
 
-    Ok(())
+pub async fn update_records(mut records: Vec<Record<AirtableSyncedUser>>) -> Result<()> {
+    for record in &mut records {
+        record.fields.wipe_credentials_if_done();
+    }
+
+    store().update(records).await
 }
 
-#[derive(Clone, Copy, Debug, Default, Serialize)]
-pub struct AggregateStats {
-    file_count: u64,
-    repl_count: u64,
+/// Ask for a job to be stopped. Jobs that haven't been picked up yet are cancelled straight away,
+/// running ones are cancelled by the worker once it notices.
+pub async fn request_cancellation(record: &Record<AirtableSyncedUser>) -> Result<()> {
+    let mut fields = json!({ "Cancel Requested": true });
+    if record.fields.status == ProcessState::Registered {
+        fields["Status"] = json!(ProcessState::Cancelled);
+        fields["Connection Token"] = json!("");
+        fields["Git Remote Password"] = json!(null);
+    }
+
+    store().patch(&record.id, fields).await
 }
-pub async fn aggregates() -> Result<AggregateStats> {
-    let client = reqwest::Client::new();
+
+/// Re-read a single job's cancellation flag, bypassing the list view.
+pub async fn cancellation_requested(record_id: &str) -> Result<bool> {
+    Ok(fetch_record(record_id).await?.fields.cancel_requested)
+}
+
+/// Re-read a single job, bypassing the list view.
+pub async fn fetch_record(record_id: &str) -> Result<Record<AirtableSyncedUser>> {
+    store().fetch(record_id).await
+}
+
+async fn patch_fields(record_id: &str, fields: Value) -> Result<()> {
+    store().patch(record_id, fields).await
+}
+
+/// Try to take a job for `worker_id` until `expires_at`, if it's still `claimable`.
+///
+/// Airtable has no compare-and-swap, so this is best-effort: it reads the job, checks nobody
+/// holds it, writes the lease, waits out `settle` and reads it back. That catches the usual
+/// race, but a worker that takes longer than `settle` between its read and its write can still
+/// overwrite our lease after we've read it back, and then both think they have the job. The
+/// next [`renew_lease`] tells the loser, and [`crate::lease::Lease`] stops its export, so a
+/// double claim only runs for a lease renewal or so. Before recording an export and emailing
+/// the user, the worker checks it still [holds the lease](holds_lease), so only one does.
+pub async fn claim(
+    record_id: &str,
+    worker_id: &str,
+    expires_at: DateTime<Utc>,
+    settle: std::time::Duration,
+    claimable: impl Fn(&AirtableSyncedUser) -> bool,
+) -> Result<Option<Record<AirtableSyncedUser>>> {
+    let record = fetch_record(record_id).await?;
+    if !claimable(&record.fields) {
+        return Ok(None);
+    }
+
+    patch_fields(
+        record_id,
+        json!({
+            "Worker ID": worker_id,
+            "Lease Expires At": expires_at,
+            "Heartbeat At": Utc::now(),
+        }),
+    )
+    .await?;
+
+    tokio::time::sleep(settle).await;
+
+    let record = fetch_record(record_id).await?;
+    if record.fields.worker_id.as_deref() != Some(worker_id) {
+        return Ok(None);
+    }
+
+    Ok(Some(record))
+}
+
+/// Push back the expiry of a lease `worker_id` holds, which doubles as the job's heartbeat.
+/// Returns whether it still held the lease, it's left alone if not.
+///
+/// Like [`claim`] this reads then writes, so it can race a worker claiming the job at the
+/// same moment. Whichever of the two loses finds out on its next renewal.
+pub async fn renew_lease(
+    record_id: &str,
+    worker_id: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<bool> {
+    if !holds_lease(record_id, worker_id).await? {
+        return Ok(false);
+    }
+
+    patch_fields(
+        record_id,
+        json!({ "Lease Expires At": expires_at, "Heartbeat At": Utc::now() }),
+    )
+    .await?;
+
+    Ok(true)
+}
+
+/// Whether `worker_id` holds the job's lease, read straight from the store.
+pub async fn holds_lease(record_id: &str, worker_id: &str) -> Result<bool> {
+    let record = fetch_record(record_id).await?;
+
+    Ok(record.fields.worker_id.as_deref() == Some(worker_id))
+}
+
+/// Give up a lease `worker_id` holds, so the job can be claimed again straight away.
+pub async fn release_lease(record_id: &str, worker_id: &str) -> Result<()> {
+    if !holds_lease(record_id, worker_id).await? {
+        return Ok(());
+    }
+
+    patch_fields(
+        record_id,
+        json!({ "Worker ID": null, "Lease Expires At": null }),
+    )
+    .await
+}
+
+fn table() -> &'static str {
+    &config::get().airtable.table
+}
+
+fn api_headers() -> Result<HeaderMap> {
     let mut headers = HeaderMap::new();
     headers.insert(
         AUTHORIZATION,
         HeaderValue::from_str(&format!("Bearer {}", var("AIRTABLE_API_KEY")?))?,
     );
 
-    let url = format!(
-        "https://api.airtable.com/v0/{}/{}/{}",
-        var("AIRTABLE_BASE_ID")?,
-        STATISTICS_TABLE,
-        STATISTICS_RECORD
-    );
+    Ok(headers)
+}
+
+fn record_url(table: &str, record_id: &str) -> Result<String> {
+    Ok(format!(
+        "https://api.airtable.com/v0/{}/{table}/{record_id}",
+        var("AIRTABLE_BASE_ID")?
+    ))
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct AggregateStats {
+    file_count: u64,
+    repl_count: u64,
+}
+pub async fn aggregates() -> Result<AggregateStats> {
+    let client = reqwest::Client::new();
+    let config = &config::get().airtable;
 
     let response = client
-        .get(&url)
-        .headers(headers)
+        .get(record_url(
+            &config.statistics_table,
+            &config.statistics_record,
+        )?)
+        .headers(api_headers()?)
         .send()
         .await?
         .json::<Value>()
@@ -144,8 +515,9 @@ pub async fn aggregates() -> Result<AggregateStats> {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum ProcessState {
+    #[default]
     #[serde(rename = "Registered")]
     Registered,
     #[serde(rename = "Collecting repls")]
@@ -188,10 +560,49 @@ pub enum ProcessState {
     /// We took too long and and the token expired.
     #[serde(rename = "Token expired")]
     TokenExpired,
+
+    /// The user called off their export.
+    #[serde(rename = "Cancelled")]
+    Cancelled,
+
+    /// The job's worker kept disappearing, so it was given up on.
+    #[serde(rename = "Gave up")]
+    GaveUp,
 }
-impl Default for ProcessState {
-    fn default() -> Self {
-        Self::Registered
+impl ProcessState {
+    /// Whether the job is still queued or being worked on.
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self,
+            ProcessState::Registered
+                | ProcessState::CollectingRepls
+                | ProcessState::Collected
+                | ProcessState::WaitingInR2
+        )
+    }
+
+    /// Whether the job got the user an archive.
+    pub fn exported(&self) -> bool {
+        matches!(
+            self,
+            ProcessState::R2LinkEmailSent
+                | ProcessState::DownloadedRepls
+                | ProcessState::PartiallyDownloadedRepls
+        )
+    }
+
+    /// Whether the job is over for good. The errored states don't count, since they can be requeued.
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            ProcessState::R2LinkEmailSent
+                | ProcessState::DownloadedRepls
+                | ProcessState::PartiallyDownloadedRepls
+                | ProcessState::NoRepls
+                | ProcessState::TokenExpired
+                | ProcessState::Cancelled
+                | ProcessState::GaveUp
+        )
     }
 }
 impl std::fmt::Display for ProcessState {
@@ -209,7 +620,147 @@ impl std::fmt::Display for ProcessState {
             ProcessState::ErroredR2 => "ErroredR2",
             ProcessState::NoRepls => "NoRepls",
             Self::TokenExpired => "Token expired",
+            Self::Cancelled => "Cancelled",
+            Self::GaveUp => "Gave up",
         };
         write!(f, "{}", value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+    use super::*;
+
+    /// Jobs by record id. A rival set for a job writes its own claim straight after ours, like a
+    /// worker that read the job at the same time and took longer than the settle to write.
+    #[derive(Default)]
+    struct TestJobs {
+        jobs: Mutex<HashMap<String, AirtableSyncedUser>>,
+        rivals: Mutex<HashMap<String, String>>,
+    }
+
+    static JOBS: Lazy<&'static TestJobs> = Lazy::new(|| {
+        let jobs: &'static TestJobs = Box::leak(Box::default());
+        use_store(jobs).unwrap();
+        jobs
+    });
+
+    fn add(record_id: &str, fields: AirtableSyncedUser) {
+        JOBS.jobs
+            .lock()
+            .unwrap()
+            .insert(record_id.to_string(), fields);
+    }
+
+    fn job(record_id: &str) -> AirtableSyncedUser {
+        JOBS.jobs.lock().unwrap()[record_id].clone()
+    }
+
+    #[async_trait]
+    impl JobStore for TestJobs {
+        async fn create(&self, _: AirtableSyncedUser) -> Result<()> {
+            unimplemented!()
+        }
+
+        async fn list(&self) -> Result<Vec<Record<AirtableSyncedUser>>> {
+            unimplemented!()
+        }
+
+        async fn fetch(&self, record_id: &str) -> Result<Record<AirtableSyncedUser>> {
+            Ok(Record {
+                id: record_id.to_string(),
+                fields: job(record_id),
+                created_time: None,
+            })
+        }
+
+        async fn update(&self, _: Vec<Record<AirtableSyncedUser>>) -> Result<()> {
+            unimplemented!()
+        }
+
+        async fn patch(&self, record_id: &str, fields: Value) -> Result<()> {
+            let mut jobs = self.jobs.lock().unwrap();
+            let job = jobs.get_mut(record_id).unwrap();
+
+            if let Some(worker_id) = fields.get("Worker ID") {
+                job.worker_id = worker_id.as_str().map(str::to_string);
+                if let Some(rival) = self.rivals.lock().unwrap().get(record_id) {
+                    job.worker_id = Some(rival.clone());
+                }
+            }
+            if let Some(expires_at) = fields.get("Lease Expires At") {
+                job.lease_expires_at = serde_json::from_value(expires_at.clone())?;
+            }
+
+            Ok(())
+        }
+    }
+
+    async fn claim_for(record_id: &str, worker_id: &str) -> Option<Record<AirtableSyncedUser>> {
+        let expires_at = Utc::now() + chrono::Duration::minutes(5);
+        claim(
+            record_id,
+            worker_id,
+            expires_at,
+            Duration::ZERO,
+            AirtableSyncedUser::is_claimable,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn wins_a_claim_nobody_else_makes() {
+        add("recWon", AirtableSyncedUser::default());
+
+        let claimed = claim_for("recWon", "worker-a").await.unwrap();
+        assert_eq!(claimed.fields.worker_id.as_deref(), Some("worker-a"));
+        assert!(holds_lease("recWon", "worker-a").await.unwrap());
+
+        // Held, so nobody else can have it
+        assert!(claim_for("recWon", "worker-b").await.is_none());
+        assert!(!holds_lease("recWon", "worker-b").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn loses_a_claim_overwritten_by_another_worker() {
+        add("recLost", AirtableSyncedUser::default());
+        JOBS.rivals
+            .lock()
+            .unwrap()
+            .insert("recLost".into(), "worker-b".into());
+
+        assert!(claim_for("recLost", "worker-a").await.is_none());
+        assert_eq!(job("recLost").worker_id.as_deref(), Some("worker-b"));
+
+        let expires_at = Utc::now() + chrono::Duration::minutes(5);
+        assert!(!renew_lease("recLost", "worker-a", expires_at)
+            .await
+            .unwrap());
+        assert!(!holds_lease("recLost", "worker-a").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn takes_over_an_expired_lease() {
+        add(
+            "recExpired",
+            AirtableSyncedUser {
+                worker_id: Some("worker-gone".into()),
+                lease_expires_at: Some(Utc::now() - chrono::Duration::seconds(1)),
+                ..Default::default()
+            },
+        );
+
+        let claimed = claim_for("recExpired", "worker-a").await.unwrap();
+        assert_eq!(claimed.fields.worker_id.as_deref(), Some("worker-a"));
+
+        // The old holder finds out it lost the job when it next renews
+        let expires_at = Utc::now() + chrono::Duration::minutes(5);
+        assert!(!renew_lease("recExpired", "worker-gone", expires_at)
+            .await
+            .unwrap());
+        assert_eq!(job("recExpired").worker_id.as_deref(), Some("worker-a"));
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    path::{Component, Path},
+    str::FromStr,
+};
+
+use anyhow::{format_err, Result};
+use async_compression::tokio::{bufread::ZstdDecoder, write::ZstdEncoder};
+use async_zip::{
+    base::write::ZipFileWriter, tokio::read::fs::ZipFileReader, Compression, ZipEntryBuilder,
+};
+use futures::{AsyncReadExt as _, AsyncWriteExt as _, StreamExt};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+};
+
+use crate::crosisdownload::util::recursively_flatten_dir;
+
+/// Files are streamed from disk in pieces this big, so memory use doesn't depend on file size.
+const READ_BUFFER_SIZE: usize = 256 * 1024; // 256 KiB
+
+/// Deflating these again only burns CPU, so they're stored as-is.
+static ALREADY_COMPRESSED: [&str; 24] = [
+    "7z", "avif", "br", "bz2", "gif", "gz", "heic", "jar", "jpeg", "jpg", "m4a", "mkv", "mov",
+    "mp3", "mp4", "ogg", "png", "rar", "tgz", "webm", "webp", "woff2", "xz", "zip",
+];
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "zip")]
+    Zip,
+    #[serde(rename = "tar.zst")]
+    TarZst,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarZst => "tar.zst",
+        }
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "zip" => Ok(ArchiveFormat::Zip),
+            "tar.zst" | "tzst" => Ok(ArchiveFormat::TarZst),
+            other => Err(format_err!("Unknown archive format `{other}`")),
+        }
+    }
+}
+
+impl std::fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+/// Archive everything under `dir` into a new file at `archive_path`.
+pub async fn make_archive(dir: String, archive_path: String, format: ArchiveFormat) -> Result<()> {
+    let file = fs::File::create(&archive_path).await?;
+    let mut file = write_archive(dir, file, format).await?;
+    file.flush().await?;
+    file.sync_all().await?;
+
+    Ok(())
+}
+
+/// Stream everything under `dir` into `writer`, one entry at a time, and hand the writer back.
+pub async fn write_archive<W: AsyncWrite + Unpin + Send + 'static>(
+    dir: String,
+    writer: W,
+    format: ArchiveFormat,
+) -> Result<W> {
+    match format {
+        ArchiveFormat::Zip => write_zip(dir, writer).await,
+        ArchiveFormat::TarZst => write_tar_zst(dir, writer).await,
+    }
+}
+
+pub async fn write_zip<W: AsyncWrite + Unpin>(dir: String, writer: W) -> Result<W> {
+    let mut writer = ZipFileWriter::with_tokio(writer);
+
+    let files = recursively_flatten_dir(dir.clone()).await?;
+    let file_prefix = dir + "/";
+    let mut buf = vec![0; READ_BUFFER_SIZE];
+
+    for file in files {
+        let name = file.strip_prefix(&file_prefix).unwrap_or(&file);
+        let builder = ZipEntryBuilder::new(name.into(), compression_for(name));
+
+        // Streamed entries always get zip64 headers, so there's no 4 GiB limit per file or archive
+        let mut entry = writer.write_entry_stream(builder).await?;
+        let mut reader = fs::File::open(&file).await?;
+        copy_to_entry(&mut reader, &mut entry, &mut buf).await?;
+        entry.close().await?;
+    }
+
+    Ok(writer.close().await?.into_inner())
+}
+
+pub async fn write_tar_zst<W: AsyncWrite + Unpin + Send + 'static>(
+    dir: String,
+    writer: W,
+) -> Result<W> {
+    let mut builder = tokio_tar::Builder::new(ZstdEncoder::new(writer));
+    builder.mode(tokio_tar::HeaderMode::Deterministic);
+
+    let files = recursively_flatten_dir(dir.clone()).await?;
+    let file_prefix = dir + "/";
+
+    for file in files {
+        let name = file.strip_prefix(&file_prefix).unwrap_or(&file);
+        builder.append_path_with_name(&file, name).await?;
+    }
+
+    let mut encoder = builder.into_inner().await?;
+    encoder.shutdown().await?;
+
+    Ok(encoder.into_inner())
+}
+
+/// Extract the entries of an archive whose names pass `keep` into `dest`.
+/// Returns how many files were extracted.
+pub async fn extract_archive<F: Fn(&str) -> bool>(
+    archive_path: &str,
+    dest: &str,
+    format: ArchiveFormat,
+    keep: F,
+) -> Result<usize> {
+    match format {
+        ArchiveFormat::Zip => extract_zip(archive_path, dest, keep).await,
+        ArchiveFormat::TarZst => extract_tar_zst(archive_path, dest, keep).await,
+    }
+}
+
+async fn extract_zip<F: Fn(&str) -> bool>(
+    archive_path: &str,
+    dest: &str,
+    keep: F,
+) -> Result<usize> {
+    let reader = ZipFileReader::new(archive_path).await?;
+    let mut buf = vec![0; READ_BUFFER_SIZE];
+    let mut extracted = 0;
+
+    for index in 0..reader.file().entries().len() {
+        let entry = &reader.file().entries()[index];
+        let name = entry.filename().as_str()?.to_string();
+
+        if entry.dir()? || !keep(&name) {
+            continue;
+        }
+
+        // Entry names come from our own archives, but never write outside of `dest`
+        if Path::new(&name)
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            warn!("Skipping suspicious archive entry {name}");
+            continue;
+        }
+
+        let out_path = format!("{dest}/{name}");
+        if let Some(parent) = Path::new(&out_path).parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let mut entry_reader = reader.reader_without_entry(index).await?;
+        let mut file = fs::File::create(&out_path).await?;
+
+        loop {
+            let read = entry_reader.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+
+            file.write_all(&buf[..read]).await?;
+        }
+
+        file.flush().await?;
+        extracted += 1;
+    }
+
+    Ok(extracted)
+}
+
+async fn extract_tar_zst<F: Fn(&str) -> bool>(
+    archive_path: &str,
+    dest: &str,
+    keep: F,
+) -> Result<usize> {
+    let file = fs::File::open(archive_path).await?;
+    let mut archive = tokio_tar::Archive::new(ZstdDecoder::new(BufReader::new(file)));
+    let mut entries = archive.entries()?;
+    let mut extracted = 0;
+
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+
+        if !keep(&name) {
+            continue;
+        }
+
+        // `unpack_in` refuses anything that would land outside of `dest`
+        if entry.unpack_in(dest).await? {
+            extracted += 1;
+        } else {
+            warn!("Skipping suspicious archive entry {name}");
+        }
+    }
+
+    Ok(extracted)
+}
+
+async fn copy_to_entry<R, E>(reader: &mut R, entry: &mut E, buf: &mut [u8]) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    E: futures::AsyncWrite + Unpin,
+{
+    let mut total = 0;
+
+    loop {
+        let read = reader.read(buf).await?;
+        if read == 0 {
+            break;
+        }
+
+        entry.write_all(&buf[..read]).await?;
+        total += read as u64;
+    }
+
+    Ok(total)
+}
+
+fn compression_for(name: &str) -> Compression {
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    match extension {
+        Some(ext) if ALREADY_COMPRESSED.contains(&ext.as_str()) => {
+            debug!("Storing {name} without compression");
+            Compression::Stored
+        }
+        _ => Compression::Deflate,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    const WORKSPACE: [(&str, &[u8]); 4] = [
+        ("main.py", b"print('h\xc3\xa9llo')\n"),
+        ("src/util.py", b"def add(a, b):\n    return a + b\n"),
+        ("src/deep/er/notes.md", "# 世界 👋\n".as_bytes()),
+        ("logo.png", &[0x89, b'P', b'N', b'G', 0, 1, 2, 3]),
+    ];
+
+    async fn read_tree(dir: &Path) -> BTreeMap<String, Vec<u8>> {
+        let dir = dir.display().to_string();
+        let mut tree = BTreeMap::new();
+
+        for file in recursively_flatten_dir(dir.clone()).await.unwrap() {
+            let name = file.strip_prefix(&format!("{dir}/")).unwrap().to_string();
+            tree.insert(name, fs::read(&file).await.unwrap());
+        }
+
+        tree
+    }
+
+    async fn round_trip(format: ArchiveFormat) {
+        let tmp = tempfile::tempdir().unwrap();
+        let workspace = tmp.path().join("workspace");
+        for (name, contents) in WORKSPACE {
+            let path = workspace.join(name);
+            fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            fs::write(path, contents).await.unwrap();
+        }
+
+        let archive = tmp.path().join(format!("export.{}", format.extension()));
+        let archive = archive.display().to_string();
+        make_archive(workspace.display().to_string(), archive.clone(), format)
+            .await
+            .unwrap();
+
+        let out = tmp.path().join("out");
+        let extracted = extract_archive(&archive, &out.display().to_string(), format, |_| true)
+            .await
+            .unwrap();
+
+        assert_eq!(extracted, WORKSPACE.len());
+        assert_eq!(read_tree(&out).await, read_tree(&workspace).await);
+    }
+
+    #[tokio::test]
+    async fn zip_round_trip() {
+        round_trip(ArchiveFormat::Zip).await;
+    }
+
+    #[tokio::test]
+    async fn tar_zst_round_trip() {
+        round_trip(ArchiveFormat::TarZst).await;
+    }
+
+    async fn write_traversing_zip(path: &str) {
+        let mut writer = ZipFileWriter::with_tokio(fs::File::create(path).await.unwrap());
+        for (name, contents) in [("../evil.txt", b"gotcha".as_slice()), ("ok.txt", b"fine")] {
+            let builder = ZipEntryBuilder::new(name.into(), Compression::Stored);
+            writer.write_entry_whole(builder, contents).await.unwrap();
+        }
+        writer.close().await.unwrap();
+    }
+
+    async fn write_traversing_tar_zst(path: &str) {
+        let file = fs::File::create(path).await.unwrap();
+        let mut builder = tokio_tar::Builder::new(ZstdEncoder::new(file));
+
+        for (name, contents) in [("../evil.txt", b"gotcha".as_slice()), ("ok.txt", b"fine")] {
+            // `set_path` won't take `..`, so the name is written in by hand
+            let mut header = tokio_tar::Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, contents).await.unwrap();
+        }
+
+        let mut encoder = builder.into_inner().await.unwrap();
+        encoder.shutdown().await.unwrap();
+    }
+
+    async fn refuses_path_traversal(format: ArchiveFormat) {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join(format!("evil.{}", format.extension()));
+        let archive = archive.display().to_string();
+        match format {
+            ArchiveFormat::Zip => write_traversing_zip(&archive).await,
+            ArchiveFormat::TarZst => write_traversing_tar_zst(&archive).await,
+        }
+
+        let out = tmp.path().join("out");
+        fs::create_dir_all(&out).await.unwrap();
+        let extracted = extract_archive(&archive, &out.display().to_string(), format, |_| true)
+            .await
+            .unwrap();
+
+        assert_eq!(extracted, 1);
+        assert!(!tmp.path().join("evil.txt").exists());
+        assert_eq!(fs::read(out.join("ok.txt")).await.unwrap(), b"fine");
+    }
+
+    #[tokio::test]
+    async fn zip_refuses_path_traversal() {
+        refuses_path_traversal(ArchiveFormat::Zip).await;
+    }
+
+    #[tokio::test]
+    async fn tar_zst_refuses_path_traversal() {
+        refuses_path_traversal(ArchiveFormat::TarZst).await;
+    }
+}
@@ -0,0 +1,132 @@
+use anyhow::Result;
+use log::{info, warn};
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    airtable, config,
+    lease::{Lease, WORKER_ID},
+    shutdown,
+};
+
+/// Watches a job's record for the user asking to cancel it. Stops watching when dropped.
+///
+/// The worker shutting down stops a job too: it counts as cancelled straight away, so the job
+/// stops between repls, but [`CancelWatch::cancelled`] waits out the shutdown grace period so
+/// the repl being downloaded has a chance to finish.
+///
+/// Losing the job's [`Lease`] stops it straight away, since another worker is running it now.
+pub struct CancelWatch {
+    cancelled: watch::Receiver<bool>,
+    lease_lost: watch::Receiver<bool>,
+    leased: bool,
+    task: Option<JoinHandle<()>>,
+}
+
+impl CancelWatch {
+    pub fn spawn(record_id: String) -> Self {
+        let (send, cancelled) = watch::channel(false);
+
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(config::get().worker.cancel_poll());
+
+            loop {
+                interval.tick().await;
+
+                match airtable::cancellation_requested(&record_id).await {
+                    Ok(true) => {
+                        info!("Job {record_id} was cancelled");
+                        let _ = send.send(true);
+                        return;
+                    }
+                    Ok(false) => {}
+                    Err(err) => warn!("Couldn't check if job {record_id} was cancelled: {err:#}"),
+                }
+            }
+        });
+
+        Self {
+            cancelled,
+            lease_lost: watch::channel(false).1,
+            leased: false,
+            task: Some(task),
+        }
+    }
+
+    /// Also stop if `lease` is lost.
+    pub fn with_lease(mut self, lease: &Lease) -> Self {
+        self.lease_lost = lease.lost();
+        self.leased = true;
+        self
+    }
+
+    /// A watch that never fires, for downloading outside of the job queue.
+    pub fn never() -> Self {
+        let (_, cancelled) = watch::channel(false);
+
+        Self {
+            cancelled,
+            lease_lost: watch::channel(false).1,
+            leased: false,
+            task: None,
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.by_user() || self.lease_lost() || shutdown::is_requested()
+    }
+
+    /// Whether it was the user that cancelled the job, rather than the worker shutting down.
+    pub fn by_user(&self) -> bool {
+        *self.cancelled.borrow()
+    }
+
+    /// Whether another worker took the job over, in which case the job's record is theirs now.
+    pub fn lease_lost(&self) -> bool {
+        *self.lease_lost.borrow()
+    }
+
+    /// Check with the job store that the job is still this worker's, rather than waiting for the
+    /// next lease renewal to say. Always true for jobs run without a lease.
+    pub async fn lease_held(&self, record_id: &str) -> Result<bool> {
+        if !self.leased {
+            return Ok(true);
+        }
+        if self.lease_lost() {
+            return Ok(false);
+        }
+
+        airtable::holds_lease(record_id, &WORKER_ID).await
+    }
+
+    /// Resolves once the job is cancelled (or the shutdown grace period is over), and never if
+    /// it isn't.
+    pub async fn cancelled(&self) {
+        let mut cancelled = self.cancelled.clone();
+        let by_user = async {
+            if cancelled.wait_for(|cancelled| *cancelled).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        };
+
+        let mut lease_lost = self.lease_lost.clone();
+        let lease_lost = async {
+            if lease_lost.wait_for(|lost| *lost).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        };
+
+        tokio::select! {
+            _ = by_user => {}
+            _ = lease_lost => {}
+            _ = shutdown::grace_expired() => {}
+        }
+    }
+}
+
+impl Drop for CancelWatch {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,660 @@
+//! Everything that differs between instances. It's read from the TOML file at `CONFIG_FILE`
+//! (default `takeout.toml`, which can be left out), then the environment variables listed on
+//! each field override it, and then it's checked over once at startup.
+//!
+//! API keys and credentials the client libraries pick up themselves (`AIRTABLE_API_KEY`,
+//! `AIRTABLE_BASE_ID`, `LOOPS_API_KEY`, `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`) stay
+//! in the environment.
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{format_err, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use dotenv::var;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// R2 won't take multipart upload parts smaller than this, other than the last.
+const MIN_PART_SIZE_MIB: usize = 5;
+
+/// Load and validate the config, if that hasn't happened yet.
+pub fn init() -> Result<&'static Config> {
+    CONFIG.get_or_try_init(Config::load)
+}
+
+/// The config, which is loaded on first use if [`init`] wasn't called.
+pub fn get() -> &'static Config {
+    init().expect("a valid config")
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub airtable: AirtableConfig,
+    pub storage: StorageConfig,
+    pub email: EmailConfig,
+    pub download: DownloadConfig,
+    pub retry: RetryConfig,
+    pub worker: WorkerConfig,
+    pub rate_limit: RateLimitConfig,
+    pub replit: ReplitConfig,
+    pub secrets: SecretsConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AirtableConfig {
+    /// The jobs table. `AIRTABLE_TABLE`.
+    pub table: String,
+
+    /// The table holding the aggregate statistics. `AIRTABLE_STATISTICS_TABLE`.
+    pub statistics_table: String,
+
+    /// The one record in it. `AIRTABLE_STATISTICS_RECORD`.
+    pub statistics_record: String,
+}
+
+impl Default for AirtableConfig {
+    fn default() -> Self {
+        Self {
+            table: "tblZABr7qbdjjZo1G".to_string(),
+            statistics_table: "tbl2RjULxUSRXJZ39".to_string(),
+            statistics_record: "recpWEjc0zLoKEtZP".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// `R2_ACCOUNT_ID`.
+    pub account_id: String,
+
+    /// `R2_BUCKET`.
+    pub bucket: String,
+
+    /// Part size for uploading files. `R2_CHUNK_SIZE_MIB`, default 100.
+    pub chunk_size_mib: usize,
+
+    /// Parts of a file uploaded at once. `R2_CONCURRENT_UPLOADS`, default 8.
+    pub concurrent_uploads: usize,
+
+    /// Part size for streamed uploads, which hold every part in flight in memory.
+    /// `R2_STREAM_CHUNK_SIZE_MIB`, default 16.
+    pub stream_chunk_size_mib: usize,
+
+    /// Parts of a stream uploaded at once. `R2_STREAM_CONCURRENT_UPLOADS`, default 4.
+    pub stream_concurrent_uploads: usize,
+
+    /// How long download links work for, at most 7 days. `LINK_EXPIRY_DAYS`, default 7.
+    pub link_expiry_days: u32,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            account_id: "90e2da927f7b2f6c30f10f86d1b5e679".to_string(),
+            bucket: "replit-takeout".to_string(),
+            chunk_size_mib: 100,
+            concurrent_uploads: 8,
+            stream_chunk_size_mib: 16,
+            stream_concurrent_uploads: 4,
+            link_expiry_days: 7,
+        }
+    }
+}
+
+impl StorageConfig {
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size_mib * 1024 * 1024
+    }
+
+    pub fn stream_chunk_size(&self) -> usize {
+        self.stream_chunk_size_mib * 1024 * 1024
+    }
+
+    pub fn link_expiry_secs(&self) -> u32 {
+        self.link_expiry_days * 24 * 60 * 60
+    }
+}
+
+/// Loops transactional email IDs.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmailConfig {
+    /// `LOOPS_GREET_ID`.
+    pub greet: String,
+
+    /// `LOOPS_PARTIAL_SUCCESS_ID`.
+    pub partial_success: String,
+
+    /// `LOOPS_SUCCESS_ID`.
+    pub success: String,
+
+    /// `LOOPS_NO_REPLS_ID`.
+    pub no_repls: String,
+
+    /// `LOOPS_FAILURE_ID`.
+    pub failure: String,
+}
+
+impl Default for EmailConfig {
+    fn default() -> Self {
+        Self {
+            greet: "cm0pegyzg01xquhjkf7r3fh85".to_string(),
+            partial_success: "cm0pgg7bw002gp8uk5ufqfvov".to_string(),
+            success: "cm0pg42wh002u3ml1d4et51zp".to_string(),
+            no_repls: "cm0pgqger00bbo6ie7wyia98y".to_string(),
+            failure: "cm0pgxpy303hf53mv955aoqls".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloadConfig {
+    /// Files of a repl downloaded at once. `MAX_FILE_PARALLELISM`, default 20.
+    pub max_file_parallelism: usize,
+
+    /// How long a repl download can go without progress once it's used up its time budget.
+    /// `REPL_STALL_SECS`, default 180.
+    pub repl_stall_secs: u64,
+
+    /// The most time any one repl gets. `REPL_MAX_TIMEOUT_MINS`, default 120.
+    pub repl_max_timeout_mins: u64,
+
+    /// Files bigger than this are left out. `MAX_FILE_BYTES`, default 50 MB.
+    pub max_file_bytes: u64,
+
+    /// OT versions fetched per request, so long histories never sit in memory whole.
+    /// `OT_FETCH_WINDOW`, default 1000.
+    pub ot_fetch_window: u32,
+
+    /// Where job workspaces go, a directory only they should use. `WORKSPACE_ROOT`, default
+    /// `/tmp/replit-takeout`.
+    pub workspace_root: PathBuf,
+
+    /// How much disk is kept free for everything else. `MIN_FREE_DISK_MB`, default 1024.
+    pub min_free_disk_mb: u64,
+
+    /// How long a job waits for disk to free up when there isn't room for it.
+    /// `DISK_DEFER_MINS`, default 15.
+    pub disk_defer_mins: u64,
+
+    /// Record crosis sessions into this directory to replay later, off unless it's set. Traces
+    /// hold the repl's files, so only turn this on to chase down a bug. `CROSIS_TRACE_DIR`.
+    pub trace_dir: Option<PathBuf>,
+
+    /// Only record these repls, or every repl when it's empty. `CROSIS_TRACE_REPL_IDS`, comma
+    /// separated.
+    pub trace_repl_ids: Vec<String>,
+
+    /// The hosts users can have their repls pushed to, always over https. Empty turns git pushes
+    /// off. `GIT_REMOTE_HOSTS`, comma separated, default GitHub, GitLab, Bitbucket and Codeberg.
+    pub git_remote_hosts: Vec<String>,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            max_file_parallelism: 20,
+            repl_stall_secs: 3 * 60,
+            repl_max_timeout_mins: 2 * 60,
+            max_file_bytes: 50_000_000,
+            ot_fetch_window: 1000,
+            workspace_root: PathBuf::from("/tmp/replit-takeout"),
+            min_free_disk_mb: 1024,
+            disk_defer_mins: 15,
+            trace_dir: None,
+            trace_repl_ids: vec![],
+            git_remote_hosts: ["github.com", "gitlab.com", "bitbucket.org", "codeberg.org"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+}
+
+impl DownloadConfig {
+    pub fn repl_stall(&self) -> Duration {
+        Duration::from_secs(self.repl_stall_secs)
+    }
+
+    pub fn repl_max_timeout(&self) -> Duration {
+        Duration::from_secs(self.repl_max_timeout_mins * 60)
+    }
+
+    pub fn min_free_disk(&self) -> u64 {
+        self.min_free_disk_mb * 1024 * 1024
+    }
+
+    pub fn disk_defer(&self) -> Duration {
+        Duration::from_secs(self.disk_defer_mins * 60)
+    }
+
+    /// Where to record `repl_id`'s crosis session, if it should be.
+    pub fn trace_dir_for(&self, repl_id: &str) -> Option<&Path> {
+        let wanted =
+            self.trace_repl_ids.is_empty() || self.trace_repl_ids.iter().any(|id| id == repl_id);
+
+        self.trace_dir.as_deref().filter(|_| wanted)
+    }
+}
+
+/// See [`crate::retry::RetryPolicy`].
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// `RETRY_ATTEMPTS`, default 2.
+    pub attempts: u32,
+
+    /// `RETRY_BACKOFF_SECS`, default 30.
+    pub backoff_secs: u64,
+
+    /// `FOLLOW_UP_JOBS`, default 1.
+    pub follow_ups: usize,
+
+    /// `FOLLOW_UP_DELAY_MINS`, default 60.
+    pub follow_up_delay_mins: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 2,
+            backoff_secs: 30,
+            follow_ups: 1,
+            follow_up_delay_mins: 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerConfig {
+    /// How long the repl being downloaded gets to finish on shutdown. `SHUTDOWN_GRACE_SECS`, default 90.
+    pub shutdown_grace_secs: u64,
+
+    /// How long a claim on a job lasts without being renewed. `LEASE_SECS`, default 300.
+    pub lease_secs: u64,
+
+    /// How often held leases are renewed. `LEASE_RENEW_SECS`, default 60.
+    pub lease_renew_secs: u64,
+
+    /// How long to wait after claiming a job before checking the claim stuck. `CLAIM_SETTLE_SECS`, default 3.
+    pub claim_settle_secs: u64,
+
+    /// How long a running job can go without a heartbeat before it's reaped. `STALE_JOB_SECS`, default 600.
+    pub stale_job_secs: u64,
+
+    /// How many times a job can be reaped before it's given up on. `MAX_JOB_ATTEMPTS`, default 3.
+    pub max_job_attempts: usize,
+
+    /// How often the job store is checked for stale jobs. `REAP_INTERVAL_SECS`, default 120.
+    pub reap_interval_secs: u64,
+
+    /// How often a running job checks whether the user cancelled it. `CANCEL_POLL_SECS`, default 10.
+    pub cancel_poll_secs: u64,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            shutdown_grace_secs: 90,
+            lease_secs: 5 * 60,
+            lease_renew_secs: 60,
+            claim_settle_secs: 3,
+            stale_job_secs: 10 * 60,
+            max_job_attempts: 3,
+            reap_interval_secs: 2 * 60,
+            cancel_poll_secs: 10,
+        }
+    }
+}
+
+impl WorkerConfig {
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs)
+    }
+
+    pub fn lease(&self) -> Duration {
+        Duration::from_secs(self.lease_secs)
+    }
+
+    pub fn lease_renew(&self) -> Duration {
+        Duration::from_secs(self.lease_renew_secs)
+    }
+
+    pub fn claim_settle(&self) -> Duration {
+        Duration::from_secs(self.claim_settle_secs)
+    }
+
+    pub fn stale_after(&self) -> Duration {
+        Duration::from_secs(self.stale_job_secs)
+    }
+
+    pub fn reap_interval(&self) -> Duration {
+        Duration::from_secs(self.reap_interval_secs)
+    }
+
+    pub fn cancel_poll(&self) -> Duration {
+        Duration::from_secs(self.cancel_poll_secs)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// `RATE_LIMIT_IP_BURST`, default 60.
+    pub ip_burst: f64,
+
+    /// `RATE_LIMIT_IP_PER_MINUTE`, default 60.
+    pub ip_per_minute: f64,
+
+    /// `RATE_LIMIT_ACCOUNT_BURST`, default 20.
+    pub account_burst: f64,
+
+    /// `RATE_LIMIT_ACCOUNT_PER_MINUTE`, default 30.
+    pub account_per_minute: f64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            ip_burst: 60.0,
+            ip_per_minute: 60.0,
+            account_burst: 20.0,
+            account_per_minute: 30.0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplitConfig {
+    /// Where Replit is, which only ever changes to point at a mock. `REPLIT_URL`.
+    pub url: String,
+}
+
+impl Default for ReplitConfig {
+    fn default() -> Self {
+        Self {
+            url: "https://replit.com".to_string(),
+        }
+    }
+}
+
+/// Better left to the environment than written in the file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecretsConfig {
+    /// Bearer token for the admin API, which is off without it. `ADMIN_TOKEN`.
+    pub admin_token: Option<String>,
+
+    /// Base64 encoded 32 byte AES-256-GCM key for secrets in the job store. Required.
+    /// `TOKEN_ENCRYPTION_KEY`.
+    pub token_encryption_key: Option<String>,
+}
+
+impl Config {
+    fn load() -> Result<Self> {
+        let path = var("CONFIG_FILE").unwrap_or_else(|_| "takeout.toml".to_string());
+
+        let mut config: Config = match std::fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents).with_context(|| format!("Parsing {path}"))?,
+            Err(err)
+                if err.kind() == std::io::ErrorKind::NotFound && var("CONFIG_FILE").is_err() =>
+            {
+                Config::default()
+            }
+            Err(err) => return Err(err).with_context(|| format!("Reading {path}")),
+        };
+
+        config.override_from_env()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn override_from_env(&mut self) -> Result<()> {
+        let airtable = &mut self.airtable;
+        env_override(&mut airtable.table, "AIRTABLE_TABLE")?;
+        env_override(&mut airtable.statistics_table, "AIRTABLE_STATISTICS_TABLE")?;
+        env_override(
+            &mut airtable.statistics_record,
+            "AIRTABLE_STATISTICS_RECORD",
+        )?;
+
+        let storage = &mut self.storage;
+        env_override(&mut storage.account_id, "R2_ACCOUNT_ID")?;
+        env_override(&mut storage.bucket, "R2_BUCKET")?;
+        env_override(&mut storage.chunk_size_mib, "R2_CHUNK_SIZE_MIB")?;
+        env_override(&mut storage.concurrent_uploads, "R2_CONCURRENT_UPLOADS")?;
+        env_override(
+            &mut storage.stream_chunk_size_mib,
+            "R2_STREAM_CHUNK_SIZE_MIB",
+        )?;
+        env_override(
+            &mut storage.stream_concurrent_uploads,
+            "R2_STREAM_CONCURRENT_UPLOADS",
+        )?;
+        env_override(&mut storage.link_expiry_days, "LINK_EXPIRY_DAYS")?;
+
+        let email = &mut self.email;
+        env_override(&mut email.greet, "LOOPS_GREET_ID")?;
+        env_override(&mut email.partial_success, "LOOPS_PARTIAL_SUCCESS_ID")?;
+        env_override(&mut email.success, "LOOPS_SUCCESS_ID")?;
+        env_override(&mut email.no_repls, "LOOPS_NO_REPLS_ID")?;
+        env_override(&mut email.failure, "LOOPS_FAILURE_ID")?;
+
+        let download = &mut self.download;
+        env_override(&mut download.max_file_parallelism, "MAX_FILE_PARALLELISM")?;
+        env_override(&mut download.repl_stall_secs, "REPL_STALL_SECS")?;
+        env_override(&mut download.repl_max_timeout_mins, "REPL_MAX_TIMEOUT_MINS")?;
+        env_override(&mut download.max_file_bytes, "MAX_FILE_BYTES")?;
+        env_override(&mut download.ot_fetch_window, "OT_FETCH_WINDOW")?;
+        env_override(&mut download.workspace_root, "WORKSPACE_ROOT")?;
+        env_override(&mut download.min_free_disk_mb, "MIN_FREE_DISK_MB")?;
+        env_override(&mut download.disk_defer_mins, "DISK_DEFER_MINS")?;
+        env_override_opt(&mut download.trace_dir, "CROSIS_TRACE_DIR");
+        env_override_list(&mut download.trace_repl_ids, "CROSIS_TRACE_REPL_IDS");
+        env_override_list(&mut download.git_remote_hosts, "GIT_REMOTE_HOSTS");
+
+        let retry = &mut self.retry;
+        env_override(&mut retry.attempts, "RETRY_ATTEMPTS")?;
+        env_override(&mut retry.backoff_secs, "RETRY_BACKOFF_SECS")?;
+        env_override(&mut retry.follow_ups, "FOLLOW_UP_JOBS")?;
+        env_override(&mut retry.follow_up_delay_mins, "FOLLOW_UP_DELAY_MINS")?;
+
+        let worker = &mut self.worker;
+        env_override(&mut worker.shutdown_grace_secs, "SHUTDOWN_GRACE_SECS")?;
+        env_override(&mut worker.lease_secs, "LEASE_SECS")?;
+        env_override(&mut worker.lease_renew_secs, "LEASE_RENEW_SECS")?;
+        env_override(&mut worker.claim_settle_secs, "CLAIM_SETTLE_SECS")?;
+        env_override(&mut worker.stale_job_secs, "STALE_JOB_SECS")?;
+        env_override(&mut worker.max_job_attempts, "MAX_JOB_ATTEMPTS")?;
+        env_override(&mut worker.reap_interval_secs, "REAP_INTERVAL_SECS")?;
+        env_override(&mut worker.cancel_poll_secs, "CANCEL_POLL_SECS")?;
+
+        let rate_limit = &mut self.rate_limit;
+        env_override(&mut rate_limit.ip_burst, "RATE_LIMIT_IP_BURST")?;
+        env_override(&mut rate_limit.ip_per_minute, "RATE_LIMIT_IP_PER_MINUTE")?;
+        env_override(&mut rate_limit.account_burst, "RATE_LIMIT_ACCOUNT_BURST")?;
+        env_override(
+            &mut rate_limit.account_per_minute,
+            "RATE_LIMIT_ACCOUNT_PER_MINUTE",
+        )?;
+
+        env_override(&mut self.replit.url, "REPLIT_URL")?;
+
+        let secrets = &mut self.secrets;
+        env_override_opt(&mut secrets.admin_token, "ADMIN_TOKEN");
+        env_override_opt(&mut secrets.token_encryption_key, "TOKEN_ENCRYPTION_KEY");
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut problems = vec![];
+        let mut check = |ok: bool, problem: &str| {
+            if !ok {
+                problems.push(problem.to_string());
+            }
+        };
+
+        check(!self.airtable.table.is_empty(), "airtable.table is empty");
+        check(
+            !self.airtable.statistics_table.is_empty()
+                && !self.airtable.statistics_record.is_empty(),
+            "airtable.statistics_table and airtable.statistics_record can't be empty",
+        );
+
+        let storage = &self.storage;
+        check(
+            !storage.account_id.is_empty() && !storage.bucket.is_empty(),
+            "storage.account_id and storage.bucket can't be empty",
+        );
+        check(
+            storage.chunk_size_mib >= MIN_PART_SIZE_MIB
+                && storage.stream_chunk_size_mib >= MIN_PART_SIZE_MIB,
+            "storage chunk sizes must be at least 5 MiB",
+        );
+        check(
+            storage.concurrent_uploads > 0 && storage.stream_concurrent_uploads > 0,
+            "storage concurrent uploads must be at least 1",
+        );
+        check(
+            (1..=7).contains(&storage.link_expiry_days),
+            "storage.link_expiry_days must be between 1 and 7",
+        );
+
+        let email = &self.email;
+        check(
+            [
+                &email.greet,
+                &email.partial_success,
+                &email.success,
+                &email.no_repls,
+                &email.failure,
+            ]
+            .iter()
+            .all(|id| !id.is_empty()),
+            "every email transactional ID must be set",
+        );
+
+        let download = &self.download;
+        check(
+            download.max_file_parallelism > 0,
+            "download.max_file_parallelism must be at least 1",
+        );
+        check(
+            download.repl_stall_secs > 0 && download.repl_max_timeout_mins > 0,
+            "download.repl_stall_secs and download.repl_max_timeout_mins must be at least 1",
+        );
+        check(
+            download.max_file_bytes > 0,
+            "download.max_file_bytes must be at least 1",
+        );
+        check(
+            download.ot_fetch_window > 0,
+            "download.ot_fetch_window must be at least 1",
+        );
+        check(
+            !download.workspace_root.as_os_str().is_empty(),
+            "download.workspace_root is empty",
+        );
+        check(
+            download.disk_defer_mins > 0,
+            "download.disk_defer_mins must be at least 1",
+        );
+
+        let worker = &self.worker;
+        check(
+            worker.lease_renew_secs > 0 && worker.lease_renew_secs < worker.lease_secs,
+            "worker.lease_renew_secs must be shorter than worker.lease_secs",
+        );
+        check(
+            worker.claim_settle_secs < worker.lease_secs,
+            "worker.claim_settle_secs must be shorter than worker.lease_secs",
+        );
+        check(
+            worker.stale_job_secs > worker.lease_secs,
+            "worker.stale_job_secs must be longer than worker.lease_secs",
+        );
+        check(
+            worker.max_job_attempts > 0,
+            "worker.max_job_attempts must be at least 1",
+        );
+        check(
+            worker.reap_interval_secs > 0 && worker.cancel_poll_secs > 0,
+            "worker.reap_interval_secs and worker.cancel_poll_secs must be at least 1",
+        );
+
+        let rate_limit = &self.rate_limit;
+        check(
+            rate_limit.ip_burst >= 1.0 && rate_limit.account_burst >= 1.0,
+            "rate limit bursts must be at least 1",
+        );
+        check(
+            rate_limit.ip_per_minute >= 0.0 && rate_limit.account_per_minute >= 0.0,
+            "rate limits can't be negative",
+        );
+
+        check(
+            reqwest::Url::parse(&self.replit.url)
+                .is_ok_and(|url| matches!(url.scheme(), "http" | "https")),
+            "replit.url must be an http(s) URL",
+        );
+
+        // Tokens would otherwise have nowhere safe to go
+        check(
+            self.secrets
+                .token_encryption_key
+                .as_ref()
+                .is_some_and(|key| STANDARD.decode(key).is_ok_and(|key| key.len() == 32)),
+            "secrets.token_encryption_key must be set to a base64 encoded 32 byte key",
+        );
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format_err!("Invalid config: {}", problems.join("; ")))
+        }
+    }
+}
+
+fn env_override<T: FromStr>(field: &mut T, name: &str) -> Result<()>
+where
+    T::Err: Display,
+{
+    if let Ok(value) = var(name) {
+        *field = value
+            .parse()
+            .map_err(|err| format_err!("{name} isn't valid: {err}"))?;
+    }
+
+    Ok(())
+}
+
+fn env_override_list(field: &mut Vec<String>, name: &str) {
+    if let Ok(value) = var(name) {
+        *field = value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect();
+    }
+}
+
+fn env_override_opt<T: From<String>>(field: &mut Option<T>, name: &str) {
+    if let Ok(value) = var(name) {
+        *field = Some(value).filter(|value| !value.is_empty()).map(T::from);
+    }
+}
@@ -1,31 +1,40 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use async_trait::async_trait;
 use crosis::{
     ConnectionMetadataFetcher, FetchConnectionMetadataError, FetchConnectionMetadataResult,
 };
 use reqwest::Client;
+use tracing::{error, warn};
 
 pub struct CookieJarConnectionMetadataFetcher {
     pub client: Client,
     pub replid: String,
+
+    /// Connection metadata is fetched again every time crosis reconnects.
+    pub fetches: AtomicUsize,
 }
 
 #[async_trait]
 impl ConnectionMetadataFetcher for CookieJarConnectionMetadataFetcher {
     async fn fetch(&self) -> FetchConnectionMetadataResult {
+        if self.fetches.fetch_add(1, Ordering::Relaxed) > 0 {
+            crate::metrics::record_crosis_reconnect();
+        }
+
         let response = match self
             .client
-            .post(format!(
-                "https://replit.com/data/repls/{}/get_connection_metadata",
+            .post(crate::replit::url(&format!(
+                "/data/repls/{}/get_connection_metadata",
                 self.replid
-            ))
+            )))
             .body("{}")
             .send()
             .await
         {
             Ok(resp) => resp,
-            // TODO: log error once tracing
             Err(err) => {
-                eprintln!("{}", err);
+                error!(replid = %self.replid, "Connection metadata request failed: {err}");
                 return Err(FetchConnectionMetadataError::Abort);
             }
         };
@@ -35,19 +44,17 @@ impl ConnectionMetadataFetcher for CookieJarConnectionMetadataFetcher {
                 return Err(FetchConnectionMetadataError::Retriable);
             }
 
-            // TODO: log error once tracing
-
             match response.text().await.as_ref().map(|txt| txt.as_str()) {
                 Ok("Repl temporarily unavailable") => {
-                    eprintln!("Repl temporarily unavailable");
+                    warn!(replid = %self.replid, "Repl temporarily unavailable");
                     return Err(FetchConnectionMetadataError::Retriable);
                 }
                 Err(err) => {
-                    eprintln!("{err:#?}");
+                    error!(replid = %self.replid, "Couldn't read the connection metadata error: {err}");
                     return Err(FetchConnectionMetadataError::Abort);
                 }
                 Ok(other) => {
-                    eprintln!("Connection metadata request failed: {other}");
+                    error!(replid = %self.replid, "Connection metadata request failed: {other}");
                     return Err(FetchConnectionMetadataError::Abort);
                 }
             }
@@ -55,9 +62,8 @@ impl ConnectionMetadataFetcher for CookieJarConnectionMetadataFetcher {
 
         match response.json().await {
             Ok(resp) => Ok(resp),
-            // TODO: log error once tracing
             Err(err) => {
-                eprintln!("{}", err);
+                error!(replid = %self.replid, "Invalid connection metadata: {err}");
                 return Err(FetchConnectionMetadataError::Abort);
             }
         }
@@ -1,21 +1,18 @@
 mod metadata;
+pub mod progress;
+pub mod trace;
 pub mod util;
 
-pub use util::make_zip;
-
 use std::{
     io::ErrorKind,
     path::Path,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
+    sync::{atomic::AtomicUsize, Arc},
 };
 
 use anyhow::{format_err, Result};
 use crosis::{
     goval::{self, command::Body, Command, StatResult},
-    Channel, Client,
+    Client,
 };
 use git2::{Repository, Signature, Time};
 use log::{debug, error, trace, warn};
@@ -23,13 +20,18 @@ use metadata::CookieJarConnectionMetadataFetcher;
 use ropey::Rope;
 use serde::Serialize;
 use time::OffsetDateTime;
+use tracing::Instrument;
 // use serde::Serialize;
 use tokio::{
     fs,
-    io::AsyncWriteExt,
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
     sync::{OwnedSemaphorePermit, Semaphore},
 };
-use util::{do_ot, download_repl_zip, normalize_ts, recursively_flatten_dir};
+
+use crate::{cancel::CancelWatch, config};
+use progress::DownloadProgress;
+use trace::{Recorder, TraceHeader, TracedChannel, Tracer};
+use util::{download_repl_zip, normalize_ts, recursively_flatten_dir, OtReplay};
 
 // Files to ignore for history and commits
 static NO_GO: [&str; 28] = [
@@ -63,8 +65,6 @@ static NO_GO: [&str; 28] = [
     "zig-out",
 ];
 
-const MAX_FILE_PARALLELISM: usize = 20;
-
 #[derive(Clone)]
 pub struct DownloadLocations {
     pub main: String,
@@ -73,6 +73,7 @@ pub struct DownloadLocations {
     pub ot: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DownloadStatus {
     Full,
     NoHistory,
@@ -88,6 +89,7 @@ pub struct ReplInfo<'a> {
     pub slug: &'a str,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn download(
     client: reqwest::Client,
     replinfo: ReplInfo<'_>,
@@ -95,27 +97,34 @@ pub async fn download(
     download_locations: DownloadLocations,
     ts_offset: i64,
     email: &str,
+    progress: Arc<DownloadProgress>,
+    cancel: &CancelWatch,
 ) -> Result<(DownloadStatus, usize)> {
     debug!("https://replit.com/replid/{}", replinfo.id);
 
-    let file_count = Arc::new(AtomicUsize::new(0));
-
     if let Err(err) = download_crosis(
         client.clone(),
         replinfo,
         download_locations,
         ts_offset,
         email,
-        file_count.clone(),
+        progress.clone(),
+        cancel,
     )
     .await
     {
+        if cancel.is_cancelled() {
+            return Err(err);
+        }
+
         warn!(
             "Failed to download repl history for {}::{} with error: {:#?}",
             replinfo.id, replinfo.slug, err
         );
 
-        if let Err(err_download_zip) = download_repl_zip(client, replinfo, download_zip).await {
+        if let Err(err_download_zip) =
+            download_repl_zip(client, replinfo, download_zip, &progress).await
+        {
             if let Err(err_rm_zip) = fs::remove_file(download_zip).await {
                 if err_rm_zip.kind() != ErrorKind::NotFound {
                     return Err(format_err!("Error downloading repl zip: {err_download_zip}, and error deleting failed download: {err_rm_zip}"));
@@ -125,13 +134,10 @@ pub async fn download(
                 "Error downloading repl zip: {err_download_zip}"
             ))
         } else {
-            Ok((
-                DownloadStatus::NoHistory,
-                file_count.load(Ordering::Relaxed),
-            ))
+            Ok((DownloadStatus::NoHistory, progress.files_done()))
         }
     } else {
-        Ok((DownloadStatus::Full, file_count.load(Ordering::Relaxed)))
+        Ok((DownloadStatus::Full, progress.files_done()))
     }
 }
 
@@ -141,27 +147,63 @@ pub async fn download_crosis(
     download_locations: DownloadLocations,
     ts_offset: i64,
     email: &str,
-    file_count: Arc<AtomicUsize>,
+    progress: Arc<DownloadProgress>,
+    cancel: &CancelWatch,
 ) -> Result<()> {
-    let client = Client::new(Box::new(CookieJarConnectionMetadataFetcher {
+    let mut client = Client::new(Box::new(CookieJarConnectionMetadataFetcher {
         client,
         replid: replinfo.id.to_string(),
+        fetches: AtomicUsize::new(0),
     }));
 
     let close_watcher = client.close_recv.clone();
 
-    tokio::select! {
-        res = download_crosis_internal(client, replinfo, download_locations, ts_offset, email, file_count) => {
+    let recorder = match config::get().download.trace_dir_for(replinfo.id) {
+        Some(dir) => match Recorder::create(dir, TraceHeader::new(replinfo, ts_offset)).await {
+            Ok(recorder) => Some(recorder),
+            Err(err) => {
+                warn!("Couldn't start recording {}: {err:#}", replinfo.id);
+                None
+            }
+        },
+        None => None,
+    };
+    let tracer = recorder
+        .as_ref()
+        .map(Recorder::tracer)
+        .unwrap_or_else(Tracer::off);
+
+    let res = tokio::select! {
+        res = download_crosis_internal(&mut client, &tracer, replinfo, download_locations, ts_offset, email, progress) => {
             res
         }
         data = close_watcher.recv() => {
             Err(format_err!("Websocket was closed: {data:#?}"))
         }
+        _ = cancel.cancelled() => {
+            Err(format_err!("Cancelled by the user"))
+        }
+    };
+
+    if let Some(recorder) = recorder {
+        recorder.finish(&res).await;
+    }
+
+    if cancel.is_cancelled() {
+        if let Err(err) = client.destroy().await {
+            warn!(
+                "Error disconnecting from {}::{} after cancelling: {err:?}",
+                replinfo.id, replinfo.slug
+            );
+        }
     }
+
+    res
 }
 
 async fn download_crosis_internal(
-    mut client: Client,
+    client: &mut Client,
+    tracer: &Tracer,
     ReplInfo {
         id: replid,
         slug: replname,
@@ -170,233 +212,248 @@ async fn download_crosis_internal(
     download_locations: DownloadLocations,
     ts_offset: i64,
     email: &str,
-    file_count: Arc<AtomicUsize>,
+    progress: Arc<DownloadProgress>,
 ) -> Result<()> {
     // Will take up to a max of 2 minutes until it fails if ratelimited
-    let mut chan0 = client.connect_max_retries_and_backoff(5, 3000, 2).await?;
+    let mut chan0 = tracer.connection(client.connect_max_retries_and_backoff(5, 3000, 2).await?);
 
     trace!("Connected to {replid}::{replname}");
     {
         let (connected_send, connected_read) = kanal::oneshot_async();
         let mut connected_send = Some(connected_send);
-        tokio::spawn(async move {
-            while let Ok(msg) = chan0.next().await {
-                if let Some(body) = msg.body {
-                    match body {
-                        Body::Ping(_) | Body::Pong(_) => {}
-                        Body::BootStatus(goval::BootStatus { stage, .. }) => {
-                            if goval::boot_status::Stage::from_i32(stage)
-                                == Some(goval::boot_status::Stage::Complete)
-                            {
-                                if let Some(send) = connected_send.take() {
-                                    send.send(()).await.expect("Sender is alive");
-                                }
+        tokio::spawn(
+            async move {
+                while let Ok(msg) = chan0.next().await {
+                    // Everything else on chan0 is left unlogged, it can carry the repl's secrets
+                    if let Some(Body::BootStatus(goval::BootStatus { stage, .. })) = msg.body {
+                        if goval::boot_status::Stage::from_i32(stage)
+                            == Some(goval::boot_status::Stage::Complete)
+                        {
+                            if let Some(send) = connected_send.take() {
+                                send.send(()).await.expect("Sender is alive");
                             }
                         }
-                        _ => {
-                            debug!("{body:#?}")
-                        }
                     }
                 }
             }
-        });
+            .in_current_span(),
+        );
 
         connected_read.recv().await?;
     }
+    progress.touch();
 
-    let gcsfiles_scan = client.open("gcsfiles".into(), None, None).await?;
+    let gcsfiles_scan = tracer.open(client, "gcsfiles", None, None).await?;
     trace!("Obtained 1st gcsfiles for {replid}::{replname}");
 
     let (file_list_writer, file_list_reader) = kanal::unbounded_async();
     let (file_list_writer2, file_list_reader2) = kanal::unbounded_async();
-    let file_finder_handle = tokio::spawn(async move {
-        // let mut files_list = vec![];
-        let mut is_git = false;
+    let finder_progress = progress.clone();
+    let file_finder_handle = tokio::spawn(
+        async move {
+            // let mut files_list = vec![];
+            let mut is_git = false;
 
-        let mut fres = gcsfiles_scan
-            .request(Command {
-                body: Some(Body::Readdir(goval::File {
-                    path: ".".to_string(),
+            let mut fres = gcsfiles_scan
+                .request(Command {
+                    body: Some(Body::Readdir(goval::File {
+                        path: ".".to_string(),
+                        ..Default::default()
+                    })),
                     ..Default::default()
-                })),
-                ..Default::default()
-            })
-            .await?;
-        let mut path = String::new();
-        let mut to_check_dirs = vec![];
-
-        loop {
-            if let Some(Body::Files(files)) = fres.body {
-                for file in files.files {
-                    let fpath = if path.is_empty() {
-                        file.path
-                    } else {
-                        format!("{}/{}", path.clone(), &file.path)
-                    };
-
-                    // Ignore no go files
-                    if NO_GO.contains(&fpath.as_str()) {
-                        continue;
-                    }
+                })
+                .await?;
+            let mut path = String::new();
+            let mut to_check_dirs = vec![];
+
+            loop {
+                if let Some(Body::Files(files)) = fres.body {
+                    for file in files.files {
+                        let fpath = if path.is_empty() {
+                            file.path
+                        } else {
+                            format!("{}/{}", path.clone(), &file.path)
+                        };
+
+                        // Ignore no go files
+                        if NO_GO.contains(&fpath.as_str()) {
+                            continue;
+                        }
 
-                    match goval::file::Type::from_i32(file.r#type) {
-                        Some(goval::file::Type::Directory) => {
-                            if fpath == ".git" {
-                                is_git = true;
-                            } else if fpath == ".replit-takeout-otbackup" {
-                                file_list_writer.send(None).await?;
-                                return Err(format_err!(
-                                    "Repl cannot already have `.replit-takeout-otbackup/` dir"
-                                ));
+                        match goval::file::Type::from_i32(file.r#type) {
+                            Some(goval::file::Type::Directory) => {
+                                if fpath == ".git" {
+                                    is_git = true;
+                                } else if fpath == ".replit-takeout-otbackup" {
+                                    file_list_writer.send(None).await?;
+                                    return Err(format_err!(
+                                        "Repl cannot already have `.replit-takeout-otbackup/` dir"
+                                    ));
+                                }
+                                to_check_dirs.push(fpath)
                             }
-                            to_check_dirs.push(fpath)
-                        }
-                        Some(goval::file::Type::Regular) => {
-                            let res = gcsfiles_scan
-                                .request(Command {
-                                    body: Some(Body::Stat(goval::File {
-                                        path: fpath.clone(),
+                            Some(goval::file::Type::Regular) => {
+                                let res = gcsfiles_scan
+                                    .request(Command {
+                                        body: Some(Body::Stat(goval::File {
+                                            path: fpath.clone(),
+                                            ..Default::default()
+                                        })),
                                         ..Default::default()
-                                    })),
-                                    ..Default::default()
-                                })
-                                .await?;
-
-                            let size = match res.body {
-                                Some(Body::StatRes(StatResult { size, .. })) => size,
-                                _ => return Err(format_err!("Invalid StatRes: {:#?}", res.body)),
-                            };
-
-                            if size > 50_000_000 {
-                                warn!("{fpath} is larger than max download size of 50mb");
-                            } else {
-                                file_list_writer.send(Some(fpath.clone())).await?;
-
-                                file_list_writer2.send(Some(fpath)).await?;
+                                    })
+                                    .await?;
+
+                                let size = match res.body {
+                                    Some(Body::StatRes(StatResult { size, .. })) => size,
+                                    _ => {
+                                        return Err(format_err!("Invalid StatRes: {:#?}", res.body))
+                                    }
+                                };
+
+                                let max_bytes = config::get().download.max_file_bytes;
+                                if u64::try_from(size).unwrap_or_default() > max_bytes {
+                                    warn!("{fpath} is larger than the max download size of {max_bytes} bytes");
+                                } else {
+                                    finder_progress.file_found();
+                                    file_list_writer.send(Some(fpath.clone())).await?;
+
+                                    file_list_writer2.send(Some(fpath)).await?;
+                                }
+                            }
+                            _ => {
+                                error!("bruh")
                             }
-                        }
-                        _ => {
-                            error!("bruh")
                         }
                     }
                 }
-            }
 
-            if let Some(npath) = to_check_dirs.pop() {
-                path = npath;
+                if let Some(npath) = to_check_dirs.pop() {
+                    path = npath;
 
-                fres = gcsfiles_scan
-                    .request(Command {
-                        body: Some(Body::Readdir(goval::File {
-                            path: path.clone(),
+                    fres = gcsfiles_scan
+                        .request(Command {
+                            body: Some(Body::Readdir(goval::File {
+                                path: path.clone(),
+                                ..Default::default()
+                            })),
                             ..Default::default()
-                        })),
-                        ..Default::default()
-                    })
-                    .await?;
-                debug!("Obtained file tree for path `{path}`: {:#?}", fres);
-            } else {
-                break;
+                        })
+                        .await?;
+                } else {
+                    break;
+                }
             }
-        }
 
-        file_list_writer.send(None).await?;
+            file_list_writer.send(None).await?;
 
-        // trace!("Obtained file list for {replid}::{replname}");
+            // trace!("Obtained file list for {replid}::{replname}");
 
-        Ok((file_list_writer, is_git))
-    });
+            Ok((file_list_writer, is_git))
+        }
+        .in_current_span(),
+    );
 
-    let gcsfiles_download = client.open("gcsfiles".into(), None, None).await?;
+    let gcsfiles_download = tracer.open(client, "gcsfiles", None, None).await?;
     let file_list_reader3 = file_list_reader2.clone();
     trace!("Obtained 2nd gcsfiles for {replid}::{replname}");
     // Sadly have to clone if want main file downloads in parallel with ot downloads
     // Should test / benchmark if time is available.
     let main_download = download_locations.main.clone();
-    let handle = tokio::spawn(async move {
-        while let Ok(Some(path)) = file_list_reader2.recv().await {
-            let download_path = format!("{main_download}{path}");
-            let download_path = Path::new(&download_path);
-
-            if let Some(parent) = download_path.parent() {
-                fs::create_dir_all(parent).await?;
-            }
+    let reader_progress = progress.clone();
+    let handle = tokio::spawn(
+        async move {
+            while let Ok(Some(path)) = file_list_reader2.recv().await {
+                let download_path = format!("{main_download}{path}");
+                let download_path = Path::new(&download_path);
+
+                if let Some(parent) = download_path.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
 
-            let res = gcsfiles_download
-                .request(Command {
-                    body: Some(Body::Read(goval::File {
-                        path: path.clone(),
+                let res = gcsfiles_download
+                    .request(Command {
+                        body: Some(Body::Read(goval::File {
+                            path: path.clone(),
+                            ..Default::default()
+                        })),
                         ..Default::default()
-                    })),
-                    ..Default::default()
-                })
-                .await?;
+                    })
+                    .await?;
 
-            let content = match res.body {
-                Some(Body::File(goval::File { content, .. })) => content,
-                _ => return Err(format_err!("Invalid File.Content: {:#?}", res.body)),
-            };
+                let content = match res.body {
+                    Some(Body::File(goval::File { content, .. })) => content,
+                    _ => return Err(format_err!("Invalid File.Content: {:#?}", res.body)),
+                };
 
-            fs::write(download_path, content).await?;
+                fs::write(download_path, content).await?;
+                reader_progress.touch();
 
-            trace!("Downloaded {path}");
-        }
+                trace!("Downloaded {path}");
+            }
 
-        file_list_reader2.close();
+            file_list_reader2.close();
 
-        Ok(())
-    });
+            Ok(())
+        }
+        .in_current_span(),
+    );
 
-    let gcsfiles_download = client.open("gcsfiles".into(), None, None).await?;
+    let gcsfiles_download = tracer.open(client, "gcsfiles", None, None).await?;
     trace!("Obtained 3rd gcsfiles for {replid}::{replname}");
     // Sadly have to clone if want main file downloads in parallel with ot downloads
     // Should test / benchmark if time is available.
     let main_download = download_locations.main.clone();
-    let handle2 = tokio::spawn(async move {
-        while let Ok(Some(path)) = file_list_reader3.recv().await {
-            let download_path = format!("{main_download}{path}");
-            let download_path = Path::new(&download_path);
-
-            if let Some(parent) = download_path.parent() {
-                fs::create_dir_all(parent).await?;
-            }
+    let reader_progress = progress.clone();
+    let handle2 = tokio::spawn(
+        async move {
+            while let Ok(Some(path)) = file_list_reader3.recv().await {
+                let download_path = format!("{main_download}{path}");
+                let download_path = Path::new(&download_path);
+
+                if let Some(parent) = download_path.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
 
-            let res = gcsfiles_download
-                .request(Command {
-                    body: Some(Body::Read(goval::File {
-                        path: path.clone(),
+                let res = gcsfiles_download
+                    .request(Command {
+                        body: Some(Body::Read(goval::File {
+                            path: path.clone(),
+                            ..Default::default()
+                        })),
                         ..Default::default()
-                    })),
-                    ..Default::default()
-                })
-                .await?;
+                    })
+                    .await?;
 
-            let content = match res.body {
-                Some(Body::File(goval::File { content, .. })) => content,
-                _ => return Err(format_err!("Invalid File.Content: {:#?}", res.body)),
-            };
+                let content = match res.body {
+                    Some(Body::File(goval::File { content, .. })) => content,
+                    _ => return Err(format_err!("Invalid File.Content: {:#?}", res.body)),
+                };
 
-            fs::write(download_path, content).await?;
+                fs::write(download_path, content).await?;
+                reader_progress.touch();
 
-            trace!("Downloaded {path}");
-        }
+                trace!("Downloaded {path}");
+            }
 
-        Ok(())
-    });
+            Ok(())
+        }
+        .in_current_span(),
+    );
 
     // if is_git {
     //     warn!("History -> git not currently supported for existing git repos")
     // }
 
-    let semaphore = Arc::new(Semaphore::new(MAX_FILE_PARALLELISM));
+    let semaphore = Arc::new(Semaphore::new(config::get().download.max_file_parallelism));
     let mut set = tokio::task::JoinSet::new();
 
     while let Ok(Some(file)) = file_list_reader.recv().await {
         let permit = semaphore.clone().acquire_owned().await?;
 
-        let file_channel = client
+        let file_channel = tracer
             .open(
-                "ot".to_string(),
+                client,
+                "ot",
                 Some(format!("ot:{file}")),
                 Some(goval::open_channel::Action::AttachOrCreate),
             )
@@ -409,7 +466,7 @@ async fn download_crosis_internal(
             file.clone(),
             ts_offset,
             permit,
-            file_count.clone(),
+            progress.clone(),
         ));
 
         // Poke 👉
@@ -430,9 +487,10 @@ async fn download_crosis_internal(
     handle.await??;
     handle2.await??;
 
-    let secrets = client
+    let secrets = tracer
         .open(
-            "secrets".to_string(),
+            client,
+            "secrets",
             Some("secretser".to_string()),
             Some(goval::open_channel::Action::AttachOrCreate),
         )
@@ -450,7 +508,7 @@ async fn download_crosis_internal(
             Some(contents)
         }
         _ => {
-            warn!("Invalid .env SecretsGetResponse: {:#?}", res.body);
+            warn!("Unexpected reply to the secrets request for {replid}::{replname}");
             None
         }
     };
@@ -495,13 +553,13 @@ async fn download_crosis_internal(
 }
 
 async fn handle_file(
-    mut channel: Channel,
+    mut channel: TracedChannel,
     local_filename: String,
     staging_dir: String,
     filename: String,
     global_ts: i64,
     permit: OwnedSemaphorePermit,
-    file_count: Arc<AtomicUsize>,
+    progress: Arc<DownloadProgress>,
 ) -> Result<()> {
     // TODO: do other stuff l8r
     if filename.starts_with(".git") {
@@ -515,8 +573,9 @@ async fn handle_file(
         _ => return Err(format_err!("Invalid Otstatus: {:#?}", res)),
     };
 
-    let version = if otstatus.linked_file.is_some() {
-        otstatus.version
+    // What the file holds at `version`, to fall back on if the history doesn't add up to it
+    let (version, current) = if otstatus.linked_file.is_some() {
+        (otstatus.version, otstatus.contents)
     } else {
         let res = channel
             .request(Command {
@@ -536,9 +595,16 @@ async fn handle_file(
             _ => return Err(format_err!("Invalid OtLinkFileResponse: {:#?}", res.body)),
         };
 
-        linkfileres.version
+        let current = linkfileres
+            .linked_file
+            .map(|file| String::from_utf8_lossy(&file.content).into_owned())
+            .unwrap_or_default();
+
+        (linkfileres.version, current)
     };
 
+    progress.history_found(version);
+
     if version == 0 {
         let path = Path::new(&local_filename);
 
@@ -554,140 +620,138 @@ async fn handle_file(
 
     trace!("{filename} is on version #{version}");
 
-    let res = channel
-        .request(Command {
-            body: Some(Body::OtFetchRequest(goval::OtFetchRequest {
-                version_from: 1,
-                version_to: version,
-            })),
-            ..Default::default()
-        })
-        .await?;
+    let path = Path::new(&local_filename);
 
-    let history = match res.body {
-        Some(Body::OtFetchResponse(history)) => history,
-        _ => return Err(format_err!("Invalid OtFetchResponse: {:#?}", res.body)),
-    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
 
-    // GIT STUFF!
-    if !history.packets.is_empty() {
-        let mut contents = Rope::new();
-
-        let mut timestamp = normalize_ts(
-            history
-                .packets
-                .first()
-                .expect("Has to exist")
-                .committed
-                .as_ref()
-                .map(|ts| ts.seconds)
-                .unwrap_or(0),
-            global_ts,
-        );
+    let mut otbackup = OtBackupWriter::new(BufWriter::new(fs::File::create(path).await?)).await?;
 
-        for packet in &history.packets {
+    let window = config::get().download.ot_fetch_window;
+    let mut replay = OtReplay::new(&filename, current);
+
+    for (version_from, version_to) in fetch_windows(version, window) {
+        let res = channel
+            .request(Command {
+                body: Some(Body::OtFetchRequest(goval::OtFetchRequest {
+                    version_from,
+                    version_to,
+                })),
+                ..Default::default()
+            })
+            .await?;
+
+        let history = match res.body {
+            Some(Body::OtFetchResponse(history)) => history,
+            _ => return Err(format_err!("Invalid OtFetchResponse: {:#?}", res.body)),
+        };
+
+        progress.touch();
+
+        // GIT STUFF!
+        for packet in history.packets {
             let new_ts = normalize_ts(
                 packet.committed.as_ref().map(|ts| ts.seconds).unwrap_or(0),
                 global_ts,
             );
 
-            if new_ts != timestamp {
-                let staging_ts_path = format!("{staging_dir}{timestamp}/{filename}");
-                let path = Path::new(&staging_ts_path);
+            if let Some((timestamp, contents)) = replay.apply(&packet, new_ts) {
+                write_snapshot(&staging_dir, timestamp, &filename, &contents).await?;
+            }
 
-                if let Some(parent) = path.parent() {
-                    fs::create_dir_all(parent).await?;
-                }
+            otbackup
+                .push(&OtFetchPacket::new(packet, global_ts)?)
+                .await?;
+        }
+    }
 
-                let mut file_writer = fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(path)
-                    .await?;
+    let (_, packet_count) = otbackup.finish().await?;
 
-                for chunk in contents.chunks() {
-                    let bytes = chunk.as_bytes();
+    if let Some((timestamp, contents)) = replay.finish() {
+        crate::metrics::observe_ot_packets(packet_count);
 
-                    file_writer.write_all(bytes).await?;
-                }
+        write_snapshot(&staging_dir, timestamp, &filename, &contents).await?;
+    }
 
-                file_writer.flush().await?;
-                file_writer.sync_data().await?;
+    trace!("Downloaded history for {filename}");
 
-                drop(file_writer);
+    drop(permit);
 
-                timestamp = new_ts;
-            }
+    progress.file_done();
+    log::info!("{} files processed", progress.files_done());
 
-            do_ot(&mut contents, packet)?;
-        }
+    Ok(())
+}
 
-        let staging_ts_path_final = format!("{staging_dir}{timestamp}/{filename}");
-        let path = Path::new(&staging_ts_path_final);
+/// The versions each `OtFetchRequest` asks for, `window` at a time, to fetch 1 to `version`.
+fn fetch_windows(version: u32, window: u32) -> impl Iterator<Item = (u32, u32)> {
+    (1..=version)
+        .step_by(window as usize)
+        .map(move |from| (from, from.saturating_add(window - 1).min(version)))
+}
 
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent).await?;
-        }
+/// Writes a file's `.otbackup`, a JSON array of its packets, as they're fetched.
+struct OtBackupWriter<W> {
+    writer: W,
+    packets: usize,
+}
 
-        let mut file_writer = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&path)
-            .await?;
+impl<W: AsyncWrite + Unpin> OtBackupWriter<W> {
+    async fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(b"[").await?;
 
-        for chunk in contents.chunks() {
-            let bytes = chunk.as_bytes();
+        Ok(Self { writer, packets: 0 })
+    }
 
-            file_writer.write_all(bytes).await?;
+    async fn push(&mut self, packet: &OtFetchPacket) -> Result<()> {
+        if self.packets > 0 {
+            self.writer.write_all(b",").await?;
         }
+        self.writer.write_all(&serde_json::to_vec(packet)?).await?;
+        self.packets += 1;
 
-        file_writer.flush().await?;
-        file_writer.sync_data().await?;
-
-        drop(file_writer);
+        Ok(())
     }
 
-    let mut new_history = vec![];
-
-    for item in history.packets {
-        let mut ops = vec![];
-        for op in item.op {
-            ops.push(match op.op_component.unwrap() {
-                goval::ot_op_component::OpComponent::Skip(amount) => OtOp::Skip(amount),
-                goval::ot_op_component::OpComponent::Delete(amount) => OtOp::Delete(amount),
-                goval::ot_op_component::OpComponent::Insert(text) => OtOp::Insert(text),
-            })
-        }
+    /// Close off the array, returning the writer and how many packets went in.
+    async fn finish(mut self) -> Result<(W, usize)> {
+        self.writer.write_all(b"]").await?;
+        self.writer.flush().await?;
 
-        let timestamp = item.committed.map(|ts| ts.seconds).unwrap_or(0);
-        new_history.push(OtFetchPacket {
-            ops,
-            crc32: item.crc32,
-            timestamp,
-            ts_string: format!(
-                "{}",
-                OffsetDateTime::from_unix_timestamp(normalize_ts(timestamp, global_ts))?
-            ),
-            version: item.version,
-        })
+        Ok((self.writer, self.packets))
     }
+}
 
-    let path = Path::new(&local_filename);
+/// Write `contents` as `filename` in the staging snapshot for `timestamp`.
+async fn write_snapshot(
+    staging_dir: &str,
+    timestamp: i64,
+    filename: &str,
+    contents: &Rope,
+) -> Result<()> {
+    let staging_ts_path = format!("{staging_dir}{timestamp}/{filename}");
+    let path = Path::new(&staging_ts_path);
 
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent).await?;
     }
 
-    fs::write(path, serde_json::to_string(&new_history)?).await?;
+    let mut file_writer = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .await?;
 
-    trace!("Downloaded history for {filename}");
+    for chunk in contents.chunks() {
+        let bytes = chunk.as_bytes();
 
-    drop(permit);
+        file_writer.write_all(bytes).await?;
+    }
 
-    log::info!("{} file processed", file_count.load(Ordering::Relaxed));
-    file_count.fetch_add(1, Ordering::Relaxed);
+    file_writer.flush().await?;
+    file_writer.sync_data().await?;
 
     Ok(())
 }
@@ -926,6 +990,33 @@ struct OtFetchPacket {
     version: u32,
 }
 
+impl OtFetchPacket {
+    fn new(packet: goval::OtPacket, global_ts: i64) -> Result<Self> {
+        let mut ops = vec![];
+        for op in packet.op {
+            ops.push(match op.op_component {
+                Some(goval::ot_op_component::OpComponent::Skip(amount)) => OtOp::Skip(amount),
+                Some(goval::ot_op_component::OpComponent::Delete(amount)) => OtOp::Delete(amount),
+                Some(goval::ot_op_component::OpComponent::Insert(text)) => OtOp::Insert(text),
+                None => return Err(format_err!("Ot packet without components")),
+            })
+        }
+
+        let timestamp = packet.committed.map(|ts| ts.seconds).unwrap_or(0);
+
+        Ok(Self {
+            ops,
+            crc32: packet.crc32,
+            timestamp,
+            ts_string: format!(
+                "{}",
+                OffsetDateTime::from_unix_timestamp(normalize_ts(timestamp, global_ts))?
+            ),
+            version: packet.version,
+        })
+    }
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 enum OtOp {
@@ -933,3 +1024,83 @@ enum OtOp {
     Skip(u32),
     Delete(u32),
 }
+
+#[cfg(test)]
+mod tests {
+    use goval::{ot_op_component::OpComponent, OtOpComponent, OtPacket};
+
+    use super::*;
+
+    #[test]
+    fn windows_cover_every_version_once() {
+        assert_eq!(
+            fetch_windows(7, 3).collect::<Vec<_>>(),
+            [(1, 3), (4, 6), (7, 7)]
+        );
+        assert_eq!(fetch_windows(6, 3).collect::<Vec<_>>(), [(1, 3), (4, 6)]);
+        assert_eq!(
+            fetch_windows(3, 1).collect::<Vec<_>>(),
+            [(1, 1), (2, 2), (3, 3)]
+        );
+        assert_eq!(fetch_windows(3, 100).collect::<Vec<_>>(), [(1, 3)]);
+        assert_eq!(fetch_windows(0, 3).count(), 0);
+        assert_eq!(
+            fetch_windows(u32::MAX, u32::MAX).collect::<Vec<_>>(),
+            [(1, u32::MAX)]
+        );
+    }
+
+    /// What the `.otbackup` for `history` comes out as, fetched `window` versions at a time.
+    async fn otbackup(history: &[OtPacket], window: u32) -> Vec<u8> {
+        let mut otbackup = OtBackupWriter::new(vec![]).await.unwrap();
+
+        for (from, to) in fetch_windows(history.len() as u32, window) {
+            // What goval answers that window's OtFetchRequest with
+            for packet in &history[from as usize - 1..to as usize] {
+                let packet = OtFetchPacket::new(packet.clone(), 1_672_531_200).unwrap();
+                otbackup.push(&packet).await.unwrap();
+            }
+        }
+
+        let (bytes, packets) = otbackup.finish().await.unwrap();
+        assert_eq!(packets, history.len());
+        bytes
+    }
+
+    #[tokio::test]
+    async fn otbackup_is_the_same_whatever_the_window() {
+        let history: Vec<_> = (1..=7)
+            .map(|version| OtPacket {
+                version,
+                op: vec![
+                    OtOpComponent {
+                        op_component: Some(OpComponent::Skip(version - 1)),
+                    },
+                    OtOpComponent {
+                        op_component: Some(OpComponent::Insert("é".to_string())),
+                    },
+                ],
+                crc32: version,
+                ..Default::default()
+            })
+            .collect();
+
+        let one_at_a_time = otbackup(&history, 1).await;
+
+        let parsed: serde_json::Value = serde_json::from_slice(&one_at_a_time).unwrap();
+        let packets = parsed.as_array().unwrap();
+        assert_eq!(packets.len(), 7);
+        assert_eq!(packets[6]["version"], 7);
+
+        // 6 and 7 put the end of the first window one short of the last version and on it
+        for window in [2, 3, 6, 7, 8, 1000] {
+            assert_eq!(
+                otbackup(&history, window).await,
+                one_at_a_time,
+                "window {window}"
+            );
+        }
+
+        assert_eq!(otbackup(&[], 3).await, b"[]");
+    }
+}
@@ -0,0 +1,262 @@
+//! How far along a repl download is, and how long it gets. Instead of one deadline for every
+//! repl, each gets a budget from its size on Replit and what's been found of it so far, and
+//! once that's used up it only keeps going for as long as it keeps making progress.
+
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::config;
+
+/// Every repl gets at least this long, for connecting and booting.
+const BASE_BUDGET: Duration = Duration::from_secs(60);
+
+/// Roughly how fast a repl's files come down, zip or not.
+const BYTES_PER_SEC: f64 = 512.0 * 1024.0;
+
+/// Extra time for each file found, for the reads and opening its OT channel.
+const PER_FILE: Duration = Duration::from_millis(500);
+
+/// Extra milliseconds for each OT version to fetch and replay.
+const PER_OT_VERSION_MS: u64 = 5;
+
+/// How often a download is checked on.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Counters the download bumps as it goes, shared with whoever is watching it.
+#[derive(Debug)]
+pub struct DownloadProgress {
+    started: Instant,
+
+    /// Milliseconds after `started` of the last sign of life.
+    last_progress: AtomicU64,
+
+    files_found: AtomicUsize,
+    files_done: AtomicUsize,
+    ot_versions: AtomicU64,
+}
+
+impl Default for DownloadProgress {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            last_progress: AtomicU64::new(0),
+            files_found: AtomicUsize::new(0),
+            files_done: AtomicUsize::new(0),
+            ot_versions: AtomicU64::new(0),
+        }
+    }
+}
+
+impl DownloadProgress {
+    /// Something happened, without anything to count.
+    pub fn touch(&self) {
+        let millis = self.started.elapsed().as_millis() as u64;
+        self.last_progress.fetch_max(millis, Ordering::Relaxed);
+    }
+
+    pub fn file_found(&self) {
+        self.files_found.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    /// A file turned out to have `versions` of history to fetch.
+    pub fn history_found(&self, versions: u32) {
+        self.ot_versions
+            .fetch_add(u64::from(versions), Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub fn file_done(&self) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    /// Files with all their history downloaded.
+    pub fn files_done(&self) -> usize {
+        self.files_done.load(Ordering::Relaxed)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// How long it's been since the last sign of life.
+    pub fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.last_progress.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+}
+
+/// Why a download was given up on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    /// It used up its budget and then stopped making progress.
+    Stalled { budget: Duration, idle: Duration },
+
+    /// It hit the most any repl gets.
+    Ceiling(Duration),
+}
+
+impl std::fmt::Display for Expiry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stalled { budget, idle } => write!(
+                f,
+                "No progress for {}s after using up its {}s budget",
+                idle.as_secs(),
+                budget.as_secs()
+            ),
+            Self::Ceiling(max) => write!(f, "Still going after the {}s limit", max.as_secs()),
+        }
+    }
+}
+
+/// How long a repl gets.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeoutBudget {
+    /// `bytesUsed` from Replit, when it's known.
+    bytes_used: Option<u64>,
+
+    /// How long it can go without progress once the budget is used up.
+    stall: Duration,
+
+    /// The most any repl gets, progress or not.
+    max: Duration,
+}
+
+impl TimeoutBudget {
+    pub fn new(bytes_used: Option<u64>) -> Self {
+        let download = &config::get().download;
+
+        Self {
+            bytes_used,
+            stall: download.repl_stall(),
+            max: download.repl_max_timeout(),
+        }
+    }
+
+    /// The budget given what's known about the repl so far. It grows as files and history are found.
+    pub fn budget(&self, progress: &DownloadProgress) -> Duration {
+        let files = progress.files_found.load(Ordering::Relaxed) as u32;
+        let versions = progress.ot_versions.load(Ordering::Relaxed);
+
+        let budget = BASE_BUDGET
+            + Duration::from_secs_f64(self.bytes_used.unwrap_or_default() as f64 / BYTES_PER_SEC)
+            + PER_FILE.saturating_mul(files)
+            + Duration::from_millis(PER_OT_VERSION_MS.saturating_mul(versions));
+
+        budget.min(self.max)
+    }
+
+    /// Whether to give up on the download now.
+    pub fn expired(&self, progress: &DownloadProgress) -> Option<Expiry> {
+        let elapsed = progress.elapsed();
+        if elapsed >= self.max {
+            return Some(Expiry::Ceiling(self.max));
+        }
+
+        let budget = self.budget(progress);
+        let idle = progress.idle();
+        (elapsed >= budget && idle >= self.stall).then_some(Expiry::Stalled { budget, idle })
+    }
+
+    /// Resolves once the download should be given up on.
+    pub async fn watch(&self, progress: &DownloadProgress) -> Expiry {
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+
+            if let Some(expiry) = self.expired(progress) {
+                return expiry;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUDGET: TimeoutBudget = TimeoutBudget {
+        bytes_used: Some(10 * 512 * 1024),
+        stall: Duration::from_secs(30),
+        max: Duration::from_secs(600),
+    };
+
+    /// A download that's been going for `elapsed`, the last `idle` of it without progress.
+    fn progress(
+        elapsed: Duration,
+        idle: Duration,
+        files: usize,
+        versions: u64,
+    ) -> DownloadProgress {
+        DownloadProgress {
+            started: Instant::now().checked_sub(elapsed).unwrap(),
+            last_progress: AtomicU64::new((elapsed - idle).as_millis() as u64),
+            files_found: AtomicUsize::new(files),
+            files_done: AtomicUsize::new(0),
+            ot_versions: AtomicU64::new(versions),
+        }
+    }
+
+    #[test]
+    fn budget_grows_with_the_repl() {
+        let fresh = progress(Duration::ZERO, Duration::ZERO, 0, 0);
+        assert_eq!(BUDGET.budget(&fresh), Duration::from_secs(70));
+
+        // 20 files and 2000 versions on top of the 10MiB
+        let found = progress(Duration::ZERO, Duration::ZERO, 20, 2000);
+        assert_eq!(BUDGET.budget(&found), Duration::from_secs(90));
+
+        let unknown = TimeoutBudget {
+            bytes_used: None,
+            ..BUDGET
+        };
+        assert_eq!(unknown.budget(&fresh), BASE_BUDGET);
+    }
+
+    #[test]
+    fn budget_stops_at_the_max() {
+        let huge = progress(Duration::ZERO, Duration::ZERO, 10_000, 1_000_000);
+        assert_eq!(BUDGET.budget(&huge), BUDGET.max);
+
+        let at_max = progress(Duration::ZERO, Duration::ZERO, 0, 106_000);
+        assert_eq!(BUDGET.budget(&at_max), Duration::from_secs(600));
+        let under = progress(Duration::ZERO, Duration::ZERO, 0, 105_000);
+        assert_eq!(BUDGET.budget(&under), Duration::from_secs(595));
+    }
+
+    #[test]
+    fn keeps_going_while_in_budget_or_progressing() {
+        // Idle, but still within its 70s
+        let early = progress(Duration::from_secs(60), Duration::from_secs(50), 0, 0);
+        assert_eq!(BUDGET.expired(&early), None);
+
+        // Over budget, but it did something recently
+        let busy = progress(Duration::from_secs(200), Duration::from_secs(10), 0, 0);
+        assert_eq!(BUDGET.expired(&busy), None);
+    }
+
+    #[test]
+    fn gives_up_once_stalled_past_the_budget() {
+        let stalled = progress(Duration::from_secs(200), Duration::from_secs(40), 0, 0);
+
+        match BUDGET.expired(&stalled) {
+            Some(Expiry::Stalled { budget, idle }) => {
+                assert_eq!(budget, Duration::from_secs(70));
+                assert!(idle >= Duration::from_secs(40));
+            }
+            other => panic!("expected a stall, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn gives_up_at_the_max_even_when_progressing() {
+        let endless = progress(Duration::from_secs(601), Duration::ZERO, 0, 0);
+        assert_eq!(
+            BUDGET.expired(&endless),
+            Some(Expiry::Ceiling(Duration::from_secs(600)))
+        );
+    }
+}
@@ -0,0 +1,374 @@
+//! Records every goval command sent and received during a crosis session into a zstd compressed
+//! file of JSON lines, so a repl that fails in production can be replayed offline through the
+//! mock (see `examples/replay.rs`).
+//!
+//! Secrets are left out of traces, but everything else in the repl is in them.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use anyhow::{format_err, Result};
+use async_compression::tokio::{bufread::ZstdDecoder, write::ZstdEncoder};
+use crosis::{
+    goval::{command::Body, open_channel::Action, Command, SecretsGetResponse},
+    Channel, Client,
+};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use super::ReplInfo;
+
+/// Bumped whenever old traces can't be read anymore.
+const TRACE_VERSION: u32 = 1;
+
+/// What the trace is of.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceHeader {
+    pub version: u32,
+    pub repl_id: String,
+    pub slug: String,
+    pub username: String,
+    pub ts_offset: i64,
+}
+
+impl TraceHeader {
+    pub fn new(replinfo: ReplInfo<'_>, ts_offset: i64) -> Self {
+        Self {
+            version: TRACE_VERSION,
+            repl_id: replinfo.id.to_string(),
+            slug: replinfo.slug.to_string(),
+            username: replinfo.username.to_string(),
+            ts_offset,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TraceEvent {
+    Start(TraceHeader),
+
+    /// Channels are numbered in the order they were opened, the connection itself is 0.
+    Open {
+        at_ms: u64,
+        channel: u32,
+        service: String,
+        name: Option<String>,
+    },
+
+    Request {
+        at_ms: u64,
+        channel: u32,
+        #[serde(with = "frame")]
+        sent: Command,
+        #[serde(with = "frame")]
+        received: Command,
+    },
+
+    /// A request that never got a reply: the channel closed or errored, or the download was
+    /// abandoned while waiting, like when it times out. Error replies are still [`Self::Request`]s.
+    RequestFailed {
+        at_ms: u64,
+        channel: u32,
+        #[serde(with = "frame")]
+        sent: Command,
+        error: String,
+    },
+
+    /// Something goval sent without being asked.
+    Received {
+        at_ms: u64,
+        channel: u32,
+        #[serde(with = "frame")]
+        command: Command,
+    },
+
+    End {
+        at_ms: u64,
+        error: Option<String>,
+    },
+}
+
+/// Commands go in traces as base64 encoded protobuf, the same as on the wire.
+mod frame {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use crosis::goval::Command;
+    use prost::Message;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(command: &Command, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(command.encode_to_vec()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Command, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let bytes = STANDARD.decode(encoded).map_err(D::Error::custom)?;
+
+        Command::decode(bytes.as_slice()).map_err(D::Error::custom)
+    }
+}
+
+/// A trace read back from disk.
+#[derive(Debug)]
+pub struct Trace {
+    pub header: TraceHeader,
+    pub events: Vec<TraceEvent>,
+}
+
+impl Trace {
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let file = BufReader::new(fs::File::open(path).await?);
+        let mut lines = BufReader::new(ZstdDecoder::new(file)).lines();
+
+        let header = match lines.next_line().await? {
+            Some(line) => match serde_json::from_str(&line)? {
+                TraceEvent::Start(header) => header,
+                _ => return Err(format_err!("Trace doesn't start with its header")),
+            },
+            None => return Err(format_err!("Trace is empty")),
+        };
+
+        if header.version != TRACE_VERSION {
+            return Err(format_err!(
+                "Trace is version {}, only version {TRACE_VERSION} can be read",
+                header.version
+            ));
+        }
+
+        let mut events = vec![];
+        while let Some(line) = lines.next_line().await? {
+            events.push(serde_json::from_str(&line)?);
+        }
+
+        Ok(Self { header, events })
+    }
+}
+
+/// Writes a session's trace, which only ends up complete once it's [finished](Self::finish).
+pub struct Recorder {
+    tracer: Tracer,
+    writer: JoinHandle<Result<()>>,
+    path: PathBuf,
+}
+
+impl Recorder {
+    pub async fn create(dir: &Path, header: TraceHeader) -> Result<Self> {
+        fs::create_dir_all(dir).await?;
+
+        // Every attempt at a repl gets its own trace
+        let path = dir.join(format!(
+            "{}.{}.trace.zst",
+            header.repl_id,
+            chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+        ));
+        let mut file = ZstdEncoder::new(fs::File::create(&path).await?);
+
+        let (events, mut pending) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(async move {
+            while let Some(event) = pending.recv().await {
+                let end = matches!(event, TraceEvent::End { .. });
+
+                let mut line = serde_json::to_vec(&event)?;
+                line.push(b'\n');
+                file.write_all(&line).await?;
+
+                if end {
+                    break;
+                }
+            }
+
+            file.shutdown().await?;
+            Ok(())
+        });
+
+        let tracer = Tracer {
+            events: Some(events),
+            started: Instant::now(),
+            channels: Arc::new(AtomicU32::new(1)),
+        };
+        tracer.record(|_| TraceEvent::Start(header));
+
+        Ok(Self {
+            tracer,
+            writer,
+            path,
+        })
+    }
+
+    pub fn tracer(&self) -> Tracer {
+        self.tracer.clone()
+    }
+
+    /// Close off the trace with how the session went.
+    pub async fn finish(self, result: &Result<()>) {
+        let error = result.as_ref().err().map(|err| format!("{err:#}"));
+        self.tracer.record(|at_ms| TraceEvent::End { at_ms, error });
+
+        match self.writer.await {
+            Ok(Ok(())) => info!("Recorded crosis trace {}", self.path.display()),
+            Ok(Err(err)) => error!("Couldn't write trace {}: {err:#}", self.path.display()),
+            Err(err) => error!("Trace writer for {} died: {err}", self.path.display()),
+        }
+    }
+}
+
+/// Hands out channels that record their traffic, or don't when tracing's off.
+#[derive(Clone)]
+pub struct Tracer {
+    events: Option<mpsc::UnboundedSender<TraceEvent>>,
+    started: Instant,
+    channels: Arc<AtomicU32>,
+}
+
+impl Tracer {
+    pub fn off() -> Self {
+        Self {
+            events: None,
+            started: Instant::now(),
+            channels: Arc::new(AtomicU32::new(1)),
+        }
+    }
+
+    fn record(&self, event: impl FnOnce(u64) -> TraceEvent) {
+        if let Some(events) = &self.events {
+            // Anything after the trace was finished is dropped
+            let _ = events.send(event(self.started.elapsed().as_millis() as u64));
+        }
+    }
+
+    /// The connection's own channel.
+    pub fn connection(&self, channel: Channel) -> TracedChannel {
+        self.record(|at_ms| TraceEvent::Open {
+            at_ms,
+            channel: 0,
+            service: "chan0".to_string(),
+            name: None,
+        });
+
+        TracedChannel {
+            channel,
+            id: 0,
+            tracer: self.clone(),
+        }
+    }
+
+    pub async fn open(
+        &self,
+        client: &mut Client,
+        service: &str,
+        name: Option<String>,
+        action: Option<Action>,
+    ) -> Result<TracedChannel, crosis::Error> {
+        let channel = client
+            .open(service.to_string(), name.clone(), action)
+            .await?;
+        let id = self.channels.fetch_add(1, Ordering::Relaxed);
+
+        self.record(|at_ms| TraceEvent::Open {
+            at_ms,
+            channel: id,
+            service: service.to_string(),
+            name,
+        });
+
+        Ok(TracedChannel {
+            channel,
+            id,
+            tracer: self.clone(),
+        })
+    }
+}
+
+/// A crosis channel that records what goes over it.
+pub struct TracedChannel {
+    channel: Channel,
+    id: u32,
+    tracer: Tracer,
+}
+
+impl TracedChannel {
+    pub async fn next(&mut self) -> Result<Command, crosis::Error> {
+        let command = self.channel.next().await?;
+
+        self.tracer.record(|at_ms| TraceEvent::Received {
+            at_ms,
+            channel: self.id,
+            command: command.clone(),
+        });
+
+        Ok(command)
+    }
+
+    pub async fn request(&self, command: Command) -> Result<Command, crosis::Error> {
+        let mut pending = PendingRequest {
+            tracer: &self.tracer,
+            channel: self.id,
+            sent: self.tracer.events.as_ref().map(|_| command.clone()),
+        };
+
+        let result = self.channel.request(command).await;
+
+        match &result {
+            Ok(received) => pending.finish(|at_ms, channel, sent| TraceEvent::Request {
+                at_ms,
+                channel,
+                sent,
+                received: redact(received.clone()),
+            }),
+            Err(err) => pending.finish(|at_ms, channel, sent| TraceEvent::RequestFailed {
+                at_ms,
+                channel,
+                sent,
+                error: err.to_string(),
+            }),
+        }
+
+        result
+    }
+}
+
+/// A traced request waiting on its reply, recorded as failed if it's dropped before getting one.
+struct PendingRequest<'a> {
+    tracer: &'a Tracer,
+    channel: u32,
+    sent: Option<Command>,
+}
+
+impl PendingRequest<'_> {
+    fn finish(&mut self, event: impl FnOnce(u64, u32, Command) -> TraceEvent) {
+        if let Some(sent) = self.sent.take() {
+            self.tracer.record(|at_ms| event(at_ms, self.channel, sent));
+        }
+    }
+}
+
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        self.finish(|at_ms, channel, sent| TraceEvent::RequestFailed {
+            at_ms,
+            channel,
+            sent,
+            error: "Abandoned while waiting for the reply".to_string(),
+        });
+    }
+}
+
+fn redact(mut command: Command) -> Command {
+    if let Some(Body::SecretsGetResponse(_)) = command.body {
+        command.body = Some(Body::SecretsGetResponse(SecretsGetResponse::default()));
+    }
+
+    command
+}
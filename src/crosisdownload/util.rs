@@ -1,11 +1,11 @@
 use anyhow::{format_err, Result};
-use async_zip::{base::write::ZipFileWriter, ZipEntryBuilder};
 use crc32fast::Hasher;
 use crosis::goval::{self, OtPacket};
+use log::warn;
 use ropey::Rope;
 use tokio::{fs, io::AsyncWriteExt};
 
-use super::ReplInfo;
+use super::{progress::DownloadProgress, ReplInfo};
 
 const STEP_SIZE: i64 = 60 * 60;
 const STEP_SIZE_HALF: i64 = STEP_SIZE / 2;
@@ -20,6 +20,8 @@ pub fn normalize_ts(ts: i64, start_ts: i64) -> i64 {
     STEP_SIZE * ((ts + STEP_SIZE_HALF - adj_offset) / STEP_SIZE) + adj_offset
 }
 
+/// Apply `ot` to `contents` and check the result against its crc32. Like Replit, skips, deletes
+/// and the cursor all count chars (unicode scalar values), not bytes.
 pub fn do_ot(contents: &mut Rope, ot: &OtPacket) -> Result<()> {
     let mut cursor: usize = 0;
 
@@ -48,7 +50,7 @@ pub fn do_ot(contents: &mut Rope, ot: &OtPacket) -> Result<()> {
             goval::ot_op_component::OpComponent::Insert(insert) => {
                 contents.insert(cursor, insert);
 
-                cursor += insert.len();
+                cursor += insert.chars().count();
             }
         }
     }
@@ -70,6 +72,60 @@ pub fn do_ot(contents: &mut Rope, ot: &OtPacket) -> Result<()> {
     }
 }
 
+/// Rebuilds a file from its OT history, a snapshot per timestamp. If the history stops adding
+/// up, the snapshots so far are kept and the file picks back up at `current`, what it holds now,
+/// rather than losing the whole repl's history over one file.
+pub struct OtReplay<'a> {
+    filename: &'a str,
+    current: String,
+    contents: Rope,
+    timestamp: Option<i64>,
+    resynced: bool,
+}
+
+impl<'a> OtReplay<'a> {
+    pub fn new(filename: &'a str, current: String) -> Self {
+        Self {
+            filename,
+            current,
+            contents: Rope::new(),
+            timestamp: None,
+            resynced: false,
+        }
+    }
+
+    /// Apply the next packet, committed at (normalized) `timestamp`. Returns the previous
+    /// snapshot once a packet starts a new one.
+    pub fn apply(&mut self, packet: &OtPacket, timestamp: i64) -> Option<(i64, Rope)> {
+        // Once resynced there's nothing worth snapshotting until the end
+        let snapshot = self
+            .timestamp
+            .filter(|ts| *ts != timestamp && !self.resynced)
+            .map(|ts| (ts, self.contents.clone()));
+        self.timestamp = Some(timestamp);
+
+        if !self.resynced {
+            if let Err(err) = do_ot(&mut self.contents, packet) {
+                warn!(
+                    "{} diverged from its history at version {}: {err}, resyncing from its current contents",
+                    self.filename, packet.version
+                );
+                crate::metrics::record_ot_resync();
+
+                self.contents = Rope::from_str(&self.current);
+                self.resynced = true;
+            }
+        }
+
+        snapshot
+    }
+
+    /// The last snapshot, if there was any history at all.
+    pub fn finish(self) -> Option<(i64, Rope)> {
+        self.timestamp.map(|ts| (ts, self.contents))
+    }
+}
+
 pub async fn recursively_flatten_dir(dir: String) -> Result<Vec<String>> {
     let mut fres = fs::read_dir(&dir).await?;
 
@@ -115,32 +171,22 @@ pub async fn recursively_flatten_dir(dir: String) -> Result<Vec<String>> {
     Ok(files_list)
 }
 
-pub async fn make_zip(dir: String, zip_path: String) -> Result<()> {
-    let mut file = fs::File::create(zip_path).await?;
-    let mut writer = ZipFileWriter::with_tokio(&mut file);
-
-    let files = recursively_flatten_dir(dir.clone()).await?;
-
-    let file_prefix = dir + "/";
+/// The combined size in bytes of every file under `dir`.
+pub async fn dir_size(dir: String) -> Result<u64> {
+    let mut size = 0;
 
-    for file in files {
-        let data = fs::read(&file).await?;
-        let builder = ZipEntryBuilder::new(
-            file.strip_prefix(&file_prefix).unwrap_or(&file).into(),
-            async_zip::Compression::Deflate,
-        );
-
-        writer.write_entry_whole(builder, &data).await?;
+    for file in recursively_flatten_dir(dir).await? {
+        size += fs::metadata(&file).await?.len();
     }
-    writer.close().await?;
 
-    Ok(())
+    Ok(size)
 }
 
 pub async fn download_repl_zip(
     client: reqwest::Client,
     replinfo: ReplInfo<'_>,
     location: &str,
+    progress: &DownloadProgress,
 ) -> Result<()> {
     let mut file = fs::OpenOptions::new()
         .create(true)
@@ -149,16 +195,118 @@ pub async fn download_repl_zip(
         .open(location)
         .await?;
     let mut res = client
-        .get(format!(
-            "https://replit.com/@{}/{}.zip",
+        .get(crate::replit::url(&format!(
+            "/@{}/{}.zip",
             replinfo.username, replinfo.slug
-        ))
+        )))
         .send()
         .await?;
 
     while let Some(chunk) = res.chunk().await? {
         file.write_all(&chunk).await?;
+        progress.touch();
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use goval::{ot_op_component::OpComponent, OtOpComponent};
+
+    fn packet(version: u32, ops: Vec<OpComponent>, result: &str) -> OtPacket {
+        OtPacket {
+            version,
+            op: ops
+                .into_iter()
+                .map(|op| OtOpComponent {
+                    op_component: Some(op),
+                })
+                .collect(),
+            crc32: crc32fast::hash(result.as_bytes()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ot_counts_chars_not_bytes() {
+        let mut contents = Rope::new();
+
+        let text = "héllo 👋 世界\n";
+        do_ot(
+            &mut contents,
+            &packet(1, vec![OpComponent::Insert(text.into())], text),
+        )
+        .unwrap();
+        assert_eq!(contents.to_string(), text);
+
+        // Swap the emoji for another, then replace the CJK with more of it
+        let edited = "héllo 🌍 你好世界\n";
+        let ops = vec![
+            OpComponent::Skip(6),
+            OpComponent::Delete(1),
+            OpComponent::Insert("🌍".into()),
+            OpComponent::Skip(1),
+            OpComponent::Delete(2),
+            OpComponent::Insert("你好世界".into()),
+            OpComponent::Skip(1),
+        ];
+        do_ot(&mut contents, &packet(2, ops, edited)).unwrap();
+        assert_eq!(contents.to_string(), edited);
+    }
+
+    #[test]
+    fn ot_checks_crc32() {
+        let mut contents = Rope::from_str("世界");
+
+        let mut bad = packet(1, vec![OpComponent::Insert("你好".into())], "你好世界");
+        bad.crc32 ^= 1;
+
+        assert!(do_ot(&mut contents, &bad).is_err());
+    }
+
+    #[test]
+    fn ot_rejects_ops_past_the_end() {
+        // 2 chars but 8 bytes
+        let mut contents = Rope::from_str("👋👋");
+
+        let skip = packet(1, vec![OpComponent::Skip(3)], "👋👋");
+        assert!(do_ot(&mut contents, &skip).is_err());
+
+        let mut contents = Rope::from_str("👋👋");
+        let delete = packet(1, vec![OpComponent::Skip(1), OpComponent::Delete(2)], "👋");
+        assert!(do_ot(&mut contents, &delete).is_err());
+    }
+
+    #[test]
+    fn diverged_history_is_resynced() {
+        let mut replay = OtReplay::new("main.py", "print('今の')\n".into());
+
+        let first = packet(
+            1,
+            vec![OpComponent::Insert("print(1)\n".into())],
+            "print(1)\n",
+        );
+        assert!(replay.apply(&first, 100).is_none());
+
+        // Doesn't add up to what it claims, so the file falls back on its current contents
+        let mut diverged = packet(
+            2,
+            vec![OpComponent::Skip(6), OpComponent::Insert("2".into())],
+            "print(12)\n",
+        );
+        diverged.crc32 ^= 1;
+        let (ts, snapshot) = replay.apply(&diverged, 200).expect("the first snapshot");
+        assert_eq!(ts, 100);
+        assert_eq!(snapshot.to_string(), "print(1)\n");
+
+        // Nothing new is snapshotted until the end
+        let third = packet(3, vec![OpComponent::Insert("x".into())], "x");
+        assert!(replay.apply(&third, 300).is_none());
+
+        let (ts, contents) = replay.finish().expect("a last snapshot");
+        assert_eq!(ts, 300);
+        assert_eq!(contents.to_string(), "print('今の')\n");
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::{format_err, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use log::warn;
+use once_cell::sync::Lazy;
+use openssl::{
+    rand::rand_bytes,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+
+use crate::config;
+
+/// Marks a value as encrypted by [`encrypt_secret`], and with which scheme.
+const PREFIX: &str = "v1:";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// The AES-256-GCM key from the config's `token_encryption_key`. Only the server has it,
+/// so what ends up in the job store is useless on its own.
+static KEY: Lazy<Option<Vec<u8>>> = Lazy::new(|| {
+    let key = config::get().secrets.token_encryption_key.as_ref()?;
+    let key = STANDARD.decode(key).ok()?;
+    (key.len() == 32).then_some(key)
+});
+
+fn key() -> Result<&'static [u8]> {
+    KEY.as_deref()
+        .ok_or_else(|| format_err!("TOKEN_ENCRYPTION_KEY must be a base64 encoded 32 byte key"))
+}
+
+/// Encrypt a secret, like a Replit token or git remote password, before it's stored anywhere.
+pub fn encrypt_secret(secret: &str) -> Result<String> {
+    encrypt_with(key()?, secret)
+}
+
+/// Decrypt a secret from the job store. Secrets stored before encryption was added are
+/// passed through as they are.
+pub fn decrypt_secret(stored: &str) -> Result<String> {
+    decrypt_with(key()?, stored)
+}
+
+fn encrypt_with(key: &[u8], secret: &str) -> Result<String> {
+    let mut nonce = [0; NONCE_LEN];
+    rand_bytes(&mut nonce)?;
+
+    let mut tag = [0; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&nonce),
+        &[],
+        secret.as_bytes(),
+        &mut tag,
+    )?;
+
+    let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len() + TAG_LEN);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    sealed.extend_from_slice(&tag);
+
+    Ok(format!("{PREFIX}{}", STANDARD.encode(sealed)))
+}
+
+fn decrypt_with(key: &[u8], stored: &str) -> Result<String> {
+    let Some(encoded) = stored.strip_prefix(PREFIX) else {
+        warn!("Using a secret that was stored unencrypted");
+        return Ok(stored.to_string());
+    };
+
+    let sealed = STANDARD.decode(encoded)?;
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return Err(format_err!("Encrypted secret is too short"));
+    }
+
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+    let secret = decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        &[],
+        ciphertext,
+        tag,
+    )?;
+
+    Ok(String::from_utf8(secret)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    #[test]
+    fn round_trips() {
+        let sealed = encrypt_with(&KEY, "hunter2 🔑").unwrap();
+
+        assert!(sealed.starts_with(PREFIX));
+        assert!(!sealed.contains("hunter2"));
+        assert_eq!(decrypt_with(&KEY, &sealed).unwrap(), "hunter2 🔑");
+
+        // A fresh nonce every time
+        assert_ne!(encrypt_with(&KEY, "hunter2 🔑").unwrap(), sealed);
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let sealed = encrypt_with(&KEY, "hunter2").unwrap();
+        let bytes = STANDARD
+            .decode(sealed.strip_prefix(PREFIX).unwrap())
+            .unwrap();
+
+        // Flip a bit in the ciphertext, then in the tag
+        for index in [NONCE_LEN, bytes.len() - 1] {
+            let mut tampered = bytes.clone();
+            tampered[index] ^= 1;
+
+            let tampered = format!("{PREFIX}{}", STANDARD.encode(tampered));
+            assert!(decrypt_with(&KEY, &tampered).is_err());
+        }
+
+        let truncated = format!("{PREFIX}{}", STANDARD.encode(&bytes[..NONCE_LEN]));
+        assert!(decrypt_with(&KEY, &truncated).is_err());
+    }
+
+    #[test]
+    fn rejects_the_wrong_key() {
+        let sealed = encrypt_with(&KEY, "hunter2").unwrap();
+
+        assert!(decrypt_with(&[8; 32], &sealed).is_err());
+    }
+
+    #[test]
+    fn passes_through_unencrypted_secrets() {
+        assert_eq!(decrypt_with(&KEY, "legacy-token").unwrap(), "legacy-token");
+    }
+}
@@ -1,12 +1,15 @@
-use anyhow::Result;
-use log::info;
-use once_cell::sync::Lazy;
+use anyhow::{format_err, Result};
+use async_trait::async_trait;
+use log::{debug, info};
+use once_cell::sync::{Lazy, OnceCell};
 use reqwest::{
-    header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE},
+    header::{HeaderValue, AUTHORIZATION},
     Client,
 };
 use serde_json::{json, Value};
 
+use crate::config;
+
 static LOOPS_CLIENT: Lazy<Client> = Lazy::new(|| {
     let mut headers = reqwest::header::HeaderMap::new();
     headers.insert(
@@ -25,14 +28,62 @@ static LOOPS_CLIENT: Lazy<Client> = Lazy::new(|| {
         .expect("a built loops client")
 });
 static LOOPS_TX_URL: &str = "https://app.loops.so/api/v1/transactional";
+static LOOPS_API_KEY_URL: &str = "https://app.loops.so/api/v1/api-key";
+
+static MAILER: OnceCell<&'static dyn Mailer> = OnceCell::new();
 
-async fn send_loop(to: &str, payload: &Value) -> Result<reqwest::Response, reqwest::Error> {
-    LOOPS_CLIENT.post(LOOPS_TX_URL).json(&payload).send().await
+/// Sends the transactional emails. Loops, unless something else is put in with [`use_mailer`].
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, to: &str, payload: &Value) -> Result<()>;
+}
+
+/// Send emails through `mailer` instead of Loops for the rest of the process. Only works before
+/// the first email has gone out.
+pub fn use_mailer(mailer: &'static dyn Mailer) -> Result<()> {
+    MAILER
+        .set(mailer)
+        .map_err(|_| format_err!("The mailer is already in use"))
+}
+
+struct Loops;
+
+#[async_trait]
+impl Mailer for Loops {
+    async fn send(&self, to: &str, payload: &Value) -> Result<()> {
+        debug!("Sending {to} an email through Loops");
+        LOOPS_CLIENT
+            .post(LOOPS_TX_URL)
+            .json(&payload)
+            .send()
+            .await?;
+
+        Ok(())
+    }
+}
+
+async fn send_loop(to: &str, payload: &Value) -> Result<()> {
+    MAILER.get_or_init(|| &Loops).send(to, payload).await
+}
+
+/// Check Loops can be reached and accepts our API key.
+pub async fn reachable() -> Result<()> {
+    if dotenv::var("LOOPS_API_KEY").is_err() {
+        return Err(format_err!("LOOPS_API_KEY isn't set"));
+    }
+
+    LOOPS_CLIENT
+        .get(LOOPS_API_KEY_URL)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
 }
 
 pub async fn send_greet_email(to: &str, username: &str) -> Result<()> {
     let payload = json!({
-      "transactionalId": "cm0pegyzg01xquhjkf7r3fh85",
+      "transactionalId": config::get().email.greet,
       "email": to,
       "dataVariables": {
         "replitUsername": username
@@ -49,7 +100,7 @@ pub async fn send_partial_success_email(
     to: &str,
     username: &str,
     repl_count_total: usize,
-    repl_ids_failed: &Vec<String>,
+    repl_ids_failed: &[String],
     link_export_download: &str,
 ) -> Result<()> {
     let failed_repl_links = repl_ids_failed
@@ -59,7 +110,7 @@ pub async fn send_partial_success_email(
         .join("\n");
 
     let payload = json!({
-      "transactionalId": "cm0pgg7bw002gp8uk5ufqfvov",
+      "transactionalId": config::get().email.partial_success,
       "email": to,
       "dataVariables": {
         "replitUsername": username,
@@ -83,7 +134,7 @@ pub async fn send_success_email(
     link_export_download: &str,
 ) -> Result<()> {
     let payload = json!({
-      "transactionalId": "cm0pg42wh002u3ml1d4et51zp",
+      "transactionalId": config::get().email.success,
       "email": to,
       "dataVariables": {
         "replitUsername": username,
@@ -100,14 +151,14 @@ pub async fn send_success_email(
 
 pub async fn send_failed_no_repls_email(to: &str, username: &str) -> Result<()> {
     let payload = json!({
-      "transactionalId": "cm0pgqger00bbo6ie7wyia98y",
+      "transactionalId": config::get().email.no_repls,
       "email": to,
       "dataVariables": {
         "replitUsername": username
       }
     });
 
-    send_loop(&to, &payload).await?;
+    send_loop(to, &payload).await?;
     info!("Sent failed no repls email to {to} ({username})");
 
     Ok(())
@@ -115,14 +166,14 @@ pub async fn send_failed_no_repls_email(to: &str, username: &str) -> Result<()>
 
 pub async fn send_failure_email(to: &str, username: &str) -> Result<()> {
     let payload = json!({
-      "transactionalId": "cm0pgxpy303hf53mv955aoqls",
+      "transactionalId": config::get().email.failure,
       "email": to,
       "dataVariables": {
         "replitUsername": username
       }
     });
 
-    send_loop(&to, &payload).await?;
+    send_loop(to, &payload).await?;
     info!("Sent failure email to {to} ({username})");
 
     Ok(())
@@ -0,0 +1,230 @@
+//! Liveness and readiness probes. `/healthz` only looks at the worker loop, so a pod is
+//! restarted when that dies. `/readyz` only looks at the pod itself too, so an outage somewhere
+//! else can't take every pod out of the service at once. It reports on everything an export
+//! needs to finish as well, but just for whoever's looking.
+
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, AtomicI64, Ordering},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use replit_takeout::{airtable, email::emails, r2, shutdown, workspace};
+use rocket::{http::Status, serde::json::Json, Route};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+/// How long the worker can go without polling the job store while idle before it's considered stuck.
+const WORKER_STALL: Duration = Duration::from_secs(5 * 60);
+
+/// How long each dependency gets to answer.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Readiness is probed often, and every dependency check is a request to someone else's API.
+const DEPENDENCIES_CACHE: Duration = Duration::from_secs(15);
+
+static WORKER_RUNNING: AtomicBool = AtomicBool::new(false);
+static WORKER_BUSY: AtomicBool = AtomicBool::new(false);
+static LAST_POLL_ATTEMPT: AtomicI64 = AtomicI64::new(0);
+static LAST_POLL_SUCCESS: AtomicI64 = AtomicI64::new(0);
+
+static DEPENDENCIES: Lazy<Mutex<Option<(Instant, Dependencies)>>> = Lazy::new(|| Mutex::new(None));
+
+pub fn routes() -> Vec<Route> {
+    routes![healthz, readyz]
+}
+
+/// Marks the worker loop as running for as long as it's held, including if the loop panics.
+pub struct WorkerGuard;
+
+impl WorkerGuard {
+    pub fn new() -> Self {
+        WORKER_RUNNING.store(true, Ordering::Relaxed);
+        WorkerGuard
+    }
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        WORKER_RUNNING.store(false, Ordering::Relaxed);
+        WORKER_BUSY.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Record a poll of the job store, and whether it worked.
+pub fn record_poll(succeeded: bool) {
+    let now = Utc::now().timestamp();
+    LAST_POLL_ATTEMPT.store(now, Ordering::Relaxed);
+    if succeeded {
+        LAST_POLL_SUCCESS.store(now, Ordering::Relaxed);
+    }
+}
+
+/// Whether the worker is in the middle of an export, when it isn't expected to poll.
+pub fn set_busy(busy: bool) {
+    WORKER_BUSY.store(busy, Ordering::Relaxed);
+}
+
+#[derive(Serialize)]
+struct Liveness {
+    worker_running: bool,
+    worker_busy: bool,
+    last_poll_attempt: Option<DateTime<Utc>>,
+    last_successful_poll: Option<DateTime<Utc>>,
+}
+
+impl Liveness {
+    fn now() -> Self {
+        Self {
+            worker_running: WORKER_RUNNING.load(Ordering::Relaxed),
+            worker_busy: WORKER_BUSY.load(Ordering::Relaxed),
+            last_poll_attempt: timestamp(&LAST_POLL_ATTEMPT),
+            last_successful_poll: timestamp(&LAST_POLL_SUCCESS),
+        }
+    }
+
+    fn alive(&self) -> bool {
+        if !self.worker_running {
+            return false;
+        }
+
+        // The worker waits up to a minute before its first poll
+        let since_poll = self.last_poll_attempt.map(|at| Utc::now() - at);
+        self.worker_busy
+            || since_poll.is_none_or(|since| since.to_std().unwrap_or_default() < WORKER_STALL)
+    }
+}
+
+/// How everything an export needs is doing. None of it decides readiness.
+#[derive(Clone, Serialize)]
+struct Dependencies {
+    job_store: Check,
+    storage: Check,
+    notifier: Check,
+    free_disk_bytes: Option<u64>,
+    min_free_disk_bytes: u64,
+}
+
+#[derive(Clone, Serialize)]
+struct Check {
+    ok: bool,
+    error: Option<String>,
+    latency_ms: u128,
+}
+
+impl Check {
+    async fn run<F: Future<Output = anyhow::Result<T>>, T>(check: F) -> Self {
+        let started = Instant::now();
+        let result = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(err)) => Err(format!("{err:#}")),
+            Err(_) => Err(format!("Timed out after {CHECK_TIMEOUT:?}")),
+        };
+
+        Self {
+            ok: result.is_ok(),
+            error: result.err(),
+            latency_ms: started.elapsed().as_millis(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReadinessResponse {
+    ready: bool,
+    shutting_down: bool,
+    #[serde(flatten)]
+    liveness: Liveness,
+    dependencies: Dependencies,
+}
+
+#[get("/healthz")]
+fn healthz() -> (Status, Json<Liveness>) {
+    let liveness = Liveness::now();
+    let status = if liveness.alive() {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+
+    (status, Json(liveness))
+}
+
+#[get("/readyz")]
+async fn readyz() -> (Status, Json<ReadinessResponse>) {
+    let liveness = Liveness::now();
+    let shutting_down = shutdown::is_requested();
+    let ready = liveness.alive() && !shutting_down;
+
+    let status = if ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+
+    (
+        status,
+        Json(ReadinessResponse {
+            ready,
+            shutting_down,
+            liveness,
+            dependencies: dependencies().await,
+        }),
+    )
+}
+
+async fn dependencies() -> Dependencies {
+    let mut cached = DEPENDENCIES.lock().await;
+    if let Some((checked_at, dependencies)) = cached.as_ref() {
+        if checked_at.elapsed() < DEPENDENCIES_CACHE {
+            return dependencies.clone();
+        }
+    }
+
+    let (job_store, storage, notifier) = tokio::join!(
+        Check::run(airtable::aggregates()),
+        Check::run(r2::reachable()),
+        Check::run(emails::reachable()),
+    );
+
+    // Below the space kept free for everything else, jobs are put off until there's room
+    let free_disk_bytes = match workspace::free_disk() {
+        Ok(free) => Some(free),
+        Err(err) => {
+            error!("Couldn't get the free space for workspaces: {err:#}");
+            None
+        }
+    };
+    let min_free_disk_bytes = workspace::min_free_disk();
+
+    let dependencies = Dependencies {
+        job_store,
+        storage,
+        notifier,
+        free_disk_bytes,
+        min_free_disk_bytes,
+    };
+
+    let healthy = dependencies.job_store.ok
+        && dependencies.storage.ok
+        && dependencies.notifier.ok
+        && free_disk_bytes.is_some_and(|free| free >= min_free_disk_bytes);
+    if !healthy {
+        warn!(
+            "Dependency check failed: {}",
+            serde_json::to_string(&dependencies).unwrap_or_default()
+        );
+    }
+
+    *cached = Some((Instant::now(), dependencies.clone()));
+    dependencies
+}
+
+fn timestamp(at: &AtomicI64) -> Option<DateTime<Utc>> {
+    match at.load(Ordering::Relaxed) {
+        0 => None,
+        secs => DateTime::from_timestamp(secs, 0),
+    }
+}
@@ -0,0 +1,122 @@
+use airtable_api::Record;
+use anyhow::Result;
+use chrono::Utc;
+use dotenv::var;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    airtable::{self, AirtableSyncedUser},
+    config,
+};
+
+/// Identifies this process in the job store. The pod name, plus a suffix in case it's reused.
+pub static WORKER_ID: Lazy<String> = Lazy::new(|| {
+    let host = var("HOSTNAME").unwrap_or_else(|_| "worker".to_string());
+    format!("{host}-{:06x}", rand::thread_rng().gen_range(0..0x1000000))
+});
+
+/// A job this worker has claimed. The lease is renewed in the background until it's released
+/// or dropped, which doubles as the job's heartbeat. A dropped lease expires on its own, and
+/// then the job is left for [`crate::reaper`].
+///
+/// If another worker turns out to hold the job, renewing stops and the lease counts as
+/// [lost](Self::is_lost), which a [`crate::cancel::CancelWatch`] watching it turns into
+/// stopping the export.
+pub struct Lease {
+    record_id: String,
+    renew: JoinHandle<()>,
+    lost: watch::Receiver<bool>,
+}
+
+impl Lease {
+    /// Claim a job to run it, if no other worker has it.
+    pub async fn claim(
+        record: &Record<AirtableSyncedUser>,
+    ) -> Result<Option<(Self, Record<AirtableSyncedUser>)>> {
+        Self::claim_if(record, AirtableSyncedUser::is_claimable).await
+    }
+
+    /// Claim a job if no other worker has it and, once it's been fetched fresh, `claimable` says so.
+    pub async fn claim_if(
+        record: &Record<AirtableSyncedUser>,
+        claimable: impl Fn(&AirtableSyncedUser) -> bool,
+    ) -> Result<Option<(Self, Record<AirtableSyncedUser>)>> {
+        let worker = &config::get().worker;
+        let expires_at = Utc::now() + worker.lease();
+        let Some(record) = airtable::claim(
+            &record.id,
+            &WORKER_ID,
+            expires_at,
+            worker.claim_settle(),
+            claimable,
+        )
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        info!("Claimed job {} as {}", record.id, *WORKER_ID);
+
+        let record_id = record.id.clone();
+        let (send_lost, lost) = watch::channel(false);
+        let renew = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(worker.lease_renew());
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+
+                let expires_at = Utc::now() + worker.lease();
+                match airtable::renew_lease(&record_id, &WORKER_ID, expires_at).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        warn!("Lost the lease on job {record_id} to another worker, stopping it");
+                        let _ = send_lost.send(true);
+                        return;
+                    }
+                    Err(err) => warn!("Couldn't renew the lease on job {record_id}: {err:#}"),
+                }
+            }
+        });
+
+        Ok(Some((
+            Self {
+                record_id: record.id.clone(),
+                renew,
+                lost,
+            },
+            record,
+        )))
+    }
+
+    /// Whether another worker has taken the job over.
+    pub fn is_lost(&self) -> bool {
+        *self.lost.borrow()
+    }
+
+    /// Flips to `true` once the lease is lost.
+    pub fn lost(&self) -> watch::Receiver<bool> {
+        self.lost.clone()
+    }
+
+    /// Stop renewing and give the job back straight away.
+    pub async fn release(self) {
+        self.renew.abort();
+
+        if let Err(err) = airtable::release_lease(&self.record_id, &WORKER_ID).await {
+            warn!(
+                "Couldn't release the lease on job {}, it'll expire instead: {err:#}",
+                self.record_id
+            );
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.renew.abort();
+    }
+}
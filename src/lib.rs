@@ -1,23 +1,38 @@
 pub mod airtable;
+pub mod archive;
+pub mod cancel;
+pub mod config;
 pub mod crosisdownload;
+pub mod crypto;
 pub mod email;
+pub mod git_remote;
+pub mod lease;
+pub mod manifest;
+pub mod metrics;
+#[cfg(feature = "mock")]
+pub mod mock;
 pub mod r2;
+pub mod reaper;
 pub mod replit;
 pub mod replit_graphql;
+pub mod retry;
+pub mod shutdown;
+pub mod telemetry;
+pub mod workspace;
 
 pub mod utils {
     use rand::seq::SliceRandom;
     use rand::Rng;
 
     pub fn random_user_agent() -> String {
-        let browsers = vec!["Chrome", "Firefox", "Safari", "Edge", "Opera"];
-        let os = vec![
+        let browsers = ["Chrome", "Firefox", "Safari", "Edge", "Opera"];
+        let os = [
             "Windows NT 10.0",
             "Macintosh; Intel Mac OS X 10_15_7",
             "Linux; Android 10",
             "iPhone; CPU iPhone OS 14_0 like Mac OS X",
         ];
-        let engines = vec!["AppleWebKit/537.36", "Gecko/20100101", "KHTML, like Gecko"];
+        let engines = ["AppleWebKit/537.36", "Gecko/20100101", "KHTML, like Gecko"];
 
         let browser = browsers.choose(&mut rand::thread_rng()).unwrap();
         let os = os.choose(&mut rand::thread_rng()).unwrap();
@@ -10,29 +10,58 @@ use chrono::{DateTime, Utc};
 use rand::Rng;
 use replit_takeout::{
     airtable::{self, AggregateStats, ProcessState},
-    replit_graphql::{ExportProgress, ProfileRepls, QuickUser},
+    archive::ArchiveFormat,
+    cancel::CancelWatch,
+    config, crypto,
+    git_remote::GitRemote,
+    lease::Lease,
+    metrics, r2, reaper,
+    replit_graphql::{subscribe_progress, ExportProgress, ProfileRepls, QuickUser},
+    shutdown, telemetry, workspace,
 };
-use rocket::http::Method;
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Method};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::stream::{Event, EventStream};
 use rocket::serde::json::Json;
+use rocket::{Orbit, Rocket};
 use rocket_cors::{AllowedOrigins, CorsOptions};
-use std::{collections::HashMap, time::Duration};
-mod r2;
+use std::{collections::HashMap, str::FromStr, time::Duration};
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
+use tracing::Instrument;
+mod admin;
+mod health;
+mod rate_limit;
+
+use rate_limit::RateLimit;
+
+/// Time on top of the shutdown grace period for the worker to hand its job back.
+const WORKER_STOP_MARGIN: Duration = Duration::from_secs(30);
+
+/// How often a progress stream re-reads the stored progress, to pick up exports on other pods.
+const PROGRESS_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
 struct State {
     token_to_id_cache: tokio::sync::RwLock<HashMap<String, i64>>, // <token, id>
     stats_cache: tokio::sync::RwLock<(AggregateStats, DateTime<Utc>)>, // <stats, updated_at>
+    worker: tokio::sync::Mutex<Option<JoinHandle<()>>>,
 }
 
 #[derive(serde::Serialize)]
 struct SignupResponse {
     success: bool,
     message: String,
+
+    /// The state of the user's existing job, when that's why nothing was queued.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<ProcessState>,
 }
 impl SignupResponse {
     fn good(message: String) -> Json<Self> {
         Json(Self {
             success: true,
             message,
+            status: None,
         })
     }
 
@@ -40,23 +69,61 @@ impl SignupResponse {
         Json(Self {
             success: false,
             message,
+            status: None,
         })
     }
+
+    fn existing(message: String, status: ProcessState) -> Json<Self> {
+        Json(Self {
+            success: false,
+            message,
+            status: Some(status),
+        })
+    }
+}
+
+#[derive(FromForm)]
+struct Signup {
+    token: String,
+    email: String,
+    git_remote: Option<String>,
+    git_username: Option<String>,
+    git_password: Option<String>,
+    format: Option<String>,
+    incremental: Option<bool>,
+
+    /// Update the token and email on a job that's still queued, instead of leaving it be.
+    refresh: Option<bool>,
+
+    /// Export again even though a previous export has finished.
+    reexport: Option<bool>,
 }
 
 #[launch]
 async fn rocket() -> _ {
-    env_logger::init();
     dotenv::dotenv().ok();
+    telemetry::init();
 
-    // airtable::aggregates().await.expect("fialed to get aggs");
-    tokio::spawn(async {
-        loop {
+    if let Err(err) = config::init() {
+        error!("Not starting: {err:#}");
+        std::process::exit(1);
+    }
+
+    // Nothing's running yet, so whatever's locked belongs to another process sharing the root
+    if let Err(err) = workspace::sweep_orphans() {
+        error!("Couldn't sweep orphaned workspaces: {err:#}");
+    }
+
+    let worker = tokio::spawn(async {
+        let _worker = health::WorkerGuard::new();
+        while !shutdown::is_requested() {
             if let Err(err) = airtable_loop().await {
                 error!("Airtable internal loop error (restarting): {err}");
             }
         }
+        info!("Worker stopped");
     });
+    tokio::spawn(reaper::run());
 
     let cors = CorsOptions::default()
         .allowed_origins(AllowedOrigins::all())
@@ -68,16 +135,64 @@ async fn rocket() -> _ {
         )
         .allow_credentials(true);
 
+    // Start degraded rather than not at all, the stats are refreshed on the next request
+    let stats = match airtable::aggregates().await {
+        Ok(stats) => (stats, Utc::now()),
+        Err(err) => {
+            error!("Couldn't get the airtable aggregates, starting without them: {err:#}");
+            (AggregateStats::default(), DateTime::<Utc>::UNIX_EPOCH)
+        }
+    };
+
     rocket::build()
-        .mount("/", routes![hello, signup, get_progress, get_stats])
+        .mount(
+            "/",
+            routes![
+                hello,
+                signup,
+                cancel,
+                get_progress,
+                stream_progress,
+                get_stats,
+                get_metrics
+            ],
+        )
+        .mount("/", health::routes())
+        .mount("/admin", admin::routes())
         .manage(State {
             token_to_id_cache: tokio::sync::RwLock::new(HashMap::new()),
-            stats_cache: tokio::sync::RwLock::new((
-                airtable::aggregates().await.expect("airtable aggregates"),
-                Utc::now(),
-            )),
+            stats_cache: tokio::sync::RwLock::new(stats),
+            worker: tokio::sync::Mutex::new(Some(worker)),
         })
+        .register("/", rate_limit::catchers())
+        .attach(rate_limit::RateLimiter::new())
         .attach(cors.to_cors().unwrap())
+        .attach(AdHoc::on_shutdown("Stop the worker", |rocket| {
+            Box::pin(stop_worker(rocket))
+        }))
+}
+
+/// Stop claiming jobs and wait for the current one to be wrapped up or handed back.
+async fn stop_worker(rocket: &Rocket<Orbit>) {
+    shutdown::request();
+
+    let Some(state) = rocket.state::<State>() else {
+        return;
+    };
+    let Some(worker) = state.worker.lock().await.take() else {
+        return;
+    };
+
+    info!(
+        "Waiting up to {:?} for the worker to stop",
+        shutdown::grace_period()
+    );
+    if tokio::time::timeout(shutdown::grace_period() + WORKER_STOP_MARGIN, worker)
+        .await
+        .is_err()
+    {
+        warn!("The worker didn't stop in time, its job will be reaped once it goes stale");
+    }
 }
 
 #[get("/")]
@@ -91,8 +206,20 @@ fn hello() -> String {
     )
 }
 
-#[post("/signup?<token>&<email>")]
-async fn signup(token: String, email: String) -> Json<SignupResponse> {
+#[post("/signup?<signup..>")]
+async fn signup(_limit: RateLimit, signup: Signup) -> Json<SignupResponse> {
+    let Signup {
+        token,
+        email,
+        git_remote,
+        git_username,
+        git_password,
+        format,
+        incremental,
+        refresh,
+        reexport,
+    } = signup;
+
     let parts: Vec<&str> = token.split('.').collect();
 
     if parts.len() != 3
@@ -103,30 +230,118 @@ async fn signup(token: String, email: String) -> Json<SignupResponse> {
         return SignupResponse::bad("That's not a Replit connect.sid".to_string());
     }
 
+    let archive_format = match format.as_deref().map(ArchiveFormat::from_str) {
+        None => None,
+        Some(Ok(format)) => Some(format),
+        Some(Err(_)) => {
+            return SignupResponse::bad("The export format must be `zip` or `tar.zst`".to_string())
+        }
+    };
+
+    if let Some(remote) = &git_remote {
+        if let Err(err) = GitRemote::check_template(remote) {
+            return SignupResponse::bad(err.to_string());
+        }
+    }
+
     // Get the user info, add to the airtable, respond to them
     let user = match QuickUser::fetch(&token, None).await {
         Ok(user) => user,
         Err(e) => {
-            log::error!(
-                "Couldn't get the replit user info for token {}: {}",
-                token,
-                e
-            );
+            error!("Couldn't get the replit user info at signup: {e}");
             return SignupResponse::bad("Couldn't get Replit user info".to_string());
         }
     };
 
     let at_user = user.clone();
 
+    // Only the worker ever sees the token or git password in plaintext again
+    let secrets = crypto::encrypt_secret(&token).and_then(|token| {
+        let git_password = git_password
+            .as_deref()
+            .map(crypto::encrypt_secret)
+            .transpose()?;
+        Ok((token, git_password))
+    });
+    let (token, git_password) = match secrets {
+        Ok(secrets) => secrets,
+        Err(err) => {
+            error!("Couldn't encrypt {}'s secrets: {err:#}", user.username);
+            return SignupResponse::bad(format!("Sorry, {}! We couldn't add you to the queue for some reason. Please contact us at malted@hackclub.com!", user.username));
+        }
+    };
+
+    let jobs = match airtable::jobs_for_user(user.id).await {
+        Ok(jobs) => jobs,
+        Err(err) => {
+            error!(
+                "Couldn't look up {}'s existing jobs: {err:#}",
+                user.username
+            );
+            return SignupResponse::bad(format!("Sorry, {}! We couldn't add you to the queue for some reason. Please contact us at malted@hackclub.com!", user.username));
+        }
+    };
+
+    if let Some(job) = jobs.iter().find(|job| job.fields.status.is_active()) {
+        if job.fields.status == ProcessState::Registered && refresh.unwrap_or_default() {
+            let mut job = job.clone();
+            job.fields.token = token;
+            job.fields.email = email;
+
+            if let Err(err) = airtable::update_records(vec![job]).await {
+                error!("Couldn't refresh {}'s queued job: {err:#}", user.username);
+                return SignupResponse::bad(format!("Sorry, {}! We couldn't update your export for some reason. Please contact us at malted@hackclub.com!", user.username));
+            }
+
+            info!(
+                "Refreshed the token and email on {}'s queued job",
+                user.username
+            );
+            return SignupResponse::good(format!("Updated your queued export, {}!", user.username));
+        }
+
+        info!(
+            "{} signed up again with a job {}",
+            user.username, job.fields.status
+        );
+        return SignupResponse::existing(
+            format!(
+                "You already have an export in progress, {}! Check your email.",
+                user.username
+            ),
+            job.fields.status,
+        );
+    }
+
+    // Jobs that were cancelled or never got anywhere don't stand in the way of another go
+    if let Some(job) = jobs.iter().rev().find(|job| job.fields.status.exported()) {
+        if !reexport.unwrap_or_default() {
+            return SignupResponse::existing(
+                format!(
+                    "You've already exported your repls, {}. Ask for a re-export to run it again.",
+                    user.username
+                ),
+                job.fields.status,
+            );
+        }
+
+        info!("{} asked for a re-export", user.username);
+    }
+
     if !airtable::add_user(airtable::AirtableSyncedUser {
         id: user.id,
         username: at_user.username,
         token,
         email: email.clone(),
         status: airtable::ProcessState::Registered,
-        r2_link: String::from("https://example.com"),
+        r2_link: airtable::NO_R2_LINK.to_string(),
         failed_ids: String::from("none"),
-        statistics: vec!["recpWEjc0zLoKEtZP".into()],
+        statistics: vec![config::get().airtable.statistics_record.clone()],
+        git_remote,
+        git_remote_username: git_username,
+        git_remote_password: git_password,
+        archive_format,
+        incremental: incremental.unwrap_or_default(),
         ..Default::default()
     })
     .await
@@ -143,8 +358,130 @@ async fn signup(token: String, email: String) -> Json<SignupResponse> {
     SignupResponse::good(format!("Check your email, {}!", user.username))
 }
 
+#[post("/cancel?<token>")]
+async fn cancel(
+    _limit: RateLimit,
+    token: String,
+    state: &rocket::State<State>,
+) -> Json<SignupResponse> {
+    let Some(id) = user_id_for_token(token, state).await else {
+        return SignupResponse::bad("Couldn't get Replit user info".to_string());
+    };
+
+    let records = match airtable::get_records().await {
+        Ok(records) => records,
+        Err(err) => {
+            error!("Couldn't get the airtable records to cancel {id}'s export: {err:#}");
+            return SignupResponse::bad("Couldn't look up your export".to_string());
+        }
+    };
+
+    let Some(record) = records.into_iter().find(|record| {
+        record.fields.id == id
+            && !record.fields.cancel_requested
+            && matches!(
+                record.fields.status,
+                ProcessState::Registered | ProcessState::CollectingRepls | ProcessState::Collected
+            )
+    }) else {
+        return SignupResponse::bad("You don't have an export in progress".to_string());
+    };
+
+    if let Err(err) = airtable::request_cancellation(&record).await {
+        error!("Couldn't cancel {id}'s export: {err:#}");
+        return SignupResponse::bad("Couldn't cancel your export".to_string());
+    }
+
+    info!("{} cancelled their export", record.fields.username);
+
+    SignupResponse::good(format!(
+        "Your export has been cancelled, {}.",
+        record.fields.username
+    ))
+}
+
+/// Sent by `EventSource` when it reconnects, so the stream can carry on where it left off.
+struct LastEventId(Option<i64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(LastEventId(
+            req.headers()
+                .get_one("Last-Event-ID")
+                .and_then(|id| id.parse().ok()),
+        ))
+    }
+}
+
 #[get("/progress?<token>")]
-async fn get_progress(token: String, state: &rocket::State<State>) -> Option<Json<ExportProgress>> {
+async fn get_progress(
+    _limit: RateLimit,
+    token: String,
+    state: &rocket::State<State>,
+) -> Option<Json<ExportProgress>> {
+    let id = user_id_for_token(token, state).await?;
+
+    if let Some(bytes) = r2::get_file_contents(format!("progress/{id}")).await {
+        let str = std::str::from_utf8(&bytes).ok()?;
+        let progress: ExportProgress = serde_json::from_str(str).ok()?;
+        Some(Json(progress))
+    } else {
+        None
+    }
+}
+
+#[get("/progress/stream?<token>")]
+async fn stream_progress(
+    _limit: RateLimit,
+    token: String,
+    last_event_id: LastEventId,
+    state: &rocket::State<State>,
+) -> Option<EventStream![]> {
+    let id = user_id_for_token(token, state).await?;
+
+    // Subscribe before the first read of the stored progress so nothing falls in between
+    let mut updates = subscribe_progress();
+    let mut last_seq = last_event_id.0.unwrap_or(i64::MIN);
+
+    Some(EventStream! {
+        // The first tick is immediate, which sends the current progress straight away
+        let mut poll = tokio::time::interval(PROGRESS_POLL_INTERVAL);
+
+        loop {
+            let progress = tokio::select! {
+                update = updates.recv() => match update {
+                    Ok((user_id, progress)) if user_id == id => {
+                        poll.reset();
+                        Some(progress)
+                    }
+                    Ok(_) => None,
+                    Err(RecvError::Lagged(_)) => ExportProgress::fetch(id).await,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = poll.tick() => ExportProgress::fetch(id).await,
+            };
+
+            let Some(progress) = progress else { continue };
+            if progress.seq() <= last_seq {
+                continue;
+            }
+            last_seq = progress.seq();
+
+            yield Event::json(&progress)
+                .event("progress")
+                .id(last_seq.to_string());
+
+            if progress.finished() {
+                break;
+            }
+        }
+    })
+}
+
+async fn user_id_for_token(token: String, state: &rocket::State<State>) -> Option<i64> {
     let mut should_insert = false;
 
     let id = if let Some(id) = state.token_to_id_cache.read().await.get(&token) {
@@ -156,10 +493,7 @@ async fn get_progress(token: String, state: &rocket::State<State>) -> Option<Jso
                 user.id
             }
             Err(err) => {
-                log::error!(
-                    "Couldn't get the replit user info for token {token}: {:?}",
-                    err
-                );
+                error!("Couldn't get the replit user info for a token: {err:?}");
                 return None;
             }
         }
@@ -169,13 +503,7 @@ async fn get_progress(token: String, state: &rocket::State<State>) -> Option<Jso
         state.token_to_id_cache.write().await.insert(token, id);
     }
 
-    if let Some(bytes) = r2::get_file_contents(format!("progress/{id}")).await {
-        let str = std::str::from_utf8(&bytes).ok()?;
-        let progress: ExportProgress = serde_json::from_str(str).ok()?;
-        Some(Json(progress))
-    } else {
-        None
-    }
+    Some(id)
 }
 
 #[get("/stats")]
@@ -201,25 +529,108 @@ async fn get_stats(state: &rocket::State<State>) -> Option<Json<AggregateStats>>
     Some(Json(state.stats_cache.read().await.0))
 }
 
+#[get("/metrics")]
+fn get_metrics() -> (ContentType, String) {
+    let content_type =
+        ContentType::parse_flexible(&metrics::content_type()).unwrap_or(ContentType::Plain);
+    (content_type, metrics::render())
+}
+
 async fn airtable_loop() -> Result<()> {
     let initial_wait = rand::thread_rng().gen_range(0..60);
-    tokio::time::sleep(Duration::from_secs(initial_wait)).await;
+    tokio::select! {
+        _ = tokio::time::sleep(Duration::from_secs(initial_wait)) => {}
+        _ = shutdown::requested() => return Ok(()),
+    }
 
     loop {
-        let mut user;
+        let (lease, mut user);
         'mainloop: loop {
+            if shutdown::is_requested() {
+                return Ok(());
+            }
+
             debug!("Getting airtable records");
-            let records = airtable::get_records().await?;
-            for record in records {
-                if record.fields.status == ProcessState::Registered {
-                    user = record;
-                    break 'mainloop;
+            let records = airtable::get_records().await;
+            health::record_poll(records.is_ok());
+            let records = records?;
+            metrics::set_jobs(records.iter().map(|record| &record.fields));
+
+            for record in &records {
+                if shutdown::is_requested() {
+                    return Ok(());
+                }
+                if !record.fields.is_claimable() {
+                    continue;
+                }
+
+                // Signups before deduplication, or racing each other across pods
+                if let Some(original) = airtable::duplicate_of(&records, record) {
+                    info!(
+                        "Cancelling job {}, it duplicates {}",
+                        record.id, original.id
+                    );
+
+                    let mut duplicate = record.clone();
+                    duplicate.fields.status = ProcessState::Cancelled;
+                    airtable::update_records(vec![duplicate]).await?;
+                    continue;
+                }
+
+                match Lease::claim(record).await {
+                    Ok(Some((claimed, record))) => {
+                        (lease, user) = (claimed, record);
+                        break 'mainloop;
+                    }
+                    Ok(None) => debug!("Job {} was claimed by another worker", record.id),
+                    Err(err) => warn!("Couldn't claim job {}: {err:#}", record.id),
                 }
             }
-            tokio::time::sleep(Duration::from_secs(10)).await;
+
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(10)) => {}
+                _ = shutdown::requested() => return Ok(()),
+            }
         }
 
-        if let Err(err) = ProfileRepls::download(&user.fields.token, user.clone()).await {
+        let token = match crypto::decrypt_secret(&user.fields.token) {
+            Ok(token) => token,
+            Err(err) => {
+                error!(
+                    "Couldn't decrypt `{}`'s token: {err:#}",
+                    user.fields.username
+                );
+
+                user.fields.status = ProcessState::ErroredMain;
+                airtable::update_records(vec![user.clone()]).await?;
+                lease.release().await;
+                continue;
+            }
+        };
+
+        let span = tracing::info_span!(
+            "export",
+            user_id = user.fields.id,
+            username = %user.fields.username,
+            record_id = %user.id,
+        );
+
+        health::set_busy(true);
+        let cancel = CancelWatch::spawn(user.id.clone()).with_lease(&lease);
+        let result = ProfileRepls::download(&token, user.clone(), cancel)
+            .instrument(span)
+            .await;
+        health::set_busy(false);
+
+        if lease.is_lost() {
+            // Whatever happened, the record is the other worker's to update now
+            if let Err(err) = result {
+                warn!(
+                    "`{}`'s export failed after losing its lease: {err:#}",
+                    user.fields.username
+                );
+            }
+        } else if let Err(err) = result {
             error!("Error with `{}`'s download: {err:#?}", user.fields.username);
 
             user.fields.status = ProcessState::ErroredMain;
@@ -232,5 +643,7 @@ async fn airtable_loop() -> Result<()> {
             // )
             // .await;
         }
+
+        lease.release().await;
     }
 }
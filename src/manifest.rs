@@ -0,0 +1,108 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::{archive::ArchiveFormat, git_remote::GitPushReport, r2};
+
+/// Written to the root of every export archive, describing what ended up in it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExportManifest {
+    pub user_id: i64,
+    pub username: String,
+    pub generated_at: Option<DateTime<Utc>>,
+    pub repls: Vec<ManifestRepl>,
+
+    /// Where the archive this manifest describes was uploaded to.
+    #[serde(default)]
+    pub archive: Option<String>,
+
+    #[serde(default)]
+    pub archive_format: ArchiveFormat,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestRepl {
+    pub id: String,
+    pub slug: String,
+    pub title: String,
+    pub status: ManifestReplStatus,
+    pub file_count: usize,
+
+    /// The repl's `timeUpdated` when it was exported, to tell if a later export can reuse it.
+    #[serde(default)]
+    pub time_updated: Option<String>,
+
+    /// Only present if the user asked for their repls to be pushed to a git remote.
+    pub git_push: Option<GitPushReport>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ManifestReplStatus {
+    Full,
+    NoHistory,
+    Failed,
+    TimedOut,
+}
+
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+impl ExportManifest {
+    pub fn new(user_id: i64, username: String) -> Self {
+        Self {
+            user_id,
+            username,
+            ..Default::default()
+        }
+    }
+
+    /// The manifest of the user's last successful export, if they've had one.
+    pub async fn fetch_previous(user_id: i64) -> Option<Self> {
+        let bytes = r2::get_file_contents(format!("manifest/{user_id}")).await?;
+
+        match serde_json::from_slice(&bytes) {
+            Ok(manifest) => Some(manifest),
+            Err(err) => {
+                warn!("Couldn't parse the previous export manifest for {user_id}: {err}");
+                None
+            }
+        }
+    }
+
+    /// Keep a copy of the manifest in the bucket so the next export can be incremental.
+    pub async fn upload(&self) -> Result<()> {
+        r2::upload_str(
+            &format!("manifest/{}", self.user_id),
+            &serde_json::to_string(self)?,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Write the manifest into the root of the directory that's about to be archived.
+    pub async fn write(&mut self, dir: &str) -> Result<()> {
+        self.generated_at = Some(Utc::now());
+
+        fs::write(
+            format!("{dir}/{MANIFEST_FILENAME}"),
+            serde_json::to_string_pretty(self)?,
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+impl ManifestRepl {
+    /// Whether an archive entry belongs to this repl.
+    pub fn owns(&self, entry: &str) -> bool {
+        match self.status {
+            ManifestReplStatus::Full => entry.starts_with(&format!("{}/", self.slug)),
+            ManifestReplStatus::NoHistory => entry == format!("{}.zip", self.slug),
+            ManifestReplStatus::Failed | ManifestReplStatus::TimedOut => false,
+        }
+    }
+}
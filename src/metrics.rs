@@ -0,0 +1,271 @@
+//! Prometheus metrics, served by the API at `/metrics`. Every replica keeps its own, so sum
+//! them across pods (or take the max, for the job store gauges every replica reports).
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Error;
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_histogram_vec_with_registry, register_histogram_with_registry,
+    register_int_counter_vec_with_registry, register_int_counter_with_registry,
+    register_int_gauge_vec_with_registry, Encoder, Histogram, HistogramVec, IntCounter,
+    IntCounterVec, IntGaugeVec, Registry, TextEncoder,
+};
+use reqwest::StatusCode;
+
+use crate::{
+    airtable::{AirtableSyncedUser, ProcessState},
+    manifest::ManifestReplStatus,
+};
+
+static REGISTRY: Lazy<Registry> =
+    Lazy::new(|| Registry::new_custom(Some("takeout".to_string()), None).expect("registry"));
+
+static JOBS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec_with_registry!(
+        "jobs",
+        "Jobs in the job store by state, as of the last poll",
+        &["state"],
+        REGISTRY
+    )
+    .expect("metric")
+});
+
+static REPL_OUTCOMES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec_with_registry!(
+        "repl_outcomes_total",
+        "Repl download attempts by how they ended",
+        &["status", "failure_class"],
+        REGISTRY
+    )
+    .expect("metric")
+});
+
+static REPL_DOWNLOAD_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram_with_registry!(
+        "repl_download_seconds",
+        "Time taken to download a single repl",
+        exponential_buckets(1.0, 2.0, 12).expect("buckets"),
+        REGISTRY
+    )
+    .expect("metric")
+});
+
+static OT_PACKETS_REPLAYED: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram_with_registry!(
+        "ot_packets_replayed",
+        "OT packets replayed to rebuild a single file's history",
+        exponential_buckets(1.0, 4.0, 10).expect("buckets"),
+        REGISTRY
+    )
+    .expect("metric")
+});
+
+static FILES_PER_REPL: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram_with_registry!(
+        "files_per_repl",
+        "Files downloaded from a single repl",
+        exponential_buckets(1.0, 2.0, 14).expect("buckets"),
+        REGISTRY
+    )
+    .expect("metric")
+});
+
+static ARCHIVE_BYTES: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram_with_registry!(
+        "archive_bytes",
+        "Size of the archives uploaded for users",
+        exponential_buckets(1024.0 * 1024.0, 2.0, 14).expect("buckets"),
+        REGISTRY
+    )
+    .expect("metric")
+});
+
+static MULTIPART_UPLOAD_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec_with_registry!(
+        "multipart_upload_seconds",
+        "Time taken by completed multipart uploads to storage",
+        &["kind"],
+        exponential_buckets(1.0, 2.0, 12).expect("buckets"),
+        REGISTRY
+    )
+    .expect("metric")
+});
+
+static GRAPHQL_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec_with_registry!(
+        "graphql_requests_total",
+        "Requests made to Replit's GraphQL API",
+        &["query"],
+        REGISTRY
+    )
+    .expect("metric")
+});
+
+static GRAPHQL_RATE_LIMITED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec_with_registry!(
+        "graphql_rate_limited_total",
+        "Requests to Replit's GraphQL API that were rate limited",
+        &["query"],
+        REGISTRY
+    )
+    .expect("metric")
+});
+
+static RATE_LIMITED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec_with_registry!(
+        "rate_limited_total",
+        "Requests to the API rejected by the rate limiter",
+        &["scope", "route"],
+        REGISTRY
+    )
+    .expect("metric")
+});
+
+static JOBS_REAPED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec_with_registry!(
+        "jobs_reaped_total",
+        "Jobs whose worker stopped heartbeating, by what was done with them",
+        &["outcome"],
+        REGISTRY
+    )
+    .expect("metric")
+});
+
+static CROSIS_RECONNECTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter_with_registry!(
+        "crosis_reconnects_total",
+        "Times a crosis connection to a repl had to be reestablished",
+        REGISTRY
+    )
+    .expect("metric")
+});
+
+static OT_RESYNCS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter_with_registry!(
+        "ot_resyncs_total",
+        "Files whose OT history stopped matching and were resynced from their current contents",
+        REGISTRY
+    )
+    .expect("metric")
+});
+
+/// Everything gathered so far, in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = vec![];
+    if let Err(err) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        log::error!("Couldn't encode metrics: {err}");
+    }
+
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+/// The content type [`render`] produces.
+pub fn content_type() -> String {
+    TextEncoder::new().format_type().to_string()
+}
+
+/// Replace the job counts with those from a fresh poll of the job store.
+pub fn set_jobs<'a>(jobs: impl IntoIterator<Item = &'a AirtableSyncedUser>) {
+    let mut counts = HashMap::<ProcessState, i64>::new();
+    for job in jobs {
+        *counts.entry(job.status).or_default() += 1;
+    }
+
+    JOBS.reset();
+    for (state, count) in counts {
+        JOBS.with_label_values(&[&state.to_string()]).set(count);
+    }
+}
+
+/// Record how an attempt at downloading a repl went.
+pub fn record_repl(
+    status: ManifestReplStatus,
+    error: Option<&Error>,
+    elapsed: Duration,
+    file_count: usize,
+) {
+    let status_label = match status {
+        ManifestReplStatus::Full => "full",
+        ManifestReplStatus::NoHistory => "no_history",
+        ManifestReplStatus::Failed => "failed",
+        ManifestReplStatus::TimedOut => "timed_out",
+    };
+    let failure_class = match status {
+        ManifestReplStatus::Full | ManifestReplStatus::NoHistory => "none",
+        ManifestReplStatus::TimedOut => "timeout",
+        ManifestReplStatus::Failed => error.map_or("unknown", failure_class),
+    };
+
+    REPL_OUTCOMES
+        .with_label_values(&[status_label, failure_class])
+        .inc();
+    REPL_DOWNLOAD_SECONDS.observe(elapsed.as_secs_f64());
+
+    if matches!(
+        status,
+        ManifestReplStatus::Full | ManifestReplStatus::NoHistory
+    ) {
+        FILES_PER_REPL.observe(file_count as f64);
+    }
+}
+
+/// Roughly what went wrong, from the first error in the chain we know about.
+fn failure_class(error: &Error) -> &'static str {
+    for cause in error.chain() {
+        if cause.is::<reqwest::Error>() {
+            return "network";
+        } else if cause.is::<git2::Error>() {
+            return "git";
+        } else if cause.is::<std::io::Error>() {
+            return "io";
+        } else if cause.is::<serde_json::Error>() {
+            return "decode";
+        }
+    }
+
+    "other"
+}
+
+pub fn observe_ot_packets(count: usize) {
+    OT_PACKETS_REPLAYED.observe(count as f64);
+}
+
+pub fn observe_archive_bytes(bytes: u64) {
+    ARCHIVE_BYTES.observe(bytes as f64);
+}
+
+/// `kind` is `file` or `stream`.
+pub fn observe_multipart_upload(kind: &str, elapsed: Duration) {
+    MULTIPART_UPLOAD_SECONDS
+        .with_label_values(&[kind])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Count a response from the GraphQL API to `query`.
+pub fn record_graphql(query: &str, status: StatusCode) {
+    GRAPHQL_REQUESTS.with_label_values(&[query]).inc();
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        GRAPHQL_RATE_LIMITED.with_label_values(&[query]).inc();
+    }
+}
+
+/// `scope` is whichever limit was hit, `ip` or `account`.
+pub fn record_rate_limited(scope: &str, route: &str) {
+    RATE_LIMITED.with_label_values(&[scope, route]).inc();
+}
+
+/// Count a stale job that was requeued, or given up on.
+pub fn record_reaped(gave_up: bool) {
+    let outcome = if gave_up { "gave_up" } else { "requeued" };
+    JOBS_REAPED.with_label_values(&[outcome]).inc();
+}
+
+pub fn record_crosis_reconnect() {
+    CROSIS_RECONNECTS.inc();
+}
+
+pub fn record_ot_resync() {
+    OT_RESYNCS.inc();
+}
@@ -0,0 +1,223 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use async_zip::{base::write::ZipFileWriter, Compression, ZipEntryBuilder};
+use crosis::goval::{ot_op_component::OpComponent, OtOpComponent, OtPacket};
+
+/// The account the mock is logged in as.
+#[derive(Debug, Clone)]
+pub struct MockUser {
+    pub id: i64,
+    pub username: String,
+
+    /// The `connect.sid` every request has to come with.
+    pub token: String,
+}
+
+impl MockUser {
+    pub fn new(id: i64, username: &str, token: &str) -> Self {
+        Self {
+            id,
+            username: username.to_string(),
+            token: token.to_string(),
+        }
+    }
+}
+
+/// A repl as the mock serves it, over GraphQL, as a zip and over goval.
+#[derive(Debug, Clone)]
+pub struct FixtureRepl {
+    pub id: String,
+    pub slug: String,
+    pub title: String,
+
+    /// The folder it's in, `""` for the top level.
+    pub folder: String,
+
+    pub private: bool,
+    pub time_created: String,
+    pub time_updated: String,
+
+    /// By path from the root of the repl.
+    pub files: BTreeMap<String, FixtureFile>,
+
+    /// What the secrets channel hands back.
+    pub secrets: String,
+
+    /// Whether it can be connected to at all, only the zip can be downloaded when it can't.
+    pub connectable: bool,
+}
+
+impl FixtureRepl {
+    pub fn new(id: &str, slug: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            slug: slug.to_string(),
+            title: slug.to_string(),
+            folder: String::new(),
+            private: false,
+            time_created: "2023-01-01T00:00:00.000Z".to_string(),
+            time_updated: "2024-01-01T00:00:00.000Z".to_string(),
+            files: BTreeMap::new(),
+            secrets: String::new(),
+            connectable: true,
+        }
+    }
+
+    pub fn in_folder(mut self, folder: &str) -> Self {
+        self.folder = folder.to_string();
+        self
+    }
+
+    pub fn private(mut self) -> Self {
+        self.private = true;
+        self
+    }
+
+    pub fn unconnectable(mut self) -> Self {
+        self.connectable = false;
+        self
+    }
+
+    pub fn secrets(mut self, dotenv: &str) -> Self {
+        self.secrets = dotenv.to_string();
+        self
+    }
+
+    /// A file without any OT history.
+    pub fn file(mut self, path: &str, contents: impl Into<Vec<u8>>) -> Self {
+        self.files.insert(
+            path.to_string(),
+            FixtureFile {
+                contents: contents.into(),
+                history: vec![],
+            },
+        );
+        self
+    }
+
+    /// A file that went through each of `versions` in turn, as `(unix timestamp, contents)`.
+    pub fn file_with_history(mut self, path: &str, versions: &[(i64, &str)]) -> Self {
+        self.files
+            .insert(path.to_string(), FixtureFile::from_versions(versions));
+        self
+    }
+
+    /// What Replit reports for `bytesUsed`.
+    pub fn bytes_used(&self) -> u64 {
+        self.files
+            .values()
+            .map(|file| file.contents.len() as u64)
+            .sum()
+    }
+
+    /// The directories and files directly under `dir`, with whether each is a directory.
+    pub fn readdir(&self, dir: &str) -> BTreeMap<String, bool> {
+        let prefix = match dir.trim_matches('/') {
+            "" | "." => String::new(),
+            dir => format!("{dir}/"),
+        };
+
+        let mut entries = BTreeMap::new();
+        for path in self.files.keys() {
+            let Some(rest) = path.strip_prefix(&prefix) else {
+                continue;
+            };
+
+            match rest.split_once('/') {
+                Some((name, _)) => entries.insert(name.to_string(), true),
+                None => entries.insert(rest.to_string(), false),
+            };
+        }
+
+        entries
+    }
+
+    pub fn is_dir(&self, path: &str) -> bool {
+        let prefix = format!("{}/", path.trim_matches('/'));
+        self.files.keys().any(|file| file.starts_with(&prefix))
+    }
+
+    /// The repl's files as the zip Replit serves.
+    pub async fn zip(&self) -> Result<Vec<u8>> {
+        let mut writer = ZipFileWriter::with_tokio(std::io::Cursor::new(vec![]));
+
+        for (path, file) in &self.files {
+            let builder = ZipEntryBuilder::new(path.clone().into(), Compression::Deflate);
+            writer.write_entry_whole(builder, &file.contents).await?;
+        }
+
+        Ok(writer.close().await?.into_inner().into_inner())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FixtureFile {
+    pub contents: Vec<u8>,
+
+    /// Versions 1 and up, in order.
+    pub history: Vec<OtPacket>,
+}
+
+impl FixtureFile {
+    fn from_versions(versions: &[(i64, &str)]) -> Self {
+        let mut history = vec![];
+        let mut previous = "";
+
+        for (version, (timestamp, contents)) in (1..).zip(versions) {
+            let mut packet = OtPacket {
+                version,
+                op: edit(previous, contents),
+                crc32: crc32fast::hash(contents.as_bytes()),
+                ..Default::default()
+            };
+            packet
+                .committed
+                .get_or_insert_with(Default::default)
+                .seconds = *timestamp;
+
+            history.push(packet);
+            previous = contents;
+        }
+
+        Self {
+            contents: previous.as_bytes().to_vec(),
+            history,
+        }
+    }
+}
+
+/// The ops turning `from` into `to`, counted in chars like Replit does. Inserting before deleting
+/// means the cursor has to move past what was inserted.
+fn edit(from: &str, to: &str) -> Vec<OtOpComponent> {
+    let from: Vec<char> = from.chars().collect();
+    let to: Vec<char> = to.chars().collect();
+
+    let prefix = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let suffix = from[prefix..]
+        .iter()
+        .rev()
+        .zip(to[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let inserted: String = to[prefix..to.len() - suffix].iter().collect();
+    let deleted = from.len() - suffix - prefix;
+
+    let mut ops = vec![];
+    if prefix > 0 {
+        ops.push(OpComponent::Skip(prefix as u32));
+    }
+    if !inserted.is_empty() {
+        ops.push(OpComponent::Insert(inserted));
+    }
+    if deleted > 0 {
+        ops.push(OpComponent::Delete(deleted as u32));
+    }
+
+    ops.into_iter()
+        .map(|op| OtOpComponent {
+            op_component: Some(op),
+        })
+        .collect()
+}
@@ -0,0 +1,262 @@
+//! Just enough of goval for a download: opening and closing channels, and the gcsfiles, ot and
+//! secrets services, answered from a fixture repl or a recorded trace.
+
+use std::collections::HashMap;
+
+use axum::extract::ws::{Message, WebSocket};
+use crosis::goval::{self, command::Body, Command};
+use log::{debug, warn};
+use prost::Message as _;
+
+use super::{fixture::FixtureRepl, replay::Replay};
+
+/// `OpenChannelRes.state` for a new channel.
+const CHANNEL_CREATED: i32 = 0;
+
+/// `OpenChannelRes.state` for a channel that couldn't be opened.
+const CHANNEL_ERROR: i32 = 2;
+
+/// A channel opened on the connection.
+struct Channel {
+    service: String,
+
+    /// `ot:<path>` for OT channels.
+    name: String,
+}
+
+/// Where a connection's answers come from.
+pub(super) enum Source {
+    Fixture(FixtureRepl),
+    Replay(Replay),
+}
+
+/// Serve one connection.
+pub(super) async fn session(mut socket: WebSocket, source: Source) {
+    let mut session = Session {
+        source,
+        channels: HashMap::new(),
+        next_channel: 1,
+    };
+
+    let boot = Command {
+        body: Some(Body::BootStatus(goval::BootStatus {
+            stage: goval::boot_status::Stage::Complete as i32,
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    if send(&mut socket, boot).await.is_err() {
+        return;
+    }
+
+    while let Some(Ok(message)) = socket.recv().await {
+        let bytes = match message {
+            Message::Binary(bytes) => bytes,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+
+        let command = match Command::decode(bytes.as_slice()) {
+            Ok(command) => command,
+            Err(err) => {
+                warn!("Mock goval got a command it couldn't decode: {err}");
+                continue;
+            }
+        };
+
+        for reply in session.respond(command) {
+            if send(&mut socket, reply).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    debug!("Mock goval connection closed");
+}
+
+async fn send(socket: &mut WebSocket, command: Command) -> Result<(), axum::Error> {
+    socket.send(Message::Binary(command.encode_to_vec())).await
+}
+
+struct Session {
+    source: Source,
+    channels: HashMap<i32, Channel>,
+    next_channel: i32,
+}
+
+impl Session {
+    fn respond(&mut self, command: Command) -> Vec<Command> {
+        let Some(body) = command.body else {
+            return vec![];
+        };
+
+        let reply = |body| Command {
+            channel: command.channel,
+            session: command.session,
+            r#ref: command.r#ref.clone(),
+            body: Some(body),
+        };
+
+        if let Body::Ping(_) = body {
+            return vec![reply(Body::Pong(goval::Pong::default()))];
+        }
+
+        if command.channel == 0 {
+            return match body {
+                Body::OpenChan(open) => self.open(open, reply),
+                Body::CloseChan(close) => {
+                    self.channels.remove(&close.id);
+                    vec![reply(Body::CloseChanRes(goval::CloseChannelRes {
+                        id: close.id,
+                        ..Default::default()
+                    }))]
+                }
+                _ => vec![],
+            };
+        }
+
+        let Some(channel) = self.channels.get(&command.channel) else {
+            return vec![reply(Body::Error("No such channel".to_string()))];
+        };
+
+        let body = match &mut self.source {
+            Source::Fixture(repl) => match channel.service.as_str() {
+                "gcsfiles" => gcsfiles(repl, body),
+                "ot" => ot(repl, &channel.name, body),
+                "secrets" => secrets(repl, body),
+                service => Body::Error(format!("Unsupported service {service}")),
+            },
+            Source::Replay(replay) => replay
+                .answer(&channel.service, &channel.name, &body)
+                .and_then(|recorded| recorded.body)
+                .unwrap_or_else(|| Body::Error("Not in the trace".to_string())),
+        };
+
+        vec![reply(body)]
+    }
+
+    fn open(&mut self, open: goval::OpenChannel, reply: impl Fn(Body) -> Command) -> Vec<Command> {
+        let known = matches!(open.service.as_str(), "gcsfiles" | "ot" | "secrets");
+        if !known && matches!(self.source, Source::Fixture(_)) {
+            return vec![reply(Body::OpenChanRes(goval::OpenChannelRes {
+                state: CHANNEL_ERROR,
+                error: format!("Unknown service {}", open.service),
+                ..Default::default()
+            }))];
+        }
+
+        let id = self.next_channel;
+        self.next_channel += 1;
+
+        let mut replies = vec![reply(Body::OpenChanRes(goval::OpenChannelRes {
+            id,
+            state: CHANNEL_CREATED,
+            ..Default::default()
+        }))];
+
+        let unprompted = match &mut self.source {
+            // OT channels start out by saying where they're at, which is nowhere until linked
+            Source::Fixture(_) if open.service == "ot" => vec![Command {
+                body: Some(Body::Otstatus(goval::OtStatus::default())),
+                ..Default::default()
+            }],
+            Source::Fixture(_) => vec![],
+            Source::Replay(replay) => replay.open(&open.service, &open.name),
+        };
+        replies.extend(unprompted.into_iter().map(|command| Command {
+            channel: id,
+            ..command
+        }));
+
+        self.channels.insert(
+            id,
+            Channel {
+                service: open.service,
+                name: open.name,
+            },
+        );
+
+        replies
+    }
+}
+
+fn gcsfiles(repl: &FixtureRepl, body: Body) -> Body {
+    match body {
+        Body::Readdir(dir) => Body::Files(goval::Files {
+            files: repl
+                .readdir(&dir.path)
+                .into_iter()
+                .map(|(path, is_dir)| goval::File {
+                    path,
+                    r#type: if is_dir {
+                        goval::file::Type::Directory as i32
+                    } else {
+                        goval::file::Type::Regular as i32
+                    },
+                    ..Default::default()
+                })
+                .collect(),
+        }),
+        Body::Stat(file) => {
+            let stat = match repl.files.get(&file.path) {
+                Some(fixture) => goval::StatResult {
+                    exists: true,
+                    r#type: goval::file::Type::Regular as i32,
+                    size: fixture.contents.len() as i64,
+                    ..Default::default()
+                },
+                None if repl.is_dir(&file.path) => goval::StatResult {
+                    exists: true,
+                    r#type: goval::file::Type::Directory as i32,
+                    ..Default::default()
+                },
+                None => goval::StatResult::default(),
+            };
+
+            Body::StatRes(stat)
+        }
+        Body::Read(file) => match repl.files.get(&file.path) {
+            Some(fixture) => Body::File(goval::File {
+                content: fixture.contents.clone(),
+                ..file
+            }),
+            None => Body::Error(format!("No such file {}", file.path)),
+        },
+        _ => Body::Error("Unsupported gcsfiles request".to_string()),
+    }
+}
+
+fn ot(repl: &FixtureRepl, name: &str, body: Body) -> Body {
+    let path = name.strip_prefix("ot:").unwrap_or(name);
+    let Some(fixture) = repl.files.get(path) else {
+        return Body::Error(format!("No such file {path}"));
+    };
+
+    match body {
+        Body::OtLinkFile(link) => Body::OtLinkFileResponse(goval::OtLinkFileResponse {
+            version: fixture.history.len() as u32,
+            linked_file: link.file.map(|file| goval::File {
+                content: fixture.contents.clone(),
+                ..file
+            }),
+        }),
+        Body::OtFetchRequest(fetch) => Body::OtFetchResponse(goval::OtFetchResponse {
+            packets: fixture
+                .history
+                .iter()
+                .filter(|packet| (fetch.version_from..=fetch.version_to).contains(&packet.version))
+                .cloned()
+                .collect(),
+        }),
+        _ => Body::Error("Unsupported ot request".to_string()),
+    }
+}
+
+fn secrets(repl: &FixtureRepl, body: Body) -> Body {
+    match body {
+        Body::SecretsGetRequest(_) => Body::SecretsGetResponse(goval::SecretsGetResponse {
+            contents: repl.secrets.clone(),
+        }),
+        _ => Body::Error("Unsupported secrets request".to_string()),
+    }
+}
@@ -0,0 +1,398 @@
+//! A local stand-in for Replit, so downloads can run end to end without a real account. It
+//! answers the GraphQL queries in `src/graphql`, hands out connection metadata pointing at its own
+//! goval, and serves zips, all from fixture repls. Given a recorded trace (see
+//! [`crate::crosisdownload::trace`]) it plays back that session's goval instead.
+//!
+//! Point the crate at it with [`MockReplit::install`] before anything reads the config. To run
+//! whole exports, [`MockStores::install`] stands in for Airtable, R2 and Loops as well.
+
+mod fixture;
+mod goval;
+mod replay;
+mod stores;
+
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{format_err, Result};
+use axum::{
+    extract::{ws::WebSocketUpgrade, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use log::{error, info};
+use serde_json::{json, Value};
+use tokio::{net::TcpListener, task::JoinHandle};
+
+use crate::{config, crosisdownload::trace::Trace};
+pub use fixture::{FixtureFile, FixtureRepl, MockUser};
+use goval::Source;
+use replay::Replay;
+pub use stores::{MockStores, SentEmail};
+
+/// Repls per page, small enough that paging gets exercised.
+const PAGE_SIZE: usize = 5;
+
+struct Mock {
+    user: MockUser,
+    repls: Vec<FixtureRepl>,
+    addr: SocketAddr,
+
+    /// When set, goval plays this back instead of answering from the fixtures.
+    trace: Option<Trace>,
+}
+
+/// A running mock, shut down once it's dropped.
+pub struct MockReplit {
+    mock: Arc<Mock>,
+    server: JoinHandle<()>,
+}
+
+impl MockReplit {
+    /// Start serving `repls` owned by `user` on a free local port.
+    pub async fn start(user: MockUser, repls: Vec<FixtureRepl>) -> Result<Self> {
+        Self::serve(user, repls, None).await
+    }
+
+    /// Start serving the repl `trace` was recorded from, with goval playing the trace back.
+    /// Everything is logged in as `replay`.
+    pub async fn replay(trace: Trace) -> Result<Self> {
+        let header = &trace.header;
+        let user = MockUser::new(0, &header.username, "replay");
+        let repl = FixtureRepl::new(&header.repl_id, &header.slug);
+
+        Self::serve(user, vec![repl], Some(trace)).await
+    }
+
+    async fn serve(user: MockUser, repls: Vec<FixtureRepl>, trace: Option<Trace>) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mock = Arc::new(Mock {
+            user,
+            repls,
+            addr: listener.local_addr()?,
+            trace,
+        });
+
+        let app = Router::new()
+            .route("/graphql", post(graphql))
+            .route(
+                "/data/repls/:id/get_connection_metadata",
+                post(connection_metadata),
+            )
+            .route("/wsv2/:token", get(goval_socket))
+            .route("/:user/:zip", get(zip))
+            .with_state(mock.clone());
+
+        let server = tokio::spawn(async move {
+            if let Err(err) = axum::serve(listener, app).await {
+                error!("Mock Replit stopped: {err}");
+            }
+        });
+
+        info!("Mock Replit listening on {}", mock.addr);
+
+        Ok(Self { mock, server })
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.mock.addr)
+    }
+
+    /// Send everything meant for Replit to the mock instead.
+    pub fn install(&self) -> Result<()> {
+        std::env::set_var("REPLIT_URL", self.url());
+
+        let url = &config::init()?.replit.url;
+        if *url != self.url() {
+            return Err(format_err!(
+                "The config was loaded before the mock was installed, Replit is still at {url}"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for MockReplit {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+impl Mock {
+    fn authorized(&self, headers: &HeaderMap) -> bool {
+        let session = format!("connect.sid={}", self.user.token);
+
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|cookie| cookie.to_str().ok())
+            .flat_map(|cookie| cookie.split(';'))
+            .any(|cookie| cookie.trim() == session)
+    }
+
+    fn repl(&self, id: &str) -> Option<&FixtureRepl> {
+        self.repls.iter().find(|repl| repl.id == id)
+    }
+
+    fn user_json(&self, folder: Option<Value>) -> Value {
+        let user = &self.user;
+
+        json!({
+            "id": user.id,
+            "email": format!("{}@example.com", user.username),
+            "username": user.username,
+            "firstName": null,
+            "lastName": null,
+            "isVerified": true,
+            "displayName": user.username,
+            "fullName": user.username,
+            "url": format!("/@{}", user.username),
+            "bio": null,
+            "locale": "en-US",
+            "location": null,
+            "recentReplsCount": self.repls.len(),
+            "isImpersonated": false,
+            "replFolderByPath": folder,
+        })
+    }
+
+    fn repl_json(&self, repl: &FixtureRepl) -> Value {
+        let username = &self.user.username;
+
+        json!({
+            "id": repl.id,
+            "title": repl.title,
+            "description": null,
+            "slug": repl.slug,
+            "url": format!("/@{username}/{}", repl.slug),
+            "isPrivate": repl.private,
+            "isStarred": false,
+            "isOwner": true,
+            "isAlwaysOn": false,
+            "isBoosted": false,
+            "isRenamed": false,
+            "isProjectFork": false,
+            "likeCount": 0,
+            "language": "python3",
+            "iconUrl": "",
+            "hostedUrl": format!("https://{}.{username}.repl.co", repl.slug),
+            "publishedAs": null,
+            "templateInfo": { "label": "Python", "iconUrl": "" },
+            "timeCreated": repl.time_created,
+            "timeUpdated": repl.time_updated,
+            "owner": { "__typename": "User", "username": username, "image": "" },
+            "bytesUsed": {
+                "__typename": "ReplBytesUsed",
+                "value": repl.bytes_used().to_string(),
+            },
+        })
+    }
+
+    /// The folder at `path` and a page of the repls in it. Folders are made up from the repls'.
+    fn folder_json(&self, path: &str, after: Option<&str>) -> Value {
+        let pathnames = |path: &str| -> Vec<String> {
+            path.split('/')
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect()
+        };
+
+        let mut subfolders: Vec<String> = self
+            .repls
+            .iter()
+            .filter_map(|repl| {
+                let rest = match path {
+                    "" => repl.folder.as_str(),
+                    path => repl.folder.strip_prefix(path)?.strip_prefix('/')?,
+                };
+                let name = rest.split('/').next().filter(|name| !name.is_empty())?;
+
+                Some(match path {
+                    "" => name.to_string(),
+                    path => format!("{path}/{name}"),
+                })
+            })
+            .collect();
+        subfolders.sort();
+        subfolders.dedup();
+
+        let folders: Vec<Value> = subfolders
+            .iter()
+            .map(|folder| {
+                json!({
+                    "id": format!("folder:{folder}"),
+                    "name": folder.rsplit('/').next(),
+                    "pathnames": pathnames(folder),
+                    "canEdit": true,
+                    "image": null,
+                    "replsCount": self.repls.iter().filter(|repl| repl.folder == *folder).count(),
+                    "folderType": null,
+                })
+            })
+            .collect();
+
+        let repls: Vec<&FixtureRepl> = self
+            .repls
+            .iter()
+            .filter(|repl| repl.folder == path)
+            .collect();
+        let (items, next_cursor) = page(&repls, after);
+
+        let parent = (!path.is_empty()).then(|| {
+            let parent = path
+                .rsplit_once('/')
+                .map(|(parent, _)| parent)
+                .unwrap_or("");
+            json!({ "id": format!("folder:{parent}"), "pathnames": pathnames(parent) })
+        });
+
+        json!({
+            "id": format!("folder:{path}"),
+            "ownerId": self.user.id,
+            "pathnames": pathnames(path),
+            "canEdit": true,
+            "canCreateSubFolders": true,
+            "parent": parent,
+            "folders": folders,
+            "repls": {
+                "items": items.iter().map(|repl| self.repl_json(repl)).collect::<Vec<_>>(),
+                "pageInfo": { "nextCursor": next_cursor },
+            },
+        })
+    }
+
+    fn profile_repls_json(&self, after: Option<&str>) -> Value {
+        let repls: Vec<&FixtureRepl> = self.repls.iter().filter(|repl| !repl.private).collect();
+        let (items, next_cursor) = page(&repls, after);
+
+        json!({
+            "items": items.iter().map(|repl| self.repl_json(repl)).collect::<Vec<_>>(),
+            "pageInfo": {
+                "hasNextPage": next_cursor.is_some(),
+                "nextCursor": next_cursor,
+            },
+        })
+    }
+}
+
+/// The page of `items` starting at the `after` cursor, and the cursor for the next one.
+fn page<'a, T>(items: &'a [T], after: Option<&str>) -> (&'a [T], Option<String>) {
+    let start = after
+        .and_then(|cursor| cursor.parse().ok())
+        .unwrap_or(0usize)
+        .min(items.len());
+    let end = (start + PAGE_SIZE).min(items.len());
+
+    (
+        &items[start..end],
+        (end < items.len()).then(|| end.to_string()),
+    )
+}
+
+async fn graphql(
+    State(mock): State<Arc<Mock>>,
+    headers: HeaderMap,
+    Json(request): Json<Value>,
+) -> Response {
+    let variables = &request["variables"];
+    let authorized = mock.authorized(&headers);
+
+    let data = match request["operationName"].as_str() {
+        Some("QuickUserQuery") => json!({
+            "currentUser": authorized.then(|| mock.user_json(None)),
+        }),
+        Some("ReplList" | "ReplsDashboardReplFolderList") => {
+            let path = variables["path"]
+                .as_str()
+                .unwrap_or_default()
+                .trim_matches('/');
+            let folder = mock.folder_json(path, variables["after"].as_str());
+
+            json!({ "currentUser": authorized.then(|| mock.user_json(Some(folder))) })
+        }
+        Some("ProfileRepls") => {
+            let user = (variables["user_id"].as_i64() == Some(mock.user.id)).then(
+                || json!({ "profileRepls": mock.profile_repls_json(variables["after"].as_str()) }),
+            );
+
+            json!({ "user": user })
+        }
+        operation => {
+            let message = format!("The mock doesn't know the operation {operation:?}");
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "errors": [{ "message": message }] })),
+            )
+                .into_response();
+        }
+    };
+
+    Json(json!({ "data": data })).into_response()
+}
+
+async fn connection_metadata(
+    State(mock): State<Arc<Mock>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if !mock.authorized(&headers) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    match mock.repl(&id) {
+        Some(repl) if repl.connectable => Json(json!({
+            // The token is all goval gets to go on, so it's just the repl
+            "token": repl.id,
+            "gurl": format!("ws://{}", mock.addr),
+            "conmanURL": format!("http://{}", mock.addr),
+            "dotdevHostname": "",
+            "error": null,
+        }))
+        .into_response(),
+        Some(_) => (StatusCode::FORBIDDEN, "Repl can't be connected to").into_response(),
+        None => (StatusCode::NOT_FOUND, "Repl not found").into_response(),
+    }
+}
+
+async fn goval_socket(
+    State(mock): State<Arc<Mock>>,
+    Path(token): Path<String>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let source = match (mock.repl(&token), &mock.trace) {
+        (Some(_), Some(trace)) => Source::Replay(Replay::new(trace)),
+        (Some(repl), None) => Source::Fixture(repl.clone()),
+        (None, _) => return (StatusCode::NOT_FOUND, "Unknown token").into_response(),
+    };
+
+    ws.on_upgrade(move |socket| goval::session(socket, source))
+}
+
+async fn zip(
+    State(mock): State<Arc<Mock>>,
+    Path((user, zip)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if !mock.authorized(&headers) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    let slug = zip.strip_suffix(".zip");
+    let repl = match (user.strip_prefix('@'), slug) {
+        (Some(user), Some(slug)) if user == mock.user.username => {
+            mock.repls.iter().find(|repl| repl.slug == slug)
+        }
+        _ => None,
+    };
+
+    let Some(repl) = repl else {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    };
+
+    match repl.zip().await {
+        Ok(zip) => ([(header::CONTENT_TYPE, "application/zip")], zip).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
@@ -0,0 +1,107 @@
+//! Answers goval from a recorded trace instead of a fixture. Requests are matched to the recorded
+//! ones by what they ask for rather than when, since files get spread over channels differently
+//! every run.
+
+use std::collections::{HashMap, HashSet};
+
+use crosis::goval::{command::Body, Command};
+
+use crate::crosisdownload::trace::{Trace, TraceEvent};
+
+struct RecordedRequest {
+    channel: u32,
+    sent: Command,
+    received: Command,
+    answered: bool,
+}
+
+pub(super) struct Replay {
+    /// Recorded channels in the order they were opened, by service and name.
+    opens: Vec<(u32, String, String)>,
+    opened: HashSet<u32>,
+
+    /// What goval sent on each channel without being asked.
+    unprompted: HashMap<u32, Vec<Command>>,
+
+    requests: Vec<RecordedRequest>,
+}
+
+impl Replay {
+    pub(super) fn new(trace: &Trace) -> Self {
+        let mut replay = Self {
+            opens: vec![],
+            opened: HashSet::new(),
+            unprompted: HashMap::new(),
+            requests: vec![],
+        };
+
+        for event in &trace.events {
+            match event {
+                TraceEvent::Open {
+                    channel,
+                    service,
+                    name,
+                    ..
+                } => {
+                    replay
+                        .opens
+                        .push((*channel, service.clone(), name.clone().unwrap_or_default()))
+                }
+                TraceEvent::Received {
+                    channel, command, ..
+                } => replay
+                    .unprompted
+                    .entry(*channel)
+                    .or_default()
+                    .push(command.clone()),
+                TraceEvent::Request {
+                    channel,
+                    sent,
+                    received,
+                    ..
+                } => replay.requests.push(RecordedRequest {
+                    channel: *channel,
+                    sent: sent.clone(),
+                    received: received.clone(),
+                    answered: false,
+                }),
+                TraceEvent::Start(_)
+                | TraceEvent::RequestFailed { .. }
+                | TraceEvent::End { .. } => {}
+            }
+        }
+
+        replay
+    }
+
+    /// Open the next recorded channel like this one, returning what goval sent on it unasked.
+    pub(super) fn open(&mut self, service: &str, name: &str) -> Vec<Command> {
+        let next = self
+            .opens
+            .iter()
+            .find(|(channel, s, n)| s == service && n == name && !self.opened.contains(channel));
+
+        let Some((channel, ..)) = next else {
+            return vec![];
+        };
+
+        let channel = *channel;
+        self.opened.insert(channel);
+        self.unprompted.remove(&channel).unwrap_or_default()
+    }
+
+    /// What goval answered to the same request on a channel like this one.
+    pub(super) fn answer(&mut self, service: &str, name: &str, request: &Body) -> Option<Command> {
+        let opens = &self.opens;
+        let recorded = self.requests.iter_mut().find(|recorded| {
+            !recorded.answered
+                && recorded.sent.body.as_ref() == Some(request)
+                && opens.iter().any(|(channel, s, n)| {
+                    *channel == recorded.channel && s == service && n == name
+                })
+        })?;
+
+        recorded.answered = true;
+        Some(recorded.received.clone())
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use airtable_api::Record;
+use anyhow::{format_err, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use once_cell::sync::OnceCell;
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, DuplexStream},
+    sync::oneshot,
+};
+
+use crate::{
+    airtable::{self, AirtableSyncedUser, JobStore},
+    email::emails::{self, Mailer},
+    r2::{self, ObjectStore},
+};
+
+static STORES: OnceCell<MockStores> = OnceCell::new();
+
+/// In-memory stand-ins for Airtable, R2 and Loops, so an export can run through to the email
+/// without any of them. Once installed, they're the process's [`JobStore`], [`ObjectStore`] and
+/// [`Mailer`].
+#[derive(Default)]
+pub struct MockStores {
+    records: Mutex<Vec<Record<AirtableSyncedUser>>>,
+    objects: Mutex<HashMap<String, Vec<u8>>>,
+    emails: Mutex<Vec<SentEmail>>,
+    next_record: AtomicUsize,
+}
+
+/// An email that would have gone out through Loops.
+#[derive(Debug, Clone)]
+pub struct SentEmail {
+    pub to: String,
+    pub payload: Value,
+}
+
+impl MockStores {
+    /// Start using the stores, or get the ones already in use.
+    pub fn install() -> &'static Self {
+        let mut installed = false;
+        let stores = STORES.get_or_init(|| {
+            installed = true;
+            Self::default()
+        });
+
+        if installed {
+            airtable::use_store(stores).expect("the mock to be installed before any job is read");
+            r2::use_store(stores).expect("the mock to be installed before anything is uploaded");
+            emails::use_mailer(stores).expect("the mock to be installed before any email is sent");
+        }
+
+        stores
+    }
+
+    /// Queue a job, like signing up would.
+    pub fn add_record(&self, fields: AirtableSyncedUser) -> Record<AirtableSyncedUser> {
+        let number = self.next_record.fetch_add(1, Ordering::Relaxed);
+        let record = Record {
+            id: format!("recMock{number:010}"),
+            fields,
+            created_time: Some(Utc::now()),
+        };
+
+        self.records
+            .lock()
+            .expect("not poisoned")
+            .push(record.clone());
+
+        record
+    }
+
+    pub fn record(&self, record_id: &str) -> Option<Record<AirtableSyncedUser>> {
+        self.records()
+            .into_iter()
+            .find(|record| record.id == record_id)
+    }
+
+    pub fn records(&self) -> Vec<Record<AirtableSyncedUser>> {
+        self.records.lock().expect("not poisoned").clone()
+    }
+
+    pub fn object(&self, remote_path: &str) -> Option<Vec<u8>> {
+        self.objects
+            .lock()
+            .expect("not poisoned")
+            .get(remote_path)
+            .cloned()
+    }
+
+    fn put_object(&self, remote_path: &str, bytes: Vec<u8>) {
+        self.objects
+            .lock()
+            .expect("not poisoned")
+            .insert(remote_path.to_string(), bytes);
+    }
+
+    /// What a presigned link to `remote_path` would be.
+    pub fn link(&self, remote_path: &str, filename: &str) -> String {
+        format!("mock://{remote_path}?filename={filename}")
+    }
+
+    pub fn emails(&self) -> Vec<SentEmail> {
+        self.emails.lock().expect("not poisoned").clone()
+    }
+}
+
+#[async_trait]
+impl JobStore for MockStores {
+    async fn create(&self, fields: AirtableSyncedUser) -> Result<()> {
+        self.add_record(fields);
+
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<Record<AirtableSyncedUser>>> {
+        Ok(self.records())
+    }
+
+    async fn fetch(&self, record_id: &str) -> Result<Record<AirtableSyncedUser>> {
+        self.record(record_id)
+            .ok_or_else(|| format_err!("No record {record_id}"))
+    }
+
+    async fn update(&self, updates: Vec<Record<AirtableSyncedUser>>) -> Result<()> {
+        let mut records = self.records.lock().expect("not poisoned");
+
+        for update in updates {
+            let record = records
+                .iter_mut()
+                .find(|record| record.id == update.id)
+                .ok_or_else(|| format_err!("No record {}", update.id))?;
+
+            // Airtable never sees the fields that aren't serialized, so they keep their values
+            let kept = record.fields.clone();
+            record.fields = update.fields;
+            record.fields.cancel_requested = kept.cancel_requested;
+            record.fields.worker_id = kept.worker_id;
+            record.fields.lease_expires_at = kept.lease_expires_at;
+            record.fields.heartbeat_at = kept.heartbeat_at;
+        }
+
+        Ok(())
+    }
+
+    async fn patch(&self, record_id: &str, fields: Value) -> Result<()> {
+        let fields = fields
+            .as_object()
+            .ok_or_else(|| format_err!("Patching {record_id} with something other than fields"))?;
+
+        let mut records = self.records.lock().expect("not poisoned");
+        let record = records
+            .iter_mut()
+            .find(|record| record.id == record_id)
+            .ok_or_else(|| format_err!("No record {record_id}"))?;
+
+        for (name, value) in fields {
+            let job = &mut record.fields;
+            match name.as_str() {
+                "Worker ID" => job.worker_id = value.as_str().map(str::to_string),
+                "Lease Expires At" => job.lease_expires_at = time(value)?,
+                "Heartbeat At" => job.heartbeat_at = time(value)?,
+                "Cancel Requested" => job.cancel_requested = value.as_bool().unwrap_or(false),
+                "Status" => job.status = serde_json::from_value(value.clone())?,
+                "Connection Token" => job.token = value.as_str().unwrap_or_default().to_string(),
+                "Git Remote Password" => {
+                    job.git_remote_password = value.as_str().map(str::to_string)
+                }
+                _ => return Err(format_err!("The mock can't patch {name:?}")),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn time(value: &Value) -> Result<Option<DateTime<Utc>>> {
+    Ok(serde_json::from_value(value.clone())?)
+}
+
+#[async_trait]
+impl ObjectStore for MockStores {
+    async fn upload(&self, remote_path: String, local_path: String) -> Result<()> {
+        self.put_object(&remote_path, tokio::fs::read(&local_path).await?);
+
+        Ok(())
+    }
+
+    async fn upload_from_reader(
+        &self,
+        remote_path: String,
+        mut reader: DuplexStream,
+        finish: oneshot::Receiver<bool>,
+    ) -> Result<u64> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).await?;
+        drop(reader);
+
+        if !finish.await.unwrap_or(false) {
+            return Err(format_err!("Streaming upload to {remote_path} was aborted"));
+        }
+
+        let total = bytes.len() as u64;
+        self.put_object(&remote_path, bytes);
+
+        Ok(total)
+    }
+
+    async fn download(&self, remote_path: &str, local_path: &str) -> Result<()> {
+        let bytes = self
+            .object(remote_path)
+            .ok_or_else(|| format_err!("{remote_path} isn't in the mock bucket"))?;
+        tokio::fs::write(local_path, bytes).await?;
+
+        Ok(())
+    }
+
+    async fn put(&self, remote_path: &str, bytes: &[u8]) -> Result<()> {
+        self.put_object(remote_path, bytes.to_vec());
+
+        Ok(())
+    }
+
+    async fn get(&self, remote_path: &str) -> Option<Vec<u8>> {
+        self.object(remote_path)
+    }
+
+    async fn link(&self, remote_path: &str, filename: &str) -> Result<String> {
+        Ok(MockStores::link(self, remote_path, filename))
+    }
+}
+
+#[async_trait]
+impl Mailer for MockStores {
+    async fn send(&self, to: &str, payload: &Value) -> Result<()> {
+        self.emails.lock().expect("not poisoned").push(SentEmail {
+            to: to.to_string(),
+            payload: payload.clone(),
+        });
+
+        Ok(())
+    }
+}
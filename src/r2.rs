@@ -1,24 +1,30 @@
-use anyhow::Result;
+use anyhow::{format_err, Result};
+use async_trait::async_trait;
 use awsregion::Region;
 use futures::stream::{self, StreamExt};
-use log::{debug, info};
+use log::{debug, error, info};
 use s3::creds::Credentials;
-use s3::error::S3Error;
-use s3::request::ResponseData;
+use s3::serde_types::Part;
 use s3::Bucket;
 use std::collections::HashMap;
 use tokio::fs::File;
-use tokio::io::{self, AsyncReadExt, AsyncSeekExt};
+use tokio::io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::oneshot;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
 
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
+
+use crate::config;
 
 static BUCKET: Lazy<Bucket> = Lazy::new(|| {
     let credentials = Credentials::new(None, None, None, None, None).expect("credentials");
+    let config = &config::get().storage;
 
     Bucket::new(
-        "replit-takeout",
+        &config.bucket,
         Region::R2 {
-            account_id: "90e2da927f7b2f6c30f10f86d1b5e679".to_string(),
+            account_id: config.account_id.clone(),
         },
         credentials,
     )
@@ -26,8 +32,112 @@ static BUCKET: Lazy<Bucket> = Lazy::new(|| {
     .with_path_style()
 });
 
-const CHUNK_SIZE: usize = 100 * 1024 * 1024; // 100 MiB
-const CONCURRENT_UPLOADS: usize = 8;
+static STORE: OnceCell<&'static dyn ObjectStore> = OnceCell::new();
+
+// Streamed uploads hold every in-flight part in memory, so they're configured with smaller parts
+// than file uploads. R2 needs every part but the last to be the same size, and at least 5 MiB.
+const STREAM_PIPE_SIZE: usize = 1024 * 1024; // 1 MiB
+
+/// Where archives and manifests are kept. R2, unless something else is put in with
+/// [`use_store`].
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    /// Upload the file at `local_path`.
+    async fn upload(&self, remote_path: String, local_path: String) -> Result<()>;
+
+    /// Upload everything read from `reader`, but only keep it if `finish` gets `true`. Returns
+    /// the number of bytes uploaded.
+    async fn upload_from_reader(
+        &self,
+        remote_path: String,
+        reader: DuplexStream,
+        finish: oneshot::Receiver<bool>,
+    ) -> Result<u64>;
+
+    async fn download(&self, remote_path: &str, local_path: &str) -> Result<()>;
+
+    async fn put(&self, remote_path: &str, bytes: &[u8]) -> Result<()>;
+
+    async fn get(&self, remote_path: &str) -> Option<Vec<u8>>;
+
+    /// A link to download `remote_path` from, saved as `filename`.
+    async fn link(&self, remote_path: &str, filename: &str) -> Result<String>;
+}
+
+/// Keep objects in `store` instead of R2 for the rest of the process. Only works before anything
+/// has been uploaded or downloaded.
+pub fn use_store(store: &'static dyn ObjectStore) -> Result<()> {
+    STORE
+        .set(store)
+        .map_err(|_| format_err!("The object store is already in use"))
+}
+
+fn store() -> &'static dyn ObjectStore {
+    *STORE.get_or_init(|| &R2)
+}
+
+struct R2;
+
+#[async_trait]
+impl ObjectStore for R2 {
+    async fn upload(&self, remote_path: String, local_path: String) -> Result<()> {
+        upload_file(remote_path, local_path).await
+    }
+
+    async fn upload_from_reader(
+        &self,
+        remote_path: String,
+        reader: DuplexStream,
+        finish: oneshot::Receiver<bool>,
+    ) -> Result<u64> {
+        upload_from_reader(remote_path, reader, finish).await
+    }
+
+    async fn download(&self, remote_path: &str, local_path: &str) -> Result<()> {
+        let mut file = File::create(local_path).await?;
+
+        let status = BUCKET.get_object_to_writer(remote_path, &mut file).await?;
+        if status != 200 {
+            return Err(format_err!(
+                "Downloading {remote_path} returned status {status}"
+            ));
+        }
+
+        file.flush().await?;
+
+        Ok(())
+    }
+
+    async fn put(&self, remote_path: &str, bytes: &[u8]) -> Result<()> {
+        BUCKET.put_object(remote_path, bytes).await?;
+
+        Ok(())
+    }
+
+    async fn get(&self, remote_path: &str) -> Option<Vec<u8>> {
+        BUCKET
+            .get_object(remote_path)
+            .await
+            .map(|x| Some(x.to_vec()))
+            .unwrap_or(None)
+    }
+
+    async fn link(&self, remote_path: &str, filename: &str) -> Result<String> {
+        let mut custom_queries = HashMap::new();
+        custom_queries.insert(
+            "response-content-disposition".into(),
+            format!("attachment; filename=\"{filename}\""),
+        );
+
+        Ok(BUCKET
+            .presign_get(
+                remote_path,
+                config::get().storage.link_expiry_secs(),
+                Some(custom_queries),
+            )
+            .await?)
+    }
+}
 
 pub async fn read_chunk(file_path: &str, start: usize, size: usize) -> io::Result<Box<[u8]>> {
     let mut file = File::open(file_path).await?;
@@ -39,10 +149,17 @@ pub async fn read_chunk(file_path: &str, start: usize, size: usize) -> io::Resul
 }
 
 pub async fn upload(remote_path: String, local_path: String) -> Result<()> {
+    store().upload(remote_path, local_path).await
+}
+
+async fn upload_file(remote_path: String, local_path: String) -> Result<()> {
     /* Start the multipart upload. With the S3 multipart API, you start a
      * multipart upload, send the chunks (in any order - they have indices),
      * and then close out the upload. (Fun fact: S3 doesn't impose any limits
      * on how long this can take, but R2 imposes a 7 day limit.) */
+    let started = Instant::now();
+    let config = &config::get().storage;
+    let (chunk_size, concurrent_uploads) = (config.chunk_size(), config.concurrent_uploads);
     let upload_id = BUCKET
         .initiate_multipart_upload(&remote_path, "application/octet-stream")
         .await?
@@ -53,7 +170,7 @@ pub async fn upload(remote_path: String, local_path: String) -> Result<()> {
         .metadata()
         .await?
         .len();
-    let num_chunks = (file_size as f64 / CHUNK_SIZE as f64).ceil() as usize;
+    let num_chunks = (file_size as f64 / chunk_size as f64).ceil() as usize;
 
     let (part_tx, part_rx) = kanal::bounded_async(num_chunks + 1);
     let part_tx2 = part_tx.clone();
@@ -66,20 +183,20 @@ pub async fn upload(remote_path: String, local_path: String) -> Result<()> {
             let remote_path = remote_path.clone();
             tokio::spawn(async move {
                 let size = if num_chunks == chunk_index + 1 {
-                    // Conversion is safe since the output would have to be < CHUNK_SIZE
+                    // Conversion is safe since the output would have to be < chunk_size
                     // which fits into a usize
-                    (file_size % CHUNK_SIZE as u64) as usize
+                    (file_size % chunk_size as u64) as usize
                 } else {
-                    CHUNK_SIZE
+                    chunk_size
                 };
-                let chunk = read_chunk(&local_path, chunk_index * CHUNK_SIZE, size)
+                let chunk = read_chunk(&local_path, chunk_index * chunk_size, size)
                     .await
                     .unwrap();
                 let amt = chunk.len();
                 if !chunk.is_empty() {
                     debug!(
-                        "Uploading: {amt}/{CHUNK_SIZE}={} - {}/{num_chunks}",
-                        amt as f64 / CHUNK_SIZE as f64,
+                        "Uploading: {amt}/{chunk_size}={} - {}/{num_chunks}",
+                        amt as f64 / chunk_size as f64,
                         chunk_index + 1
                     );
 
@@ -109,21 +226,21 @@ pub async fn upload(remote_path: String, local_path: String) -> Result<()> {
                     }
 
                     debug!(
-                        "Uploaded: {amt}/{CHUNK_SIZE}={} - {}/{num_chunks}",
-                        amt as f64 / CHUNK_SIZE as f64,
+                        "Uploaded: {amt}/{chunk_size}={} - {}/{num_chunks}",
+                        amt as f64 / chunk_size as f64,
                         chunk_index + 1
                     );
                 }
             })
         })
-        .buffer_unordered(CONCURRENT_UPLOADS);
+        .buffer_unordered(concurrent_uploads);
 
     info!("Starting upload of parts for {local_path} -> {remote_path}");
 
     upload_tasks
         .for_each(|res| async move {
             if let Err(e) = res {
-                eprintln!("Error in upload task: {:?}", e);
+                error!("Error in upload task: {:?}", e);
             }
         })
         .await;
@@ -144,31 +261,219 @@ pub async fn upload(remote_path: String, local_path: String) -> Result<()> {
         .await?;
 
     info!("Upload complete for {local_path} -> {remote_path}");
+    crate::metrics::observe_multipart_upload("file", started.elapsed());
 
     Ok(())
 }
 
-pub async fn upload_str(remote_path: &str, payload: &str) -> Result<ResponseData, S3Error> {
-    BUCKET.put_object(remote_path, payload.as_bytes()).await
+/// A multipart upload being fed from the writer half returned by [`upload_stream`].
+pub struct UploadStream {
+    remote_path: String,
+    task: JoinHandle<Result<u64>>,
+    finish: oneshot::Sender<bool>,
 }
 
-pub async fn get_file_contents<'a>(path: String) -> Option<Vec<u8>> {
-    BUCKET
-        .get_object(path)
-        .await
-        .map(|x| Some(x.to_vec()))
-        .unwrap_or(None)
+impl UploadStream {
+    /// Complete the upload once everything has been written and the writer has been shut down.
+    /// Returns the number of bytes uploaded.
+    pub async fn finish(self) -> Result<u64> {
+        // If the task has already failed the receiver is gone, and awaiting it gives the real error
+        let _ = self.finish.send(true);
+        self.task.await?
+    }
+
+    /// Throw away everything uploaded so far.
+    pub async fn abort(self) {
+        let _ = self.finish.send(false);
+
+        if let Ok(Err(err)) = self.task.await {
+            log::warn!(
+                "Streaming upload to {} failed before being aborted: {err:?}",
+                self.remote_path
+            );
+        }
+    }
+}
+
+/// Start a multipart upload to `remote_path` that's fed from the returned writer, without going
+/// through local disk. Parts are uploaded as they fill, so at most
+/// `stream_chunk_size * (stream_concurrent_uploads + 1)` bytes are held in memory at once.
+///
+/// The writer must be shut down before calling [`UploadStream::finish`].
+pub fn upload_stream(remote_path: String) -> (DuplexStream, UploadStream) {
+    let (writer, reader) = io::duplex(STREAM_PIPE_SIZE);
+    let (finish_tx, finish_rx) = oneshot::channel();
+
+    let path = remote_path.clone();
+    let task =
+        tokio::spawn(async move { store().upload_from_reader(path, reader, finish_rx).await });
+
+    (
+        writer,
+        UploadStream {
+            remote_path,
+            task,
+            finish: finish_tx,
+        },
+    )
 }
 
-pub async fn get(r2_path: String, custom_filename: String) -> Result<String, S3Error> {
-    let mut custom_queries = HashMap::new();
-    custom_queries.insert(
-        "response-content-disposition".into(),
-        format!("attachment; filename=\"{custom_filename}\""),
+async fn upload_from_reader(
+    remote_path: String,
+    mut reader: DuplexStream,
+    finish: oneshot::Receiver<bool>,
+) -> Result<u64> {
+    let started = Instant::now();
+    let upload_id = BUCKET
+        .initiate_multipart_upload(&remote_path, "application/octet-stream")
+        .await?
+        .upload_id;
+
+    info!("Starting streaming upload to {remote_path}");
+
+    let result = upload_parts_from_reader(&remote_path, &upload_id, &mut reader).await;
+
+    // Stop the writer from blocking on a pipe nobody is reading anymore
+    drop(reader);
+
+    let (parts, total) = match result {
+        Ok(uploaded) => uploaded,
+        Err(err) => {
+            abort_multipart(&remote_path, &upload_id).await;
+            return Err(err);
+        }
+    };
+
+    // Only complete the upload if the writer finished the archive properly
+    if !finish.await.unwrap_or(false) {
+        abort_multipart(&remote_path, &upload_id).await;
+        return Err(format_err!("Streaming upload to {remote_path} was aborted"));
+    }
+
+    info!(
+        "Finalizing streaming upload of {} parts ({total} bytes) to {remote_path}",
+        parts.len()
     );
 
-    // Valid for 7 days
     BUCKET
-        .presign_get(r2_path, 604_800, Some(custom_queries))
-        .await
+        .complete_multipart_upload(&remote_path, &upload_id, parts)
+        .await?;
+
+    info!("Streaming upload complete for {remote_path}");
+    crate::metrics::observe_multipart_upload("stream", started.elapsed());
+
+    Ok(total)
+}
+
+async fn upload_parts_from_reader(
+    remote_path: &str,
+    upload_id: &str,
+    reader: &mut DuplexStream,
+) -> Result<(Vec<Part>, u64)> {
+    let config = &config::get().storage;
+    let (chunk_size, concurrent_uploads) =
+        (config.stream_chunk_size(), config.stream_concurrent_uploads);
+
+    let mut set = JoinSet::new();
+    let mut parts = vec![];
+    let mut total = 0;
+    let mut part_number = 0;
+
+    loop {
+        let mut chunk = Vec::with_capacity(chunk_size);
+
+        // Fill a whole part before sending it off, since all but the last must be the same size
+        while chunk.len() < chunk_size {
+            if (&mut *reader)
+                .take((chunk_size - chunk.len()) as u64)
+                .read_to_end(&mut chunk)
+                .await?
+                == 0
+            {
+                break;
+            }
+        }
+
+        let at_eof = chunk.len() < chunk_size;
+
+        if !chunk.is_empty() || part_number == 0 {
+            // Wait for a slot, which also surfaces failed parts before the whole archive is written
+            while set.len() >= concurrent_uploads {
+                if let Some(res) = set.join_next().await {
+                    parts.push(res??);
+                }
+            }
+
+            part_number += 1;
+            total += chunk.len() as u64;
+
+            let remote_path = remote_path.to_string();
+            let upload_id = upload_id.to_string();
+            let part = part_number;
+
+            debug!(
+                "Uploading streamed part {part} ({} bytes) of {remote_path}",
+                chunk.len()
+            );
+
+            set.spawn(async move {
+                BUCKET
+                    .put_multipart_chunk(
+                        chunk,
+                        &remote_path,
+                        part,
+                        &upload_id,
+                        "application/octet-stream",
+                    )
+                    .await
+            });
+        }
+
+        if at_eof {
+            break;
+        }
+    }
+
+    while let Some(res) = set.join_next().await {
+        parts.push(res??);
+    }
+    parts.sort_by_key(|part| part.part_number);
+
+    Ok((parts, total))
+}
+
+async fn abort_multipart(remote_path: &str, upload_id: &str) {
+    if let Err(err) = BUCKET.abort_upload(remote_path, upload_id).await {
+        log::error!("Failed to abort upload for {remote_path}: {:?}", err);
+    }
+}
+
+/// Stream an object from the bucket to a local file.
+pub async fn download(remote_path: &str, local_path: &str) -> Result<()> {
+    store().download(remote_path, local_path).await
+}
+
+/// Check the bucket can be reached with the configured credentials.
+pub async fn reachable() -> Result<()> {
+    let (_, status) = BUCKET
+        .list_page("health/".to_string(), None, None, None, Some(1))
+        .await?;
+
+    if status != 200 {
+        return Err(format_err!("Listing the bucket returned status {status}"));
+    }
+
+    Ok(())
+}
+
+pub async fn upload_str(remote_path: &str, payload: &str) -> Result<()> {
+    store().put(remote_path, payload.as_bytes()).await
+}
+
+pub async fn get_file_contents(path: String) -> Option<Vec<u8>> {
+    store().get(&path).await
+}
+
+pub async fn get(r2_path: String, custom_filename: String) -> Result<String> {
+    store().link(&r2_path, &custom_filename).await
 }
@@ -0,0 +1,369 @@
+//! Token bucket rate limits for the public endpoints, per client IP and per Replit account.
+//! Every limited request costs Replit a `QuickUser` fetch, so these keep anyone from making us
+//! hammer it (or flood the queue).
+//!
+//! The [`RateLimiter`] fairing does the accounting before routing, and routes opt in with the
+//! [`RateLimit`] guard, which fails with a 429 that [`too_many_requests`] turns into a response.
+//!
+//! Clients are told apart by [`Request::client_ip`], so behind the ingress Rocket's `ip_header`
+//! (`ROCKET_IP_HEADER`) has to name the header the ingress sets to the real client address,
+//! `X-Real-IP` for ingress-nginx. The ingress has to overwrite whatever the client sent in it,
+//! or anyone could pick their own bucket.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use replit_takeout::{config, metrics};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{Header, Status},
+    request::{self, FromRequest},
+    Catcher, Data, Request, Responder,
+};
+
+use crate::State;
+
+/// The paths that are limited, including everything below them.
+const LIMITED_PATHS: [&str; 3] = ["/signup", "/cancel", "/progress"];
+
+/// Buckets are only pruned once there are this many, to keep it off the hot path.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// How many requests a client can make at once, and how many a minute after that.
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    burst: f64,
+    per_minute: f64,
+}
+
+impl Limit {
+    fn refill_rate(&self) -> f64 {
+        self.per_minute / 60.0
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Take a token, or say how long until there's one to take.
+    fn take(&mut self, limit: Limit, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.refill_rate()).min(limit.burst);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if limit.refill_rate() > 0.0 {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / limit.refill_rate(),
+            ))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+
+    fn is_full(&self, limit: Limit, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * limit.refill_rate() >= limit.burst
+    }
+}
+
+struct Buckets<K> {
+    limit: Limit,
+    buckets: HashMap<K, Bucket>,
+}
+
+impl<K: std::hash::Hash + Eq> Buckets<K> {
+    fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    fn take(&mut self, key: K, now: Instant) -> Result<(), Duration> {
+        if self.buckets.len() >= PRUNE_THRESHOLD {
+            let limit = self.limit;
+            self.buckets.retain(|_, bucket| !bucket.is_full(limit, now));
+        }
+
+        let limit = self.limit;
+        self.buckets
+            .entry(key)
+            .or_insert(Bucket {
+                tokens: limit.burst,
+                updated: now,
+            })
+            .take(limit, now)
+    }
+}
+
+/// Who a request is on behalf of. Tokens that haven't been looked up yet are limited by
+/// themselves until they are, since looking them up is what's being limited.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Account {
+    User(i64),
+    Token(String),
+}
+
+/// What the fairing decided about a request.
+#[derive(Debug, Clone, Copy)]
+enum Decision {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+pub struct RateLimiter {
+    ips: Mutex<Buckets<IpAddr>>,
+    accounts: Mutex<Buckets<Account>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        let config = &config::get().rate_limit;
+        let ip_limit = Limit {
+            burst: config.ip_burst,
+            per_minute: config.ip_per_minute,
+        };
+        let account_limit = Limit {
+            burst: config.account_burst,
+            per_minute: config.account_per_minute,
+        };
+
+        Self {
+            ips: Mutex::new(Buckets::new(ip_limit)),
+            accounts: Mutex::new(Buckets::new(account_limit)),
+        }
+    }
+
+    async fn decide(&self, req: &Request<'_>) -> Decision {
+        let path = req.uri().path().as_str();
+        let route = LIMITED_PATHS
+            .iter()
+            .find(|limited| path == **limited || path.starts_with(&format!("{limited}/")));
+
+        let Some(route) = route else {
+            return Decision::Allowed;
+        };
+
+        let now = Instant::now();
+
+        if let Some(ip) = req.client_ip() {
+            if let Err(retry_after) = self.ips.lock().expect("not poisoned").take(ip, now) {
+                metrics::record_rate_limited("ip", route);
+                return Decision::Limited { retry_after };
+            }
+        }
+
+        if let Some(account) = account(req).await {
+            if let Err(retry_after) = self
+                .accounts
+                .lock()
+                .expect("not poisoned")
+                .take(account, now)
+            {
+                metrics::record_rate_limited("account", route);
+                return Decision::Limited { retry_after };
+            }
+        }
+
+        Decision::Allowed
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limiter",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let decision = self.decide(req).await;
+
+        if let Decision::Limited { retry_after } = decision {
+            info!(
+                "Rate limited {} {} from {:?}, retry after {retry_after:?}",
+                req.method(),
+                req.uri().path(),
+                req.client_ip()
+            );
+        }
+
+        req.local_cache(|| decision);
+    }
+}
+
+async fn account(req: &Request<'_>) -> Option<Account> {
+    let token = req.query_value::<String>("token")?.ok()?;
+
+    let cached = match req.rocket().state::<State>() {
+        Some(state) => state.token_to_id_cache.read().await.get(&token).copied(),
+        None => None,
+    };
+
+    Some(cached.map_or(Account::Token(token), Account::User))
+}
+
+/// Fails requests the [`RateLimiter`] turned away, before anything else about them is looked at.
+pub struct RateLimit;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimit {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match req.local_cache(|| Decision::Allowed) {
+            Decision::Allowed => request::Outcome::Success(RateLimit),
+            Decision::Limited { .. } => request::Outcome::Error((Status::TooManyRequests, ())),
+        }
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 429, content_type = "json")]
+struct TooManyRequests {
+    body: String,
+    retry_after: Header<'static>,
+}
+
+#[catch(429)]
+fn too_many_requests(req: &Request) -> TooManyRequests {
+    let retry_after = match req.local_cache(|| Decision::Allowed) {
+        Decision::Limited { retry_after } => retry_after.as_secs().max(1),
+        Decision::Allowed => 60,
+    };
+
+    TooManyRequests {
+        body: serde_json::json!({
+            "success": false,
+            "message": format!("Too many requests, try again in {retry_after} seconds"),
+        })
+        .to_string(),
+        retry_after: Header::new("Retry-After", retry_after.to_string()),
+    }
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![too_many_requests]
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::asynchronous::Client;
+
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        burst: 2.0,
+        per_minute: 60.0,
+    };
+
+    #[test]
+    fn bursts_then_runs_out() {
+        let mut buckets = Buckets::new(LIMIT);
+        let now = Instant::now();
+
+        assert!(buckets.take("a", now).is_ok());
+        assert!(buckets.take("a", now).is_ok());
+        assert_eq!(buckets.take("a", now), Err(Duration::from_secs(1)));
+
+        // Everyone has their own bucket
+        assert!(buckets.take("b", now).is_ok());
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut buckets = Buckets::new(LIMIT);
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            buckets.take("a", now).unwrap();
+        }
+
+        let later = now + Duration::from_millis(500);
+        assert_eq!(buckets.take("a", later), Err(Duration::from_millis(500)));
+
+        let later = now + Duration::from_secs(1);
+        assert!(buckets.take("a", later).is_ok());
+        assert!(buckets.take("a", later).is_err());
+
+        // Never past the burst, however long it's been
+        let much_later = now + Duration::from_secs(3600);
+        assert!(buckets.take("a", much_later).is_ok());
+        assert!(buckets.take("a", much_later).is_ok());
+        assert!(buckets.take("a", much_later).is_err());
+    }
+
+    #[test]
+    fn prunes_full_buckets() {
+        let mut buckets = Buckets::new(LIMIT);
+        let now = Instant::now();
+
+        for key in 0..PRUNE_THRESHOLD {
+            buckets.take(key, now).unwrap();
+        }
+        buckets.take(0, now).unwrap();
+
+        // Only the emptied bucket is worth keeping once the rest have refilled
+        buckets
+            .take(PRUNE_THRESHOLD, now + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(buckets.buckets.len(), 2);
+        assert!(buckets.buckets.contains_key(&0));
+    }
+
+    #[get("/signup")]
+    fn signup(_limit: RateLimit) -> &'static str {
+        "ok"
+    }
+
+    #[rocket::async_test]
+    async fn limited_requests_get_retry_after() {
+        let limit = Limit {
+            burst: 1.0,
+            per_minute: 1.0,
+        };
+        let limiter = RateLimiter {
+            ips: Mutex::new(Buckets::new(limit)),
+            accounts: Mutex::new(Buckets::new(limit)),
+        };
+        let rocket = rocket::build()
+            .mount("/", routes![signup])
+            .register("/", catchers())
+            .attach(limiter);
+        let client = Client::untracked(rocket).await.unwrap();
+        let remote = "203.0.113.7:4000".parse().unwrap();
+
+        let response = client.get("/signup").remote(remote).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client.get("/signup").remote(remote).dispatch().await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let retry_after: u64 = response
+            .headers()
+            .get_one("Retry-After")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after));
+
+        // Someone else isn't held up by it
+        let response = client
+            .get("/signup")
+            .remote("203.0.113.8:4000".parse().unwrap())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+}
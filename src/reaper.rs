@@ -0,0 +1,105 @@
+//! Finds jobs whose worker has stopped heartbeating and hands them back to the queue, or gives
+//! up on them once they've been picked up again too many times. Every replica runs one, the
+//! lease on each job makes sure it's only reaped once.
+
+use airtable_api::Record;
+use anyhow::Result;
+use log::{error, info, warn};
+
+use crate::{
+    airtable::{self, AirtableSyncedUser, ProcessState},
+    config,
+    email::emails::send_failure_email,
+    lease::Lease,
+    metrics, shutdown,
+};
+
+/// How long a running job can go without a heartbeat before it's reaped.
+fn stale_after() -> chrono::Duration {
+    chrono::Duration::from_std(config::get().worker.stale_after()).unwrap_or(chrono::Duration::MAX)
+}
+
+/// Reap stale jobs until shutdown.
+pub async fn run() {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(config::get().worker.reap_interval()) => {}
+            _ = shutdown::requested() => break,
+        }
+
+        if let Err(err) = reap().await {
+            error!("Couldn't reap stale jobs: {err:#}");
+        }
+    }
+
+    info!("Reaper stopped");
+}
+
+async fn reap() -> Result<()> {
+    let records = airtable::get_records().await?;
+
+    for record in records
+        .iter()
+        .filter(|record| record.fields.is_stale(stale_after()))
+    {
+        if shutdown::is_requested() {
+            break;
+        }
+
+        if let Err(err) = reap_job(record).await {
+            warn!("Couldn't reap job {}: {err:#}", record.id);
+        }
+    }
+
+    Ok(())
+}
+
+async fn reap_job(record: &Record<AirtableSyncedUser>) -> Result<()> {
+    let Some((lease, mut job)) = Lease::claim_if(record, |job| job.is_stale(stale_after())).await?
+    else {
+        return Ok(());
+    };
+
+    let max_attempts = config::get().worker.max_job_attempts;
+    job.fields.attempts += 1;
+    let gave_up = job.fields.attempts >= max_attempts;
+
+    if gave_up {
+        warn!(
+            "Giving up on job {} for `{}` after {} attempts",
+            job.id, job.fields.username, job.fields.attempts
+        );
+
+        job.fields.status = ProcessState::GaveUp;
+        job.fields.finished_at = Some(chrono::Utc::now());
+    } else {
+        info!(
+            "Requeueing stale job {} for `{}` ({} of {} attempts), last heartbeat {:?}",
+            job.id,
+            job.fields.username,
+            job.fields.attempts,
+            max_attempts,
+            job.fields.heartbeat_at.or(job.fields.started_at)
+        );
+
+        job.fields.status = ProcessState::Registered;
+        job.fields.started_at = None;
+        job.fields.repl_count = 0;
+        job.fields.file_count = 0;
+    }
+
+    airtable::update_records(vec![job.clone()]).await?;
+    lease.release().await;
+    metrics::record_reaped(gave_up);
+
+    if gave_up {
+        if let Err(err) = send_failure_email(&job.fields.email, &job.fields.username).await {
+            error!(
+                "Couldn't email `{}` about giving up: {err:#}",
+                job.fields.username
+            );
+        }
+    }
+
+    Ok(())
+}
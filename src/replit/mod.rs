@@ -5,9 +5,18 @@ use reqwest::{
 };
 use std::sync::Arc;
 
+use crate::config;
+
 pub mod repls;
 
-pub static REPLIT_GQL_URL: &str = "https://replit.com/graphql";
+/// `path` on Replit, or on whatever `replit.url` points at instead.
+pub fn url(path: &str) -> String {
+    format!("{}{path}", config::get().replit.url.trim_end_matches('/'))
+}
+
+pub fn graphql_url() -> String {
+    url("/graphql")
+}
 
 pub fn create_client(token: &String, client: Option<Client>) -> Result<Client, reqwest::Error> {
     if let Some(client) = client {
@@ -29,15 +38,15 @@ fn create_client_headers() -> HeaderMap {
     );
     headers.insert(
         reqwest::header::REFERER,
-        header::HeaderValue::from_static("https://replit.com/~"),
+        header::HeaderValue::from_str(&url("/~")).expect("a valid Replit URL"),
     );
 
     headers
 }
 
 fn create_client_cookie_jar(token: &String) -> Arc<Jar> {
-    let cookie = &format!("connect.sid={token}; Domain=replit.com");
-    let url = REPLIT_GQL_URL.parse::<Url>().unwrap();
+    let cookie = &format!("connect.sid={token}");
+    let url = graphql_url().parse::<Url>().unwrap();
 
     let jar = Jar::default();
     jar.add_cookie_str(cookie, &url);
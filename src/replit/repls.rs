@@ -1,12 +1,15 @@
 use std::collections::HashSet;
 
-use super::{create_client, REPLIT_GQL_URL};
+use super::{create_client, graphql_url};
+use crate::metrics;
 use anyhow::Result;
 use graphql_client::{GraphQLQuery, Response};
-use log::{debug, info, trace, warn};
+use log::{info, trace, warn};
 use reqwest::{Client, StatusCode};
 use tokio::time::{sleep, Duration};
 
+use repl_list::ReplListCurrentUserReplFolderByPathReplsItemsBytesUsed as BytesUsed;
+
 type DateTime = String;
 #[derive(GraphQLQuery)]
 #[graphql(
@@ -24,6 +27,10 @@ pub struct Repl {
     pub private: bool,
     pub url: String,
     pub time_created: String,
+    pub time_updated: String,
+
+    /// How much storage the repl takes up on Replit, when it could be looked up.
+    pub bytes_used: Option<u64>,
 }
 impl Repl {
     pub async fn fetch(token: &str, client_opt: Option<Client>) -> Result<HashSet<Repl>> {
@@ -72,7 +79,10 @@ impl Repl {
                     return Err(anyhow::anyhow!("Max retries reached for path {path}"));
                 }
 
-                let response = client.post(REPLIT_GQL_URL).json(&folder_query).send().await;
+                let response = client.post(graphql_url()).json(&folder_query).send().await;
+                if let Ok(res) = &response {
+                    metrics::record_graphql("ReplList", res.status());
+                }
 
                 match response {
                     Ok(res) if res.status() == StatusCode::TOO_MANY_REQUESTS => {
@@ -98,7 +108,7 @@ impl Repl {
             let folder_data = folder_data.text().await?;
 
             let folder: Response<repl_list::ResponseData> = serde_json::from_str(&folder_data)?;
-            log::trace!("{path}-{:#?}", folder);
+            trace!("Fetched {} bytes of folder data for {path}", folder_data.len());
 
             let folder = folder
                 .data
@@ -126,6 +136,11 @@ impl Repl {
                     private: repl.is_private,
                     url: repl.url,
                     time_created: repl.time_created,
+                    time_updated: repl.time_updated,
+                    bytes_used: match repl.bytes_used {
+                        BytesUsed::ReplBytesUsed(used) => used.value.parse().ok(),
+                        _ => None,
+                    },
                 });
 
             }
@@ -1,65 +1,41 @@
 use airtable_api::Record;
-use anyhow::Result;
+use anyhow::{format_err, Result};
 use graphql_client::{GraphQLQuery, Response};
 use log::*;
-use reqwest::{
-    cookie::Jar,
-    header::{self, HeaderMap},
-    Client, Url,
-};
-use std::sync::Arc;
-use std::time::Duration;
+use once_cell::sync::Lazy;
+use reqwest::Client;
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use time::OffsetDateTime;
-use tokio::{fs, time::sleep};
+use tokio::{
+    fs,
+    io::AsyncWriteExt,
+    sync::{broadcast, watch},
+    time::sleep,
+};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
     airtable::{self, AirtableSyncedUser, ProcessState},
-    crosisdownload::{make_zip, DownloadLocations, DownloadStatus, ReplInfo},
+    archive::{extract_archive, write_archive, ArchiveFormat},
+    cancel::CancelWatch,
+    config,
+    crosisdownload::{
+        progress::{DownloadProgress, TimeoutBudget},
+        util::dir_size,
+        DownloadLocations, DownloadStatus, ReplInfo,
+    },
     email::emails::{send_partial_success_email, send_success_email},
-    r2,
-    replit::repls::Repl,
+    git_remote::{GitPushReport, GitRemote},
+    manifest::{ExportManifest, ManifestRepl, ManifestReplStatus},
+    metrics, r2,
+    replit::{create_client, graphql_url, repls::Repl},
+    retry::RetryPolicy,
+    workspace::{DiskBudget, Workspace},
 };
 
-static REPLIT_GQL_URL: &str = "https://replit.com/graphql";
-
-fn create_client_headers() -> HeaderMap {
-    let mut headers = header::HeaderMap::new();
-    headers.insert(
-        "X-Requested-With",
-        header::HeaderValue::from_static("XMLHttpRequest"),
-    );
-    headers.insert(
-        reqwest::header::REFERER,
-        header::HeaderValue::from_static("https://replit.com/~"),
-    );
-
-    headers
-}
-
-fn create_client_cookie_jar(token: &String) -> Arc<Jar> {
-    let cookie = &format!("connect.sid={token}; Domain=replit.com");
-    let url = REPLIT_GQL_URL.parse::<Url>().unwrap();
-
-    let jar = Jar::default();
-    jar.add_cookie_str(cookie, &url);
-
-    Arc::new(jar)
-}
-
-fn create_client(token: &String, client: Option<Client>) -> Result<Client, reqwest::Error> {
-    if let Some(client) = client {
-        return Ok(client);
-    }
-
-    Client::builder()
-        .user_agent(crate::utils::random_user_agent())
-        .default_headers(create_client_headers())
-        .cookie_provider(create_client_cookie_jar(token))
-        .build()
-}
-
 #[derive(GraphQLQuery, Clone)]
 #[graphql(
     schema_path = "src/graphql/schema 7.graphql",
@@ -77,19 +53,15 @@ pub struct QuickUser {
 impl QuickUser {
     pub async fn fetch(token: &String, client_opt: Option<Client>) -> Result<Self> {
         let client = create_client(token, client_opt)?;
-        let user_data: String = client
-            .post(REPLIT_GQL_URL)
+        let response = client
+            .post(graphql_url())
             .json(&QuickUserQuery::build_query(quick_user_query::Variables {}))
             .send()
-            .await?
-            .text()
             .await?;
+        metrics::record_graphql("QuickUser", response.status());
+        let user_data = response.text().await?;
 
-        debug!(
-            "{}:{} Raw text quick user data: {user_data}",
-            std::line!(),
-            std::column!()
-        );
+        debug!("Fetched {} bytes of quick user data", user_data.len());
 
         let user_data: Response<quick_user_query::ResponseData> = serde_json::from_str(&user_data)?;
 
@@ -155,19 +127,11 @@ impl ProfileRepls {
         Option<String>,
     )> {
         let repls_query = ProfileRepls::build_query(profile_repls::Variables { user_id, after });
-        let repls_data: String = client
-            .post(REPLIT_GQL_URL)
-            .json(&repls_query)
-            .send()
-            .await?
-            .text()
-            .await?;
+        let response = client.post(graphql_url()).json(&repls_query).send().await?;
+        metrics::record_graphql("ProfileRepls", response.status());
+        let repls_data = response.text().await?;
 
-        debug!(
-            "{}:{} Raw text repl data: {repls_data}",
-            std::line!(),
-            std::column!()
-        );
+        debug!("Fetched {} bytes of profile repl data", repls_data.len());
 
         let repls_data_result: Response<profile_repls::ResponseData> =
             serde_json::from_str(&repls_data).map_err(|e| {
@@ -190,8 +154,32 @@ impl ProfileRepls {
     }
 
     pub async fn download(
+        token: &String,
+        synced_user: Record<AirtableSyncedUser>,
+        cancel: CancelWatch,
+    ) -> Result<()> {
+        let user = QuickUser {
+            id: synced_user.fields.id,
+            username: synced_user.fields.username.clone(),
+        };
+        let mut progress = ExportProgress::default();
+
+        let result = Self::export(token, synced_user, &cancel, &mut progress).await;
+
+        // Let anyone following along know there won't be any more reports
+        if result.is_err() && !cancel.lease_lost() {
+            progress.set_phase(ExportPhase::Errored);
+            progress.report(&user);
+        }
+
+        result
+    }
+
+    async fn export(
         token: &String,
         mut synced_user: Record<AirtableSyncedUser>,
+        cancel: &CancelWatch,
+        progress: &mut ExportProgress,
     ) -> Result<()> {
         synced_user.fields.status = ProcessState::CollectingRepls;
         synced_user.fields.started_at = Some(chrono::offset::Utc::now());
@@ -208,21 +196,33 @@ impl ProfileRepls {
                     "Issue with quickuser fetch - setting as expired token: {:?}",
                     err
                 );
+
+                progress.set_phase(ExportPhase::Errored);
+                progress.report(&QuickUser {
+                    id: synced_user.fields.id,
+                    username: synced_user.fields.username,
+                });
                 return Ok(());
             }
         };
 
         log::info!("current user: {:#?}", current_user);
 
-        fs::create_dir_all("repls").await?;
-        fs::create_dir(format!("repls/{}", current_user.username)).await?;
+        let workspace = Workspace::create(&synced_user.id).await?;
+        let user_dir = workspace.export_dir();
+
+        progress.report(&current_user); // Still enumerating
 
-        let repls = Repl::fetch(&token, Some(client.clone())).await?;
+        let repls = Repl::fetch(token, Some(client.clone())).await?;
         let repl_count = repls.len();
 
-        let mut progress = ExportProgress::new(repl_count);
+        progress.set_repls(&repls);
         progress.report(&current_user); // Report the user's progress.
 
+        if cancel.is_cancelled() {
+            return finish_stopped(synced_user, &current_user, progress, cancel).await;
+        }
+
         if repl_count == 0 {
             if let Err(err) = crate::email::emails::send_failed_no_repls_email(
                 &synced_user.fields.email,
@@ -238,114 +238,146 @@ impl ProfileRepls {
 
             synced_user.fields.status = ProcessState::NoRepls;
             airtable::update_records(vec![synced_user.clone()]).await?;
+            progress.set_phase(ExportPhase::Done);
+            progress.report(&current_user);
             return Ok(());
         }
 
-        let mut total_download_count = 0;
-        let mut successful_download_count = 0;
-        let mut no_history_download_count = 0;
+        // Carried over repls are extracted from the last archive, so they take up room too
+        let bytes_used = repls.iter().filter_map(|repl| repl.bytes_used).sum();
+        match DiskBudget::for_repls(bytes_used) {
+            Ok(budget) if !budget.fits() && budget.could_fit() => {
+                return defer(synced_user, &current_user, progress, budget).await;
+            }
+            Ok(budget) if !budget.fits() => warn!(
+                "{}'s repls might not fit on disk even when it's empty, going ahead anyway: {budget:?}",
+                current_user.username
+            ),
+            Ok(_) => {}
+            Err(err) => warn!("Couldn't check the disk budget: {err:#}"),
+        }
 
-        let mut errored = vec![];
-        for repl in repls {
-            let main_location = format!("repls/{}/{}/", current_user.username, repl.slug);
-            let git_location = format!("repls/{}/{}.git/", current_user.username, repl.slug);
-            let staging_git_location =
-                format!("repls/{}/{}.gitstaging/", current_user.username, repl.slug);
-            let ot_location = format!("repls/{}/{}.otbackup/", current_user.username, repl.slug);
-
-            fs::create_dir(&main_location).await?;
-            fs::create_dir(&git_location).await?;
-            fs::create_dir(&staging_git_location).await?;
-            fs::create_dir(&ot_location).await?;
-
-            let ts = OffsetDateTime::parse(
-                &repl.time_created,
-                &time::format_description::well_known::Rfc3339,
-            )?;
-
-            let download_zip = format!("repls/{}/{}.zip", current_user.username, repl.slug);
-            let download_locations = DownloadLocations {
-                main: main_location.clone(),
-                git: git_location,
-                staging_git: staging_git_location,
-                ot: ot_location,
+        let retry_ids = synced_user.fields.retry_repl_ids();
+        let mut manifest = ExportManifest::new(current_user.id, current_user.username.clone());
+
+        let mut carried_over = HashSet::new();
+        if synced_user.fields.incremental || retry_ids.is_some() {
+            // Follow-up jobs keep everything except what they're retrying,
+            // incremental exports keep whatever hasn't changed since last time
+            let keep = |prev: &ManifestRepl| match &retry_ids {
+                Some(ids) => !ids.contains(&prev.id),
+                None => repls.iter().any(|repl| {
+                    repl.id == prev.id
+                        && repl.slug == prev.slug
+                        && prev.time_updated.as_deref() == Some(repl.time_updated.as_str())
+                }),
             };
 
-            let download_job = crate::crosisdownload::download(
-                client.clone(),
-                ReplInfo {
-                    id: &repl.id,
-                    slug: &repl.slug,
-                    username: &current_user.username,
-                },
-                &download_zip,
-                download_locations.clone(),
-                ts.unix_timestamp(),
-                &synced_user.fields.email,
-            );
+            let carried = match ExportManifest::fetch_previous(current_user.id).await {
+                Some(previous) => carry_over(previous, keep, &workspace).await,
+                None => Err(format_err!("There's no previous export")),
+            };
 
-            // At 30 minutes abandon the repl download
-            match tokio::time::timeout(Duration::from_secs(60 * 30), download_job).await {
-                Err(_) => {
-                    error!(
-                        "Downloading {}::{} timed out after 30 minutes",
-                        repl.id, repl.slug
+            match carried {
+                Ok(kept) => {
+                    info!(
+                        "Carrying {} repls over from {}'s last export",
+                        kept.len(),
+                        current_user.username
                     );
-                    errored.push(repl.id);
-                    progress.failed.timed_out += 1;
+
+                    for manifest_repl in kept {
+                        carried_over.insert(manifest_repl.id.clone());
+                        progress.carried_over += 1;
+                        if let Some(entry) = progress.repl_mut(&manifest_repl.id) {
+                            entry.state = ReplProgressState::CarriedOver;
+                            entry.file_count = manifest_repl.file_count;
+                        }
+                        manifest.repls.push(manifest_repl);
+                    }
                 }
-                Ok(Err(err)) => {
+                Err(err) if retry_ids.is_some() => {
+                    // Without the old archive there's nothing to patch the retried repls into
                     error!(
-                        "Downloading {}::{} failed with error: {err:#?}",
-                        repl.id, repl.slug
+                        "Couldn't load {}'s last export for a follow-up: {err:#}",
+                        current_user.username
                     );
-                    errored.push(repl.id);
-                    progress.failed.failed += 1;
+
+                    synced_user.fields.status = ProcessState::Errored;
+                    synced_user.fields.finished_at = Some(chrono::offset::Utc::now());
+                    airtable::update_records(vec![synced_user]).await?;
+                    progress.set_phase(ExportPhase::Done);
+                    progress.report(&current_user);
+                    return Ok(());
                 }
-                Ok(Ok((DownloadStatus::NoHistory, file_count))) => {
-                    info!(
-                        "Downloaded {}::{} (without history) to {}",
-                        repl.id, repl.slug, download_zip
+                Err(err) => {
+                    warn!(
+                        "Couldn't carry over {}'s last export, doing a full one instead: {err:#}",
+                        current_user.username
                     );
-                    synced_user.fields.file_count += file_count;
-                    no_history_download_count += 1;
-                    progress.failed.no_history += 1;
 
-                    if let Err(err) = fs::remove_dir_all(download_locations.git).await {
-                        warn!(
-                            "Error removing git temp dir for {}::{}: {err}",
-                            repl.id, repl.slug
-                        )
-                    }
+                    // Anything half-extracted would clash with the fresh downloads
+                    fs::remove_dir_all(&user_dir).await?;
+                    fs::create_dir(&user_dir).await?;
+                }
+            }
 
-                    if let Err(err) = fs::remove_dir_all(download_locations.main).await {
-                        warn!(
-                            "Error removing main temp dir for {}::{}: {err}",
-                            repl.id, repl.slug
-                        )
-                    }
+            progress.report(&current_user);
+        }
 
-                    if let Err(err) = fs::remove_dir_all(download_locations.ot).await {
-                        warn!(
-                            "Error removing ot temp dir for {}::{}: {err}",
-                            repl.id, repl.slug
-                        )
-                    }
+        let git_remote = synced_user.fields.git_remote().unwrap_or_else(|err| {
+            warn!(
+                "Couldn't decrypt {}'s git remote password, not pushing: {err:#}",
+                current_user.username
+            );
+            None
+        });
 
-                    if let Err(err) = fs::remove_dir_all(download_locations.staging_git).await {
-                        warn!(
-                            "Error removing git staging temp dir for {}::{}: {err}",
-                            repl.id, repl.slug
-                        )
-                    }
-                }
-                Ok(Ok((DownloadStatus::Full, file_count))) => {
-                    info!("Downloaded {}::{} to {}", repl.id, repl.slug, main_location);
-                    synced_user.fields.file_count += file_count;
-                    successful_download_count += 1;
-                    progress.successful += 1;
+        let downloader = ReplDownloader {
+            client: client.clone(),
+            user: &current_user,
+            dir: &user_dir,
+            email: synced_user.fields.email.clone(),
+            git_remote,
+            cancel,
+        };
+
+        let mut total_download_count = 0;
+        let mut successful_download_count = 0;
+        let mut no_history_download_count = 0;
+
+        progress.set_phase(ExportPhase::Downloading);
+        progress.report(&current_user);
+
+        let mut errored = vec![];
+        for repl in &repls {
+            if cancel.is_cancelled() {
+                return finish_stopped(synced_user, &current_user, progress, cancel).await;
+            }
+
+            if carried_over.contains(&repl.id) {
+                continue;
+            }
+
+            progress.start_repl(&repl.id);
+            progress.report(&current_user);
+
+            let outcome = downloader.download(repl).await;
+
+            if cancel.is_cancelled() {
+                return finish_stopped(synced_user, &current_user, progress, cancel).await;
+            }
+
+            match outcome.manifest_repl.status {
+                ManifestReplStatus::Full => successful_download_count += 1,
+                ManifestReplStatus::NoHistory => no_history_download_count += 1,
+                ManifestReplStatus::Failed | ManifestReplStatus::TimedOut => {
+                    errored.push(repl.id.clone())
                 }
             }
+            synced_user.fields.file_count += outcome.manifest_repl.file_count;
+            progress.record_outcome(&outcome);
+            manifest.repls.push(outcome.manifest_repl);
 
             total_download_count += 1;
 
@@ -358,24 +390,138 @@ impl ProfileRepls {
             progress.report(&current_user);
         }
 
+        // Give the failed repls a few more goes, backing off a little more each time
+        let retry = RetryPolicy::from_config();
+        for attempt in 1..=retry.attempts {
+            if errored.is_empty() {
+                break;
+            }
+
+            let backoff = retry.backoff_for(attempt);
+            info!(
+                "Retrying {} failed repls for {} in {backoff:?} (attempt {attempt}/{})",
+                errored.len(),
+                current_user.username,
+                retry.attempts
+            );
+
+            progress.set_phase(ExportPhase::Retrying);
+            progress.report(&current_user);
+
+            tokio::select! {
+                _ = sleep(backoff) => {}
+                _ = cancel.cancelled() => {
+                    return finish_stopped(synced_user, &current_user, progress, cancel).await;
+                }
+            }
+
+            for id in errored.clone() {
+                let Some(repl) = repls.iter().find(|repl| repl.id == id) else {
+                    continue;
+                };
+                let Some(index) = manifest.repls.iter().position(|entry| entry.id == id) else {
+                    continue;
+                };
+
+                downloader.clean(repl).await;
+                progress.start_repl(&repl.id);
+                progress.report(&current_user);
+
+                let outcome = downloader.download(repl).await;
+
+                if cancel.is_cancelled() {
+                    return finish_stopped(synced_user, &current_user, progress, cancel).await;
+                }
+
+                progress.forget_outcome(manifest.repls[index].status);
+                progress.record_outcome(&outcome);
+                progress.retried += 1;
+
+                match outcome.manifest_repl.status {
+                    ManifestReplStatus::Full => successful_download_count += 1,
+                    ManifestReplStatus::NoHistory => no_history_download_count += 1,
+                    ManifestReplStatus::Failed | ManifestReplStatus::TimedOut => {}
+                }
+                if outcome.succeeded() {
+                    info!("Retrying {}::{} worked", repl.id, repl.slug);
+                    errored.retain(|errored_id| *errored_id != id);
+                    synced_user.fields.file_count += outcome.manifest_repl.file_count;
+                }
+
+                manifest.repls[index] = outcome.manifest_repl;
+                progress.report(&current_user);
+            }
+
+            airtable::update_records(vec![synced_user.clone()]).await?;
+        }
+
+        // A follow-up that recovered nothing leaves the archive the user already has alone
+        if retry_ids.is_some() && successful_download_count + no_history_download_count == 0 {
+            info!(
+                "Follow-up for {} didn't recover any repls, keeping their existing archive",
+                current_user.username
+            );
+
+            synced_user.fields.status = ProcessState::Errored;
+            synced_user.fields.failed_ids = errored.join(",");
+            synced_user.fields.finished_at = Some(chrono::offset::Utc::now());
+            airtable::update_records(vec![synced_user.clone()]).await?;
+
+            queue_follow_up(&synced_user.fields, &errored, &retry).await;
+
+            progress.set_phase(ExportPhase::Done);
+            progress.report(&current_user);
+            return Ok(());
+        }
+
+        if cancel.is_cancelled() {
+            return finish_stopped(synced_user, &current_user, progress, cancel).await;
+        }
+
         progress.completed = true;
+        progress.set_phase(ExportPhase::Zipping);
         progress.report(&current_user);
         airtable::update_records(vec![synced_user.clone()]).await?;
 
-        let path = format!("repls/{}", current_user.username);
-        make_zip(path.clone(), format!("repls/{}.zip", current_user.username)).await?;
-        fs::remove_dir_all(&path).await?;
+        let archive_format = synced_user.fields.archive_format.unwrap_or_default();
+        let archive_name = format!("{}.{}", current_user.username, archive_format.extension());
+
+        let path = user_dir; // Local
+        let upload_path = format!("export/{archive_name}"); // Remote
+        manifest.archive = Some(upload_path.clone());
+        manifest.archive_format = archive_format;
+        manifest.write(&path).await?;
+
+        // The archive goes straight into the multipart upload, so it never sits on local disk.
+        // Dropping it part way through abandons the upload.
+        let upload_result = tokio::select! {
+            res = stream_archive(
+                path.clone(),
+                upload_path.clone(),
+                archive_format,
+                progress,
+                &current_user,
+            ) => res,
+            _ = cancel.cancelled() => {
+                return finish_stopped(synced_user, &current_user, progress, cancel).await;
+            }
+        };
+        drop(workspace);
+
+        // Under a double claim (see `airtable::claim`) both workers get this far, uploading
+        // the same repls to the same path. Only the one still holding the lease records the
+        // export, so the user gets one email and at most one follow-up.
+        if !cancel.lease_held(&synced_user.id).await? {
+            warn!(
+                "Another worker took over {}'s export, leaving the email to it",
+                current_user.username
+            );
 
-        info!(
-            "User repls have been zipped into repls/{}.zip",
-            current_user.username
-        );
+            return Ok(());
+        }
 
-        let zip_path = format!("repls/{}.zip", current_user.username); // Local
-        let upload_path = format!("export/{}.zip", current_user.username); // Remote
+        info!("User repls have been archived and streamed to {upload_path}");
 
-        let upload_result = r2::upload(upload_path.clone(), zip_path.clone()).await;
-        fs::remove_file(&zip_path).await?;
         synced_user.fields.status = ProcessState::WaitingInR2;
         airtable::update_records(vec![synced_user.clone()]).await?;
 
@@ -386,12 +532,23 @@ impl ProfileRepls {
             return Err(upload_err);
         }
 
-        let link = r2::get(upload_path, format!("{}.zip", current_user.username)).await?;
+        // Only now that the new archive is in place can the next export build on it
+        if let Err(err) = manifest.upload().await {
+            warn!(
+                "Couldn't store {}'s export manifest: {err:#}",
+                current_user.username
+            );
+        }
+
+        let link = r2::get(upload_path, archive_name).await?;
 
         synced_user.fields.r2_link = link.clone();
 
+        progress.set_phase(ExportPhase::Emailing);
+        progress.report(&current_user);
+
         // Hey, if even one repl was downloaded let's give it to them.
-        if progress.successful + progress.failed.no_history > 0 {
+        if progress.successful + progress.failed.no_history + progress.carried_over > 0 {
             let full_success = progress.failed.failed + progress.failed.timed_out == 0;
 
             let email_result = if full_success {
@@ -445,12 +602,465 @@ impl ProfileRepls {
             synced_user.fields.failed_ids = errored.join(",");
         }
         synced_user.fields.finished_at = Some(chrono::offset::Utc::now());
-        airtable::update_records(vec![synced_user]).await?;
+        airtable::update_records(vec![synced_user.clone()]).await?;
+
+        // Only worth following up on if there's an archive to patch the repls into
+        if !errored.is_empty() && synced_user.fields.status == ProcessState::R2LinkEmailSent {
+            queue_follow_up(&synced_user.fields, &errored, &retry).await;
+        }
+
+        progress.set_phase(ExportPhase::Done);
+        progress.report(&current_user);
 
         Ok(())
     }
 }
 
+/// Extract the repls from the user's last export that pass `keep` out of its archive
+/// and into the workspace, returning their entries from the old manifest.
+async fn carry_over<F: Fn(&ManifestRepl) -> bool>(
+    previous: ExportManifest,
+    keep: F,
+    workspace: &Workspace,
+) -> Result<Vec<ManifestRepl>> {
+    let Some(previous_archive) = previous.archive else {
+        return Err(format_err!("The previous export has no archive"));
+    };
+
+    let kept: Vec<ManifestRepl> = previous
+        .repls
+        .into_iter()
+        .filter(|prev| {
+            matches!(
+                prev.status,
+                ManifestReplStatus::Full | ManifestReplStatus::NoHistory
+            ) && keep(prev)
+        })
+        .collect();
+
+    if kept.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let local_archive =
+        workspace.scratch_file(&format!("previous.{}", previous.archive_format.extension()));
+    r2::download(&previous_archive, &local_archive).await?;
+
+    let extracted = extract_archive(
+        &local_archive,
+        &workspace.export_dir(),
+        previous.archive_format,
+        |entry| kept.iter().any(|repl| repl.owns(entry)),
+    )
+    .await;
+
+    if let Err(err) = fs::remove_file(&local_archive).await {
+        warn!("Error removing the previous archive {local_archive}: {err}");
+    }
+
+    info!("Extracted {} files from {previous_archive}", extracted?);
+
+    Ok(kept)
+}
+
+/// Queue another job for the repls that still failed, to patch into the user's archive later.
+async fn queue_follow_up(user: &AirtableSyncedUser, failed: &[String], retry: &RetryPolicy) {
+    if user.follow_up_attempt >= retry.follow_ups {
+        info!(
+            "Not following up on {}'s {} failed repls, they've had {} follow-ups already",
+            user.username,
+            failed.len(),
+            user.follow_up_attempt
+        );
+        return;
+    }
+
+    let not_before = chrono::offset::Utc::now()
+        + chrono::Duration::from_std(retry.follow_up_delay).unwrap_or_default();
+    let follow_up = follow_up_job(user, failed, not_before);
+
+    if airtable::add_user(follow_up).await {
+        info!(
+            "Queued a follow-up for {}'s {} failed repls at {not_before}",
+            user.username,
+            failed.len()
+        );
+    } else {
+        error!("Couldn't queue a follow-up for {}", user.username);
+    }
+}
+
+/// The job to try `failed` again after `user`'s.
+fn follow_up_job(
+    user: &AirtableSyncedUser,
+    failed: &[String],
+    not_before: chrono::DateTime<chrono::Utc>,
+) -> AirtableSyncedUser {
+    // A new job, so nothing about how this one ran carries over. The token and git remote do,
+    // still encrypted, since the follow-up downloads and pushes the repls again. They're wiped
+    // when it finishes like any other job's.
+    AirtableSyncedUser {
+        id: user.id,
+        username: user.username.clone(),
+        token: user.token.clone(),
+        email: user.email.clone(),
+        status: ProcessState::Registered,
+        r2_link: airtable::NO_R2_LINK.to_string(),
+        failed_ids: String::from("none"),
+        statistics: user.statistics.clone(),
+        git_remote: user.git_remote.clone(),
+        git_remote_username: user.git_remote_username.clone(),
+        git_remote_password: user.git_remote_password.clone(),
+        archive_format: user.archive_format,
+        incremental: false,
+        retry_repl_ids: Some(failed.join(",")),
+        follow_up_attempt: user.follow_up_attempt + 1,
+        not_before: Some(not_before),
+        attempts: 0,
+        ..Default::default()
+    }
+}
+
+/// `repl`'s manifest entry, until it's downloaded.
+fn failed_manifest_repl(repl: &Repl) -> ManifestRepl {
+    ManifestRepl {
+        id: repl.id.clone(),
+        slug: repl.slug.clone(),
+        title: repl.title.clone(),
+        status: ManifestReplStatus::Failed,
+        file_count: 0,
+        time_updated: Some(repl.time_updated.clone()),
+        git_push: None,
+    }
+}
+
+/// Downloads single repls into the user's export directory.
+struct ReplDownloader<'a> {
+    client: Client,
+    user: &'a QuickUser,
+    dir: &'a str,
+    email: String,
+    git_remote: Option<GitRemote>,
+    cancel: &'a CancelWatch,
+}
+
+/// What came of trying to download a repl.
+struct ReplOutcome {
+    manifest_repl: ManifestRepl,
+
+    /// The size on disk of what was downloaded.
+    bytes: u64,
+
+    failure: Option<String>,
+}
+
+impl ReplOutcome {
+    fn succeeded(&self) -> bool {
+        matches!(
+            self.manifest_repl.status,
+            ManifestReplStatus::Full | ManifestReplStatus::NoHistory
+        )
+    }
+}
+
+impl ReplDownloader<'_> {
+    fn locations(&self, repl: &Repl) -> (DownloadLocations, String) {
+        let dir = self.dir;
+
+        let locations = DownloadLocations {
+            main: format!("{dir}/{}/", repl.slug),
+            git: format!("{dir}/{}.git/", repl.slug),
+            staging_git: format!("{dir}/{}.gitstaging/", repl.slug),
+            ot: format!("{dir}/{}.otbackup/", repl.slug),
+        };
+        let download_zip = format!("{dir}/{}.zip", repl.slug);
+
+        (locations, download_zip)
+    }
+
+    #[tracing::instrument(name = "repl", skip_all, fields(repl_id = %repl.id, slug = %repl.slug))]
+    /// Download `repl`. Anything going wrong fails just this repl, not the whole export.
+    async fn download(&self, repl: &Repl) -> ReplOutcome {
+        let started = Instant::now();
+
+        match self.try_download(repl, started).await {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                error!("Couldn't download {}::{}: {err:#}", repl.id, repl.slug);
+
+                let manifest_repl = failed_manifest_repl(repl);
+                metrics::record_repl(manifest_repl.status, Some(&err), started.elapsed(), 0);
+
+                ReplOutcome {
+                    manifest_repl,
+                    bytes: 0,
+                    failure: Some(format!("{err:#}")),
+                }
+            }
+        }
+    }
+
+    async fn try_download(&self, repl: &Repl, started: Instant) -> Result<ReplOutcome> {
+        let (download_locations, download_zip) = self.locations(repl);
+        let main_location = download_locations.main.clone();
+
+        fs::create_dir(&download_locations.main).await?;
+        fs::create_dir(&download_locations.git).await?;
+        fs::create_dir(&download_locations.staging_git).await?;
+        fs::create_dir(&download_locations.ot).await?;
+
+        let ts = OffsetDateTime::parse(
+            &repl.time_created,
+            &time::format_description::well_known::Rfc3339,
+        )?;
+
+        let progress = Arc::new(DownloadProgress::default());
+        let budget = TimeoutBudget::new(repl.bytes_used);
+
+        let download_job = crate::crosisdownload::download(
+            self.client.clone(),
+            ReplInfo {
+                id: &repl.id,
+                slug: &repl.slug,
+                username: &self.user.username,
+            },
+            &download_zip,
+            download_locations.clone(),
+            ts.unix_timestamp(),
+            &self.email,
+            progress.clone(),
+            self.cancel,
+        );
+
+        let mut manifest_repl = failed_manifest_repl(repl);
+
+        let mut failure = None;
+        let mut error = None;
+
+        // Abandon the download once it's used up its budget and stopped making progress
+        let result = tokio::select! {
+            res = download_job => Ok(res),
+            expiry = budget.watch(&progress) => Err(expiry),
+        };
+
+        match result {
+            Err(expiry) => {
+                error!(
+                    "Downloading {}::{} timed out after {:?}: {expiry}",
+                    repl.id,
+                    repl.slug,
+                    started.elapsed()
+                );
+                manifest_repl.status = ManifestReplStatus::TimedOut;
+                failure = Some(expiry.to_string());
+            }
+            Ok(Err(err)) => {
+                error!(
+                    "Downloading {}::{} failed with error: {err:#?}",
+                    repl.id, repl.slug
+                );
+                failure = Some(format!("{err:#}"));
+                error = Some(err);
+            }
+            Ok(Ok((DownloadStatus::NoHistory, file_count))) => {
+                info!(
+                    "Downloaded {}::{} (without history) to {}",
+                    repl.id, repl.slug, download_zip
+                );
+                manifest_repl.status = ManifestReplStatus::NoHistory;
+                manifest_repl.file_count = file_count;
+
+                self.remove_dirs(repl, download_locations).await;
+            }
+            Ok(Ok((DownloadStatus::Full, file_count))) => {
+                info!("Downloaded {}::{} to {}", repl.id, repl.slug, main_location);
+                manifest_repl.status = ManifestReplStatus::Full;
+                manifest_repl.file_count = file_count;
+
+                if let Some(remote) = &self.git_remote {
+                    let report = remote
+                        .push(
+                            main_location.clone(),
+                            &self.user.username,
+                            &repl.slug,
+                            &repl.id,
+                        )
+                        .await;
+
+                    if let Some(err) = &report.error {
+                        warn!(
+                            "Pushing {}::{} to {} failed: {err}",
+                            repl.id, repl.slug, report.remote
+                        );
+                    } else {
+                        info!("Pushed {}::{} to {}", repl.id, repl.slug, report.remote);
+                    }
+
+                    manifest_repl.git_push = Some(report);
+                }
+            }
+        }
+
+        metrics::record_repl(
+            manifest_repl.status,
+            error.as_ref(),
+            started.elapsed(),
+            manifest_repl.file_count,
+        );
+
+        let bytes = match manifest_repl.status {
+            ManifestReplStatus::Full => dir_size(main_location).await.unwrap_or_default(),
+            ManifestReplStatus::NoHistory => fs::metadata(&download_zip)
+                .await
+                .map(|metadata| metadata.len())
+                .unwrap_or_default(),
+            ManifestReplStatus::Failed | ManifestReplStatus::TimedOut => 0,
+        };
+
+        Ok(ReplOutcome {
+            manifest_repl,
+            bytes,
+            failure,
+        })
+    }
+
+    /// Remove whatever a failed attempt left behind, so the repl can be downloaded again.
+    async fn clean(&self, repl: &Repl) {
+        let (download_locations, download_zip) = self.locations(repl);
+
+        if let Err(err) = fs::remove_file(&download_zip).await {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!("Error removing {download_zip}: {err}");
+            }
+        }
+
+        self.remove_dirs(repl, download_locations).await;
+    }
+
+    async fn remove_dirs(&self, repl: &Repl, download_locations: DownloadLocations) {
+        for (kind, dir) in [
+            ("git", download_locations.git),
+            ("main", download_locations.main),
+            ("ot", download_locations.ot),
+            ("git staging", download_locations.staging_git),
+        ] {
+            if let Err(err) = fs::remove_dir_all(dir).await {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    warn!(
+                        "Error removing {kind} temp dir for {}::{}: {err}",
+                        repl.id, repl.slug
+                    )
+                }
+            }
+        }
+    }
+}
+
+/// Clean up after an export was stopped part way. Nothing gets uploaded or emailed.
+///
+/// If the user cancelled it that's the end of it, but if the worker is shutting down the job
+/// goes back in the queue to start over on another worker.
+async fn finish_stopped(
+    mut synced_user: Record<AirtableSyncedUser>,
+    user: &QuickUser,
+    progress: &mut ExportProgress,
+    cancel: &CancelWatch,
+) -> Result<()> {
+    if cancel.lease_lost() {
+        // The job's record and progress belong to the worker that has it now
+        warn!(
+            "Stopping {}'s export, another worker took it over",
+            user.username
+        );
+
+        return Ok(());
+    }
+
+    if cancel.by_user() {
+        info!("Stopping {}'s export, they cancelled it", user.username);
+
+        synced_user.fields.status = ProcessState::Cancelled;
+        synced_user.fields.finished_at = Some(chrono::offset::Utc::now());
+        progress.set_phase(ExportPhase::Cancelled);
+    } else {
+        info!(
+            "Stopping {}'s export, the worker is shutting down",
+            user.username
+        );
+
+        synced_user.fields.status = ProcessState::Registered;
+        synced_user.fields.started_at = None;
+        synced_user.fields.repl_count = 0;
+        synced_user.fields.file_count = 0;
+        progress.set_phase(ExportPhase::Requeued);
+    }
+
+    airtable::update_records(vec![synced_user]).await?;
+    progress.report(user);
+
+    Ok(())
+}
+
+/// Put a job back in the queue until there might be room on disk for it.
+async fn defer(
+    mut synced_user: Record<AirtableSyncedUser>,
+    user: &QuickUser,
+    progress: &mut ExportProgress,
+    budget: DiskBudget,
+) -> Result<()> {
+    let not_before = chrono::offset::Utc::now()
+        + chrono::Duration::from_std(config::get().download.disk_defer()).unwrap_or_default();
+
+    info!(
+        "Deferring {}'s export until {not_before}, it needs {} bytes of disk but only {} are free",
+        user.username, budget.needed, budget.available
+    );
+
+    synced_user.fields.status = ProcessState::Registered;
+    synced_user.fields.started_at = None;
+    synced_user.fields.not_before = Some(not_before);
+    progress.set_phase(ExportPhase::Deferred);
+
+    airtable::update_records(vec![synced_user]).await?;
+    progress.report(user);
+
+    Ok(())
+}
+
+/// Archive `dir` directly into a multipart upload at `upload_path`.
+async fn stream_archive(
+    dir: String,
+    upload_path: String,
+    format: ArchiveFormat,
+    progress: &mut ExportProgress,
+    user: &QuickUser,
+) -> Result<()> {
+    let (writer, upload) = r2::upload_stream(upload_path);
+
+    let written = match write_archive(dir, writer, format).await {
+        Ok(mut writer) => writer.shutdown().await,
+        Err(err) => {
+            upload.abort().await;
+            return Err(err);
+        }
+    };
+
+    if let Err(err) = written {
+        upload.abort().await;
+        return Err(err.into());
+    }
+
+    // Everything's archived, only the last parts are still in flight
+    progress.set_phase(ExportPhase::Uploading);
+    progress.report(user);
+
+    let size = upload.finish().await?;
+    info!("Streamed a {size} byte archive");
+    metrics::observe_archive_bytes(size);
+
+    Ok(())
+}
+
 #[derive(GraphQLQuery)]
 #[graphql(
     schema_path = "src/graphql/schema 7.graphql",
@@ -476,21 +1086,13 @@ impl ReplList {
         let repls_query = ReplList::build_query(repl_list::Variables {
             path: "".into(),
             starred: None,
-            after: None,
+            after,
         });
 
-        let repls_data: String = client
-            .post(REPLIT_GQL_URL)
-            .json(&repls_query)
-            .send()
-            .await?
-            .text()
-            .await?;
-        trace!(
-            "{}:{} Raw text repl data: {repls_data}",
-            std::line!(),
-            std::column!()
-        );
+        let response = client.post(graphql_url()).json(&repls_query).send().await?;
+        metrics::record_graphql("ReplList", response.status());
+        let repls_data = response.text().await?;
+        trace!("Fetched {} bytes of repl data", repls_data.len());
 
         let repls_data_result =
             match serde_json::from_str::<Response<repl_list::ResponseData>>(&repls_data) {
@@ -501,13 +1103,11 @@ impl ReplList {
                 }
             };
 
-        debug!("repls data result: {:#?}", repls_data_result);
         if let Some(curr) = repls_data_result
-            .map(|r| r.current_user.map(|r| r.repl_folder_by_path))
-            .flatten()
+            .and_then(|r| r.current_user.map(|r| r.repl_folder_by_path))
             .flatten()
         {
-            info!("curr repls: {:#?}", curr.repls);
+            info!("Fetched {} repls", curr.repls.items.len());
         } else {
             log::error!("No repls data!")
         }
@@ -540,41 +1140,231 @@ impl ReplList {
 )]
 pub struct ReplsDashboardReplFolderList;
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+/// Every progress report made in this process, for live streaming.
+static PROGRESS_UPDATES: Lazy<broadcast::Sender<(i64, ExportProgress)>> =
+    Lazy::new(|| broadcast::channel(256).0);
+
+/// Listen for progress reports (keyed by user id) as they're made.
+/// Reports from exports running in other processes only show up via [`ExportProgress::fetch`].
+pub fn subscribe_progress() -> broadcast::Receiver<(i64, ExportProgress)> {
+    PROGRESS_UPDATES.subscribe()
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct ExportProgress {
     completed: bool,
 
+    /// Increases with every report, so clients can tell newer reports from ones they've seen.
+    #[serde(default)]
+    seq: i64,
+
+    #[serde(default)]
+    phase: ExportPhase,
+
+    /// When the downloads are expected to be done, going by how long each repl has taken so far.
+    #[serde(default)]
+    eta: Option<chrono::DateTime<chrono::Utc>>,
+
     /// The total number of repls the user has.
     repl_count: usize,
     successful: usize,
     failed: ExportProgressFailures,
+
+    /// Per-repl results of pushing to the user's git remote, if they asked for one.
+    #[serde(default)]
+    git_pushes: Vec<GitPushReport>,
+
+    /// Repls left untouched since the user's last export, copied over from its archive.
+    #[serde(default)]
+    carried_over: usize,
+
+    /// How many downloads were attempted again after failing.
+    #[serde(default)]
+    retried: usize,
+
+    #[serde(default)]
+    repls: Vec<ReplProgress>,
 }
 
 impl ExportProgress {
-    fn new(repl_count: usize) -> Self {
-        Self {
-            repl_count,
-            ..Default::default()
+    fn set_repls(&mut self, repls: &HashSet<Repl>) {
+        self.repl_count = repls.len();
+        self.repls = repls.iter().map(ReplProgress::queued).collect();
+    }
+
+    /// The last progress report stored for a user.
+    pub async fn fetch(user_id: i64) -> Option<Self> {
+        let bytes = r2::get_file_contents(format!("progress/{user_id}")).await?;
+        serde_json::from_slice(&bytes).ok()
+    }
+
+    pub fn seq(&self) -> i64 {
+        self.seq
+    }
+
+    /// Whether the export is over and there'll be no more reports.
+    pub fn finished(&self) -> bool {
+        matches!(
+            self.phase,
+            ExportPhase::Done | ExportPhase::Cancelled | ExportPhase::Errored
+        )
+    }
+
+    fn set_phase(&mut self, phase: ExportPhase) {
+        self.phase = phase;
+
+        if phase != ExportPhase::Downloading {
+            self.eta = None;
         }
     }
 
-    fn report(&self, user: &QuickUser) {
-        let task_usr = user.clone();
+    fn repl_mut(&mut self, id: &str) -> Option<&mut ReplProgress> {
+        self.repls.iter_mut().find(|repl| repl.id == id)
+    }
+
+    fn start_repl(&mut self, id: &str) {
+        if let Some(repl) = self.repl_mut(id) {
+            repl.state = ReplProgressState::Downloading;
+            repl.started_at = Some(chrono::offset::Utc::now());
+        }
+    }
+
+    /// The count a repl with this outcome adds to.
+    fn counter(&mut self, status: ManifestReplStatus) -> &mut usize {
+        match status {
+            ManifestReplStatus::Full => &mut self.successful,
+            ManifestReplStatus::NoHistory => &mut self.failed.no_history,
+            ManifestReplStatus::Failed => &mut self.failed.failed,
+            ManifestReplStatus::TimedOut => &mut self.failed.timed_out,
+        }
+    }
+
+    fn record_outcome(&mut self, outcome: &ReplOutcome) {
+        let repl = &outcome.manifest_repl;
+
+        *self.counter(repl.status) += 1;
+        if let Some(report) = &repl.git_push {
+            self.git_pushes.push(report.clone());
+        }
+
+        self.finish_repl(
+            &repl.id,
+            repl.status.into(),
+            repl.file_count,
+            outcome.bytes,
+            outcome.failure.clone(),
+        );
+    }
+
+    /// Undo counting an earlier attempt at a repl that's being retried.
+    fn forget_outcome(&mut self, status: ManifestReplStatus) {
+        let counter = self.counter(status);
+        *counter = counter.saturating_sub(1);
+    }
+
+    fn finish_repl(
+        &mut self,
+        id: &str,
+        state: ReplProgressState,
+        file_count: usize,
+        bytes: u64,
+        failure: Option<String>,
+    ) {
+        if let Some(repl) = self.repl_mut(id) {
+            repl.state = state;
+            repl.finished_at = Some(chrono::offset::Utc::now());
+            repl.file_count = file_count;
+            repl.bytes = bytes;
+            repl.failure = failure;
+        }
+
+        self.update_eta();
+    }
+
+    /// Extrapolate from the average time the finished repls took.
+    fn update_eta(&mut self) {
+        let durations: Vec<i64> = self
+            .repls
+            .iter()
+            .filter_map(|repl| Some((repl.finished_at? - repl.started_at?).num_milliseconds()))
+            .collect();
+
+        let remaining = self
+            .repls
+            .iter()
+            .filter(|repl| {
+                matches!(
+                    repl.state,
+                    ReplProgressState::Queued | ReplProgressState::Downloading
+                )
+            })
+            .count() as i64;
+
+        if durations.is_empty() {
+            self.eta = None;
+            return;
+        }
+
+        let average = durations.iter().sum::<i64>() / durations.len() as i64;
+        self.eta =
+            Some(chrono::offset::Utc::now() + chrono::Duration::milliseconds(average * remaining));
+    }
+
+    fn report(&mut self, user: &QuickUser) {
+        // Timestamp based so it keeps increasing if the export is picked up again by another worker
+        self.seq = (self.seq + 1).max(chrono::offset::Utc::now().timestamp_millis());
+
+        // Nobody listening is fine
+        let _ = PROGRESS_UPDATES.send((user.id, self.clone()));
+
         let progress = serde_json::to_string(self).expect("a serialised progress string");
 
-        tokio::spawn(async move {
-            if let Err(err) = r2::upload_str(&format!("progress/{}", task_usr.id), &progress).await
-            {
+        let mut writers = PROGRESS_WRITERS.lock().expect("not poisoned");
+        match writers.entry(user.id) {
+            Entry::Occupied(writer) => {
+                writer.get().send_replace(progress);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(spawn_progress_writer(user.clone(), progress));
+            }
+        }
+
+        // The writer still uploads the last report after it's dropped
+        if self.finished() {
+            writers.remove(&user.id);
+        }
+    }
+}
+
+/// The latest report for each export, uploaded by one task per user so they land in order.
+static PROGRESS_WRITERS: Lazy<Mutex<HashMap<i64, watch::Sender<String>>>> =
+    Lazy::new(Default::default);
+
+/// Upload `progress` and then every newer report, skipping any that are replaced before they're
+/// written.
+fn spawn_progress_writer(user: QuickUser, progress: String) -> watch::Sender<String> {
+    let (tx, mut rx) = watch::channel(progress);
+
+    tokio::spawn(async move {
+        loop {
+            let progress = rx.borrow_and_update().clone();
+            if let Err(err) = r2::upload_str(&format!("progress/{}", user.id), &progress).await {
                 error!(
                     "Couldn't upload {}'s progress report ({progress}) to R2: {:?}",
-                    task_usr.username, err
+                    user.username, err
                 );
             }
-        });
-    }
+
+            if rx.changed().await.is_err() {
+                break;
+            }
+        }
+    });
+
+    tx
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 struct ExportProgressFailures {
     /// The number of repls that have failed to download due to hitting the download timeout threshold.
     timed_out: usize,
@@ -585,8 +1375,132 @@ struct ExportProgressFailures {
     /// The number of repls that have failed to download history, but a zip was successfully downloaded
     no_history: usize,
 }
-impl ExportProgressFailures {
-    fn total(&self) -> usize {
-        self.timed_out + self.failed + self.no_history
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ExportPhase {
+    #[default]
+    Enumerating,
+    Downloading,
+    Retrying,
+    Zipping,
+    Uploading,
+    Emailing,
+    Done,
+    Cancelled,
+
+    /// The export stopped on an error, the job's status says what happens to it next.
+    Errored,
+
+    /// The worker shut down part way through, the export will start over on another.
+    Requeued,
+
+    /// There wasn't room on disk for the export yet, it'll be tried again later.
+    Deferred,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ReplProgress {
+    id: String,
+    slug: String,
+    title: String,
+    state: ReplProgressState,
+    started_at: Option<chrono::DateTime<chrono::Utc>>,
+    finished_at: Option<chrono::DateTime<chrono::Utc>>,
+    file_count: usize,
+
+    /// The size on disk of what was downloaded.
+    bytes: u64,
+
+    /// Why the repl couldn't be downloaded, if it failed or timed out.
+    failure: Option<String>,
+}
+
+impl ReplProgress {
+    fn queued(repl: &Repl) -> Self {
+        Self {
+            id: repl.id.clone(),
+            slug: repl.slug.clone(),
+            title: repl.title.clone(),
+            state: ReplProgressState::Queued,
+            started_at: None,
+            finished_at: None,
+            file_count: 0,
+            bytes: 0,
+            failure: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ReplProgressState {
+    Queued,
+    Downloading,
+    Full,
+    NoHistory,
+    Failed,
+    TimedOut,
+    CarriedOver,
+}
+
+impl From<ManifestReplStatus> for ReplProgressState {
+    fn from(status: ManifestReplStatus) -> Self {
+        match status {
+            ManifestReplStatus::Full => ReplProgressState::Full,
+            ManifestReplStatus::NoHistory => ReplProgressState::NoHistory,
+            ManifestReplStatus::Failed => ReplProgressState::Failed,
+            ManifestReplStatus::TimedOut => ReplProgressState::TimedOut,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follow_ups_start_over() {
+        let user = AirtableSyncedUser {
+            id: 1,
+            username: "mocker".into(),
+            token: "v1:token".into(),
+            email: "mocker@example.com".into(),
+            status: ProcessState::R2LinkEmailSent,
+            r2_link: "https://r2.example.com/mocker.zip".into(),
+            failed_ids: "a,b".into(),
+            started_at: Some(chrono::Utc::now()),
+            finished_at: Some(chrono::Utc::now()),
+            repl_count: 12,
+            file_count: 300,
+            git_remote: Some("https://github.com/mocker/{slug}.git".into()),
+            git_remote_password: Some("v1:password".into()),
+            incremental: true,
+            worker_id: Some("worker".into()),
+            attempts: 2,
+            ..Default::default()
+        };
+        let not_before = chrono::Utc::now();
+
+        let follow_up = follow_up_job(&user, &["a".into(), "b".into()], not_before);
+
+        assert_eq!(follow_up.status, ProcessState::Registered);
+        assert_eq!(follow_up.attempts, 0);
+        assert!(!follow_up.incremental);
+        assert_eq!(follow_up.repl_count, 0);
+        assert_eq!(follow_up.started_at, None);
+        assert_eq!(follow_up.worker_id, None);
+        assert_eq!(follow_up.failed_ids, "none");
+        assert_eq!(follow_up.r2_link, airtable::NO_R2_LINK);
+        assert_eq!(follow_up.retry_repl_ids.as_deref(), Some("a,b"));
+        assert_eq!(follow_up.follow_up_attempt, 1);
+        assert_eq!(follow_up.not_before, Some(not_before));
+
+        // Still needed to download and push the repls again
+        assert_eq!(follow_up.token, "v1:token");
+        assert_eq!(
+            follow_up.git_remote_password.as_deref(),
+            Some("v1:password")
+        );
     }
 }
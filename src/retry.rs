@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+use crate::config;
+
+/// How hard to try again on repls that failed to download.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Extra passes over the failed repls at the end of an export.
+    pub attempts: u32,
+
+    /// Wait before the first retry pass, doubling each pass after.
+    pub backoff: Duration,
+
+    /// How many follow-up jobs to queue for repls that still failed.
+    pub follow_ups: usize,
+
+    /// How long a follow-up job waits before it can be picked up.
+    pub follow_up_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_config() -> Self {
+        let retry = &config::get().retry;
+
+        Self {
+            attempts: retry.attempts,
+            backoff: Duration::from_secs(retry.backoff_secs),
+            follow_ups: retry.follow_ups,
+            follow_up_delay: Duration::from_secs(60 * retry.follow_up_delay_mins),
+        }
+    }
+
+    /// The wait before retry pass `attempt` (starting at 1).
+    pub fn backoff_for(&self, attempt: u32) -> Duration {
+        self.backoff * 2u32.saturating_pow(attempt.saturating_sub(1))
+    }
+}
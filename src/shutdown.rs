@@ -0,0 +1,54 @@
+//! The process-wide signal that the worker is shutting down. Once it's requested no new jobs
+//! are claimed, and a running export gets a grace period to finish the repl it's on before
+//! it's stopped and handed back to the queue.
+
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use tokio::{sync::watch, time::Instant};
+
+use crate::config;
+
+/// How long the repl being downloaded gets to finish.
+pub fn grace_period() -> Duration {
+    config::get().worker.shutdown_grace()
+}
+
+/// When the grace period runs out, once shutdown has been requested.
+static DEADLINE: Lazy<watch::Sender<Option<Instant>>> = Lazy::new(|| watch::channel(None).0);
+
+/// Start shutting down. Requesting it again doesn't push the deadline back.
+pub fn request() {
+    DEADLINE.send_if_modified(|deadline| {
+        if deadline.is_some() {
+            return false;
+        }
+
+        *deadline = Some(Instant::now() + grace_period());
+        true
+    });
+}
+
+pub fn is_requested() -> bool {
+    DEADLINE.borrow().is_some()
+}
+
+/// Resolves once shutdown is requested.
+pub async fn requested() {
+    wait_for_deadline().await;
+}
+
+/// Resolves once shutdown is requested and the grace period has run out.
+pub async fn grace_expired() {
+    let deadline = wait_for_deadline().await;
+    tokio::time::sleep_until(deadline).await;
+}
+
+async fn wait_for_deadline() -> Instant {
+    let mut updates = DEADLINE.subscribe();
+    let Ok(deadline) = updates.wait_for(Option::is_some).await else {
+        return std::future::pending().await;
+    };
+
+    deadline.expect("waited for a deadline")
+}
@@ -0,0 +1,177 @@
+//! Logging setup. Everything goes through `tracing`, with existing `log` calls bridged in, and
+//! every line passes through [`redact`] before it's written so tokens, secrets and emails never
+//! reach the logs.
+
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+};
+
+use dotenv::var;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
+
+/// Patterns for values that must never be logged, and what to replace them with.
+static REDACTIONS: Lazy<Vec<(Regex, &'static str)>> = Lazy::new(|| {
+    [
+        // connect.sid cookies, whether in a header or on their own
+        (r"connect\.sid=[^;\s,\x22\\]+", "connect.sid=[redacted]"),
+        (
+            r"\bs(?::|%3A)[A-Za-z0-9_\-]+\.[A-Za-z0-9_\-+/%]+",
+            "[redacted]",
+        ),
+        (
+            r"\b[A-Za-z0-9_\-]{8,}\.[A-Za-z0-9_\-]{8,}\.[A-Za-z0-9_\-]{8,}\b",
+            "[redacted]",
+        ),
+        // Secrets encrypted by `crypto::encrypt_secret`
+        (r"\bv1:[A-Za-z0-9+/=]{16,}", "[redacted]"),
+        (r"Bearer\s+[^\s,\x22\\]+", "Bearer [redacted]"),
+        // Secrets and file contents from goval responses, plain and JSON escaped
+        (
+            r#"(?s)contents: "(?:[^"\\]|\\.)*""#,
+            r#"contents: "[redacted]""#,
+        ),
+        (
+            r#"(?s)contents: \\"(?:[^\\]|\\[^"])*\\""#,
+            r#"contents: \"[redacted]\""#,
+        ),
+        (
+            r"[A-Za-z0-9._%+\-]+@[A-Za-z0-9.\-]+\.[A-Za-z]{2,}",
+            "[email]",
+        ),
+    ]
+    .into_iter()
+    .map(|(pattern, replacement)| (Regex::new(pattern).expect("valid pattern"), replacement))
+    .collect()
+});
+
+/// Mask anything sensitive in `text`.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let mut text = Cow::Borrowed(text);
+
+    for (pattern, replacement) in REDACTIONS.iter() {
+        if let Cow::Owned(redacted) = pattern.replace_all(&text, *replacement) {
+            text = Cow::Owned(redacted);
+        }
+    }
+
+    text
+}
+
+/// Set up logging for the process. Filtering follows `RUST_LOG` (default `info`), and
+/// `LOG_FORMAT=json` switches to one JSON object per line.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(RedactingStderr);
+
+    let result = if var("LOG_FORMAT").is_ok_and(|format| format == "json") {
+        builder.json().flatten_event(true).try_init()
+    } else {
+        builder.try_init()
+    };
+
+    if let Err(err) = result {
+        eprintln!("Logging was already set up: {err}");
+    }
+}
+
+/// Hands out a [`RedactingWriter`] per event.
+struct RedactingStderr;
+
+impl<'a> MakeWriter<'a> for RedactingStderr {
+    type Writer = RedactingWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter(Vec::new())
+    }
+}
+
+/// Buffers one formatted event, so it can be redacted as a whole before it's written out.
+struct RedactingWriter(Vec<u8>);
+
+impl Write for RedactingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for RedactingWriter {
+    fn drop(&mut self) {
+        let line = String::from_utf8_lossy(&self.0);
+        let _ = io::stderr().lock().write_all(redact(&line).as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_connect_sid() {
+        assert_eq!(
+            redact("cookie: connect.sid=abc123def; theme=dark"),
+            "cookie: connect.sid=[redacted]; theme=dark"
+        );
+    }
+
+    #[test]
+    fn redacts_signed_session_tokens() {
+        assert_eq!(
+            redact("token s%3AzX1_abcDEF.q8Pl%2Bk9d%2Fsig and s:abc-DEF.sig+/9"),
+            "token [redacted] and [redacted]"
+        );
+    }
+
+    #[test]
+    fn redacts_encrypted_secrets() {
+        assert_eq!(
+            redact(r#"{"token":"v1:AAECAwQFBgcICQoLDA0ODxAREhM="}"#),
+            r#"{"token":"[redacted]"}"#
+        );
+    }
+
+    #[test]
+    fn redacts_bearer_tokens() {
+        assert_eq!(
+            redact("Authorization: Bearer hunter2hunter2, next"),
+            "Authorization: Bearer [redacted], next"
+        );
+    }
+
+    #[test]
+    fn redacts_goval_contents() {
+        assert_eq!(
+            redact(r#"SecretsGetResponse { contents: "API_KEY=\"hunter2\"\nOTHER=1" }"#),
+            r#"SecretsGetResponse { contents: "[redacted]" }"#
+        );
+
+        // The same, inside a JSON log line
+        assert_eq!(
+            redact(r#"{"message":"SecretsGetResponse { contents: \"API_KEY=1\" }"}"#),
+            r#"{"message":"SecretsGetResponse { contents: \"[redacted]\" }"}"#
+        );
+    }
+
+    #[test]
+    fn redacts_emails() {
+        assert_eq!(
+            redact("Sent mocker+test@example.co.uk their link"),
+            "Sent [email] their link"
+        );
+    }
+
+    #[test]
+    fn leaves_everything_else_alone() {
+        let line = "Downloaded 0b1d7c2a::hello (12 files) in 3.2s";
+        assert!(matches!(redact(line), Cow::Borrowed(_)));
+    }
+}
@@ -0,0 +1,214 @@
+//! Every job downloads into its own directory under the configured workspace root, which
+//! should be dedicated to workspaces. A workspace is locked for as long as it's in use, so the
+//! ones left behind by a crashed worker can be told apart and swept up. Anything else that ends
+//! up under the root is left alone.
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use fs2::FileExt;
+use log::{error, info, warn};
+
+use crate::config;
+
+/// Held by whoever is using a workspace, and what marks a directory as one.
+const LOCK_FILE: &str = ".lock";
+
+/// What goes in the archive, the rest of a workspace is scratch space.
+const EXPORT_DIR: &str = "export";
+
+/// Roughly how much disk a download takes up compared to the repl on Replit, between the files,
+/// the git history, the OT backup and the zip it falls back on.
+const DISK_OVERHEAD: u64 = 3;
+
+fn root() -> &'static Path {
+    &config::get().download.workspace_root
+}
+
+/// How much space is kept free for everything else.
+pub fn min_free_disk() -> u64 {
+    config::get().download.min_free_disk()
+}
+
+/// A job's directory, removed once it's dropped.
+pub struct Workspace {
+    path: PathBuf,
+    _lock: File,
+}
+
+impl Workspace {
+    /// Set up a fresh workspace for `job_id`, clearing out anything an earlier attempt left.
+    pub async fn create(job_id: &str) -> Result<Self> {
+        let path = root().join(job_id);
+        if tokio::fs::try_exists(&path).await? {
+            warn!("Clearing out the workspace left over from {job_id}'s last attempt");
+            tokio::fs::remove_dir_all(&path).await?;
+        }
+
+        tokio::fs::create_dir_all(path.join(EXPORT_DIR)).await?;
+
+        let lock = File::create(path.join(LOCK_FILE))?;
+        lock.try_lock_exclusive()
+            .with_context(|| format!("Locking the workspace {}", path.display()))?;
+
+        Ok(Self { path, _lock: lock })
+    }
+
+    /// Where the export itself is put together.
+    pub fn export_dir(&self) -> String {
+        self.path.join(EXPORT_DIR).to_string_lossy().into_owned()
+    }
+
+    /// A path for a scratch file that doesn't end up in the archive.
+    pub fn scratch_file(&self, name: &str) -> String {
+        self.path.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let path = self.path.clone();
+        let remove = move || {
+            if let Err(err) = std::fs::remove_dir_all(&path) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    error!("Couldn't remove the workspace {}: {err}", path.display());
+                }
+            }
+        };
+
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(remove)),
+            Err(_) => remove(),
+        }
+    }
+}
+
+/// Remove the workspaces nobody holds the lock on. Only meant for startup, before any job has
+/// had the chance to create a workspace but not lock it yet.
+pub fn sweep_orphans() -> Result<()> {
+    sweep(root())
+}
+
+fn sweep(root: &Path) -> Result<()> {
+    let entries = match std::fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut swept = 0;
+    for entry in entries {
+        let path = entry?.path();
+
+        // Not a workspace, or one that's still in use
+        if !path.is_dir() || is_locked(&path).unwrap_or(true) {
+            continue;
+        }
+
+        std::fs::remove_dir_all(&path)?;
+        swept += 1;
+    }
+
+    if swept > 0 {
+        info!("Swept {swept} orphaned workspaces from {}", root.display());
+    }
+
+    Ok(())
+}
+
+/// Whether the workspace at `path` is in use, or `None` if it isn't a workspace at all.
+fn is_locked(path: &Path) -> Option<bool> {
+    let lock = File::options()
+        .write(true)
+        .open(path.join(LOCK_FILE))
+        .ok()?;
+
+    Some(lock.try_lock_exclusive().is_err())
+}
+
+/// How the space a job needs compares to what's on the disk workspaces live on.
+#[derive(Debug, Clone, Copy)]
+pub struct DiskBudget {
+    pub needed: u64,
+    pub available: u64,
+    pub total: u64,
+}
+
+impl DiskBudget {
+    /// The budget for downloading repls taking up `bytes_used` on Replit.
+    pub fn for_repls(bytes_used: u64) -> Result<Self> {
+        let path = disk_path();
+
+        Ok(Self {
+            needed: bytes_used.saturating_mul(DISK_OVERHEAD) + min_free_disk(),
+            available: fs2::available_space(path)?,
+            total: fs2::total_space(path)?,
+        })
+    }
+
+    pub fn fits(&self) -> bool {
+        self.needed <= self.available
+    }
+
+    /// Whether waiting for space to free up could ever help.
+    pub fn could_fit(&self) -> bool {
+        self.needed <= self.total
+    }
+}
+
+/// Free space on the disk workspaces live on.
+pub fn free_disk() -> Result<u64> {
+    Ok(fs2::available_space(disk_path())?)
+}
+
+/// The root might not have been created yet.
+fn disk_path() -> &'static Path {
+    if root().exists() {
+        root()
+    } else {
+        Path::new(".")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweeps_only_abandoned_workspaces() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+
+        let abandoned = root.join("recAbandoned");
+        std::fs::create_dir_all(abandoned.join(EXPORT_DIR)).unwrap();
+        File::create(abandoned.join(LOCK_FILE)).unwrap();
+
+        let in_use = root.join("recInUse");
+        std::fs::create_dir_all(in_use.join(EXPORT_DIR)).unwrap();
+        let lock = File::create(in_use.join(LOCK_FILE)).unwrap();
+        lock.lock_exclusive().unwrap();
+
+        // Whatever else someone put there
+        let other = root.join("backups");
+        std::fs::create_dir_all(&other).unwrap();
+        std::fs::write(root.join("notes.txt"), "keep me").unwrap();
+
+        sweep(root).unwrap();
+
+        assert!(!abandoned.exists());
+        assert!(in_use.join(EXPORT_DIR).exists());
+        assert!(other.exists());
+        assert!(!other.join(LOCK_FILE).exists());
+        assert!(root.join("notes.txt").exists());
+    }
+
+    #[test]
+    fn sweeping_a_missing_root_is_fine() {
+        let root = tempfile::tempdir().unwrap();
+
+        sweep(&root.path().join("missing")).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+use replit_takeout::{
+    airtable::{AirtableSyncedUser, ProcessState},
+    archive::{extract_archive, ArchiveFormat},
+    cancel::CancelWatch,
+    crypto,
+    manifest::{ExportManifest, ManifestReplStatus, MANIFEST_FILENAME},
+    mock::{FixtureRepl, MockReplit, MockStores, MockUser},
+    replit_graphql::{ExportProgress, ProfileRepls},
+};
+
+const TOKEN: &str = "mock-token";
+
+fn fixtures() -> Vec<FixtureRepl> {
+    vec![
+        FixtureRepl::new("0b1d7c2a-0001", "hello")
+            .file_with_history(
+                "main.py",
+                &[
+                    (1_672_531_200, "print('hi')\n"),
+                    (1_672_534_800, "print('héllo 👋')\n"),
+                ],
+            )
+            .file("README.md", "# Hello\n"),
+        FixtureRepl::new("0b1d7c2a-0002", "homework")
+            .in_folder("school/cs")
+            .file_with_history(
+                "index.js",
+                &[
+                    (1_672_531_200, "console.log(1)\n"),
+                    (1_672_617_600, "console.log(2)\n"),
+                ],
+            ),
+        FixtureRepl::new("0b1d7c2a-0003", "zip-only")
+            .unconnectable()
+            .file("main.rs", "fn main() {}\n"),
+    ]
+}
+
+/// A whole export, from the job being picked up to the email, against the mock.
+#[tokio::test(flavor = "multi_thread")]
+async fn exports_every_repl() {
+    let workspace = tempfile::tempdir().unwrap();
+    std::env::set_var("WORKSPACE_ROOT", workspace.path());
+    std::env::set_var(
+        "TOKEN_ENCRYPTION_KEY",
+        "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=",
+    );
+    std::env::set_var("OT_FETCH_WINDOW", "1");
+    std::env::set_var("MIN_FREE_DISK_MB", "0");
+
+    let mock = MockReplit::start(MockUser::new(1, "mocker", TOKEN), fixtures())
+        .await
+        .unwrap();
+    mock.install().unwrap();
+    let stores = MockStores::install();
+
+    let record = stores.add_record(AirtableSyncedUser {
+        id: 1,
+        username: "mocker".into(),
+        token: crypto::encrypt_secret(TOKEN).unwrap(),
+        email: "mocker@example.com".into(),
+        status: ProcessState::Registered,
+        failed_ids: "none".into(),
+        archive_format: Some(ArchiveFormat::TarZst),
+        ..Default::default()
+    });
+
+    ProfileRepls::download(&TOKEN.to_string(), record.clone(), CancelWatch::never())
+        .await
+        .unwrap();
+
+    let job = stores.record(&record.id).unwrap().fields;
+    assert_eq!(job.status, ProcessState::R2LinkEmailSent);
+    assert_eq!(job.repl_count, 3);
+    assert!(job.token.is_empty(), "the token outlived the job");
+
+    let link = stores.link("export/mocker.tar.zst", "mocker.tar.zst");
+    assert_eq!(job.r2_link, link);
+
+    let emails = stores.emails();
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0].to, "mocker@example.com");
+
+    // What the user downloads has every repl in it
+    let archive = workspace.path().join("export.tar.zst");
+    std::fs::write(&archive, stores.object("export/mocker.tar.zst").unwrap()).unwrap();
+    let out = workspace.path().join("out");
+    extract_archive(
+        &archive.display().to_string(),
+        &out.display().to_string(),
+        ArchiveFormat::TarZst,
+        |_| true,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(out.join("hello/main.py")).unwrap(),
+        "print('héllo 👋')\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(out.join("homework/index.js")).unwrap(),
+        "console.log(2)\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(out.join("zip-only/main.rs")).unwrap(),
+        "fn main() {}\n"
+    );
+
+    let manifest: ExportManifest =
+        serde_json::from_slice(&std::fs::read(out.join(MANIFEST_FILENAME)).unwrap()).unwrap();
+    let status = |slug: &str| {
+        manifest
+            .repls
+            .iter()
+            .find(|repl| repl.slug == slug)
+            .map(|repl| repl.status)
+    };
+    assert_eq!(status("hello"), Some(ManifestReplStatus::Full));
+    assert_eq!(status("homework"), Some(ManifestReplStatus::Full));
+    assert_eq!(status("zip-only"), Some(ManifestReplStatus::NoHistory));
+
+    // The next export can build on this one
+    assert!(stores.object("manifest/1").is_some());
+
+    // Progress reports are written in the background, so the last one can lag a little
+    let mut progress = None;
+    for _ in 0..50 {
+        progress = ExportProgress::fetch(1).await;
+        if progress.as_ref().is_some_and(ExportProgress::finished) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert!(progress.is_some_and(|progress| progress.finished()));
+
+    // The reconstructed history made it into the archive too
+    let repo = git2::Repository::open(out.join("hello")).unwrap();
+    let mut revwalk = repo.revwalk().unwrap();
+    revwalk.push_head().unwrap();
+    assert!(revwalk.count() >= 2, "hello's history is missing commits");
+}